@@ -3,9 +3,14 @@
 #[ink::contract]
 mod eduverse {
     use ink::prelude::vec::Vec;
+    use ink::scale::Encode;
     use ink::storage::Mapping;
     use ink::env::call::FromAccountId;
 
+    /// Prefix of every attendance attestation the server signs, so the signature can't
+    /// be passed off as anything else.
+    pub const ATTESTATION_DOMAIN: &[u8] = b"eduverse-attendance";
+
     #[ink(storage)]
     pub struct Eduverse {
         /// Stores a single `bool` value on the storage.
@@ -21,6 +26,8 @@ mod eduverse {
         course_students: Mapping<u32, Vec<AccountId>>,
         course_completions: Mapping<(u32, AccountId), bool>,
         nft_contract: AccountId,
+        /// sr25519 key of the classroom server, which signs attendance attestations
+        attestation_signer: AccountId,
     }
 
     #[ink::scale_derive(Encode, Decode, TypeInfo)]
@@ -37,6 +44,19 @@ mod eduverse {
         price: Balance,
         active: bool,
         metadata_hash: Vec<u8>,
+        /// Attendance a student needs before the course can be completed, 0 for no minimum
+        min_attendance_minutes: u32,
+        min_sessions_attended: u32,
+    }
+
+    /// The classroom server's signed statement of how much of a course a student attended.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct AttendanceAttestation {
+        pub minutes_attended: u32,
+        pub sessions_attended: u32,
+        /// sr25519 signature over `attestation_message`
+        pub signature: [u8; 64],
     }
 
     #[ink(event)]
@@ -46,6 +66,11 @@ mod eduverse {
         #[ink(topic)]
         teacher: AccountId,
         title: Vec<u8>,
+        /// Carried into the classroom, which caps live occupancy at the same number
+        max_students: u32,
+        start_time: Timestamp,
+        end_time: Timestamp,
+        metadata_hash: Vec<u8>,
     }
 
     #[ink(event)]
@@ -68,12 +93,28 @@ mod eduverse {
         CourseNotActive,
         InvalidTime,
         NFTMintingFailed,
+        AttestationRequired,
+        InvalidAttestation,
+        InsufficientAttendance,
+    }
+
+    /// The bytes the server signs: the domain prefix, then the SCALE encoded
+    /// (course id, student, minutes attended, sessions attended).
+    pub fn attestation_message(
+        course_id: u32,
+        student: &AccountId,
+        minutes_attended: u32,
+        sessions_attended: u32,
+    ) -> Vec<u8> {
+        let mut message = Vec::from(ATTESTATION_DOMAIN);
+        (course_id, student, minutes_attended, sessions_attended).encode_to(&mut message);
+        message
     }
 
 
     impl Eduverse {
         #[ink(constructor)]
-        pub fn new(nft_contract_address: AccountId) -> Self {
+        pub fn new(nft_contract_address: AccountId, attestation_signer: AccountId) -> Self {
             Eduverse {
                 course_counter: 0,
                 courses: Mapping::default(),
@@ -82,6 +123,7 @@ mod eduverse {
                 course_students: Mapping::default(),
                 course_completions: Mapping::default(),
                 nft_contract: nft_contract_address,
+                attestation_signer,
             }
         }
 
@@ -95,6 +137,8 @@ mod eduverse {
             end_time: Timestamp,
             price: Balance,
             metadata_hash: Vec<u8>,
+            min_attendance_minutes: u32,
+            min_sessions_attended: u32,
         ) -> Result<u32, Error> {
             let caller = self.env().caller();
             let current_time = self.env().block_timestamp();
@@ -118,7 +162,9 @@ mod eduverse {
                 end_time,
                 price,
                 active: true,
-                metadata_hash,
+                metadata_hash: metadata_hash.clone(),
+                min_attendance_minutes,
+                min_sessions_attended,
             };
 
             // Store course
@@ -134,6 +180,10 @@ mod eduverse {
                 course_id,
                 teacher: caller,
                 title,
+                max_students,
+                start_time,
+                end_time,
+                metadata_hash,
             });
 
             Ok(course_id)
@@ -181,8 +231,15 @@ mod eduverse {
             Ok(())
         }
 
+        /// Completion still comes from the teacher, but for courses with an attendance
+        /// minimum it also needs the server's attestation that the student met it.
         #[ink(message)]
-        pub fn complete_course(&mut self, course_id: u32, student: AccountId) -> Result<(), Error> {
+        pub fn complete_course(
+            &mut self,
+            course_id: u32,
+            student: AccountId,
+            attestation: Option<AttendanceAttestation>,
+        ) -> Result<(), Error> {
             let caller = self.env().caller();
             let course = self.courses.get(course_id).ok_or(Error::CourseNotFound)?;
 
@@ -194,6 +251,18 @@ mod eduverse {
                 return Err(Error::NotEnrolled);
             }
 
+            if course.min_attendance_minutes > 0 || course.min_sessions_attended > 0 {
+                let attestation = attestation.ok_or(Error::AttestationRequired)?;
+                if !self.verify_attestation(course_id, student, attestation.clone()) {
+                    return Err(Error::InvalidAttestation);
+                }
+                if attestation.minutes_attended < course.min_attendance_minutes
+                    || attestation.sessions_attended < course.min_sessions_attended
+                {
+                    return Err(Error::InsufficientAttendance);
+                }
+            }
+
             self.course_completions.insert((course_id, student), &true);
 
             // Mint NFT for course completion
@@ -215,6 +284,30 @@ mod eduverse {
             }
         }
 
+        /// Check that an attendance attestation for the student was signed by the server
+        #[ink(message)]
+        pub fn verify_attestation(
+            &self,
+            course_id: u32,
+            student: AccountId,
+            attestation: AttendanceAttestation,
+        ) -> bool {
+            let message = attestation_message(
+                course_id,
+                &student,
+                attestation.minutes_attended,
+                attestation.sessions_attended,
+            );
+            let signer: &[u8; 32] = self.attestation_signer.as_ref();
+            ink::env::sr25519_verify(&attestation.signature, &message, signer).is_ok()
+        }
+
+        /// Get the server key attestations have to be signed with
+        #[ink(message)]
+        pub fn get_attestation_signer(&self) -> AccountId {
+            self.attestation_signer
+        }
+
         /// Get course details
         #[ink(message)]
         pub fn get_course(&self, course_id: u32) -> Option<Course> {
@@ -245,25 +338,171 @@ mod eduverse {
         }
     }
 
-    /// Unit tests in Rust are normally defined within such a `#[cfg(test)]`
-    /// module and test functions are marked with a `#[test]` attribute.
-    /// The below code is technically just normal Rust code.
     #[cfg(test)]
     mod tests {
-        /// We test if the default constructor does its job.
+        use super::*;
+        use schnorrkel::{signing_context, ExpansionMode, Keypair, MiniSecretKey};
+
+        fn keypair(seed: u8) -> Keypair {
+            MiniSecretKey::from_bytes(&[seed; 32])
+                .unwrap()
+                .expand_to_keypair(ExpansionMode::Ed25519)
+        }
+
+        fn account(keypair: &Keypair) -> AccountId {
+            AccountId::from(keypair.public.to_bytes())
+        }
+
+        // Signed the way the server does it, with the substrate signing context
+        fn attest(
+            keypair: &Keypair,
+            course_id: u32,
+            student: &AccountId,
+            minutes_attended: u32,
+            sessions_attended: u32,
+        ) -> AttendanceAttestation {
+            let message =
+                attestation_message(course_id, student, minutes_attended, sessions_attended);
+            let signature = keypair.sign(signing_context(b"substrate").bytes(&message));
+            AttendanceAttestation {
+                minutes_attended,
+                sessions_attended,
+                signature: signature.to_bytes(),
+            }
+        }
+
+        fn set_caller(caller: AccountId) {
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(caller);
+        }
+
+        fn enrolled_student() -> AccountId {
+            ink::env::test::default_accounts::<ink::env::DefaultEnvironment>().charlie
+        }
+
+        // A course taught by bob that charlie is enrolled in, with a 30 minute, 2 session minimum
+        fn enrolled_course(contract: &mut Eduverse) -> u32 {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            set_caller(accounts.bob);
+            let course_id = contract
+                .create_course(b"Rust".to_vec(), Vec::new(), 10, 1, 2, 0, Vec::new(), 30, 2)
+                .unwrap();
+            set_caller(enrolled_student());
+            contract.enroll(course_id).unwrap();
+            set_caller(accounts.bob);
+            course_id
+        }
+
+        #[ink::test]
+        fn attestation_message_is_domain_then_scale_tuple() {
+            let student = AccountId::from([7; 32]);
+            let message = attestation_message(3, &student, 90, 4);
+
+            let mut expected = b"eduverse-attendance".to_vec();
+            expected.extend_from_slice(&3u32.to_le_bytes());
+            expected.extend_from_slice(&[7; 32]);
+            expected.extend_from_slice(&90u32.to_le_bytes());
+            expected.extend_from_slice(&4u32.to_le_bytes());
+            assert_eq!(message, expected);
+        }
+
+        #[ink::test]
+        fn verify_attestation_accepts_the_server_signature() {
+            let server = keypair(1);
+            let contract = Eduverse::new(AccountId::from([0; 32]), account(&server));
+            let student = AccountId::from([7; 32]);
+
+            let attestation = attest(&server, 3, &student, 90, 4);
+            assert!(contract.verify_attestation(3, student, attestation));
+        }
+
+        #[ink::test]
+        fn verify_attestation_rejects_another_signer() {
+            let contract = Eduverse::new(AccountId::from([0; 32]), account(&keypair(1)));
+            let student = AccountId::from([7; 32]);
+
+            let attestation = attest(&keypair(2), 3, &student, 90, 4);
+            assert!(!contract.verify_attestation(3, student, attestation));
+        }
+
+        #[ink::test]
+        fn verify_attestation_rejects_another_course_or_student() {
+            let server = keypair(1);
+            let contract = Eduverse::new(AccountId::from([0; 32]), account(&server));
+            let student = AccountId::from([7; 32]);
+
+            let attestation = attest(&server, 3, &student, 90, 4);
+            assert!(!contract.verify_attestation(4, student, attestation.clone()));
+            assert!(!contract.verify_attestation(3, AccountId::from([8; 32]), attestation));
+        }
+
+        #[ink::test]
+        fn verify_attestation_rejects_inflated_attendance() {
+            let server = keypair(1);
+            let contract = Eduverse::new(AccountId::from([0; 32]), account(&server));
+            let student = AccountId::from([7; 32]);
+
+            let mut attestation = attest(&server, 3, &student, 10, 1);
+            attestation.minutes_attended = 90;
+            assert!(!contract.verify_attestation(3, student, attestation));
+        }
+
+        #[ink::test]
+        fn complete_course_requires_an_attestation() {
+            let mut contract = Eduverse::new(AccountId::from([0; 32]), account(&keypair(1)));
+            let course_id = enrolled_course(&mut contract);
+            let student = enrolled_student();
+
+            assert_eq!(
+                contract.complete_course(course_id, student, None),
+                Err(Error::AttestationRequired)
+            );
+        }
+
         #[ink::test]
-        fn default_works() {
-            let contracts = Contracts::default();
-            assert_eq!(contracts.get(), false);
+        fn complete_course_rejects_a_forged_attestation() {
+            let mut contract = Eduverse::new(AccountId::from([0; 32]), account(&keypair(1)));
+            let course_id = enrolled_course(&mut contract);
+            let student = enrolled_student();
+
+            let attestation = attest(&keypair(2), course_id, &student, 60, 3);
+            assert_eq!(
+                contract.complete_course(course_id, student, Some(attestation)),
+                Err(Error::InvalidAttestation)
+            );
         }
 
-        /// We test a simple use case of our contract.
         #[ink::test]
-        fn it_works() {
-            let mut contracts = Contracts::new(false);
-            assert_eq!(contracts.get(), false);
-            contracts.flip();
-            assert_eq!(contracts.get(), true);
+        fn complete_course_rejects_too_little_attendance() {
+            let server = keypair(1);
+            let mut contract = Eduverse::new(AccountId::from([0; 32]), account(&server));
+            let course_id = enrolled_course(&mut contract);
+            let student = enrolled_student();
+
+            let too_few_minutes = attest(&server, course_id, &student, 29, 3);
+            assert_eq!(
+                contract.complete_course(course_id, student, Some(too_few_minutes)),
+                Err(Error::InsufficientAttendance)
+            );
+            let too_few_sessions = attest(&server, course_id, &student, 60, 1);
+            assert_eq!(
+                contract.complete_course(course_id, student, Some(too_few_sessions)),
+                Err(Error::InsufficientAttendance)
+            );
+        }
+
+        #[ink::test]
+        fn complete_course_is_for_the_teacher() {
+            let server = keypair(1);
+            let mut contract = Eduverse::new(AccountId::from([0; 32]), account(&server));
+            let course_id = enrolled_course(&mut contract);
+            let student = enrolled_student();
+
+            set_caller(student);
+            let attestation = attest(&server, course_id, &student, 60, 3);
+            assert_eq!(
+                contract.complete_course(course_id, student, Some(attestation)),
+                Err(Error::Unauthorized)
+            );
         }
     }
 
@@ -274,17 +513,21 @@ mod eduverse {
     /// - Are running a Substrate node which contains `pallet-contracts` in the background
     #[cfg(all(test, feature = "e2e-tests"))]
     mod e2e_tests {
-        /// A helper function used for calling contract messages.
+        use super::*;
         use ink_e2e::ContractsBackend;
 
         /// The End-to-End test `Result` type.
         type E2EResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-        /// We test that we can upload and instantiate the contract using its default constructor.
+        /// The contract instantiates with the NFT contract and server key it's given.
         #[ink_e2e::test]
-        async fn default_works(mut client: ink_e2e::Client<C, E>) -> E2EResult<()> {
+        async fn new_keeps_the_attestation_signer(
+            mut client: ink_e2e::Client<C, E>,
+        ) -> E2EResult<()> {
             // Given
-            let mut constructor = ContractsRef::default();
+            let nft_contract = ink_e2e::account_id(ink_e2e::AccountKeyring::Charlie);
+            let signer = ink_e2e::account_id(ink_e2e::AccountKeyring::Dave);
+            let mut constructor = EduverseRef::new(nft_contract, signer);
 
             // When
             let contract = client
@@ -292,44 +535,12 @@ mod eduverse {
                 .submit()
                 .await
                 .expect("instantiate failed");
-            let call_builder = contract.call_builder::<Contracts>();
+            let call_builder = contract.call_builder::<Eduverse>();
 
             // Then
-            let get = call_builder.get();
+            let get = call_builder.get_attestation_signer();
             let get_result = client.call(&ink_e2e::alice(), &get).dry_run().await?;
-            assert!(matches!(get_result.return_value(), false));
-
-            Ok(())
-        }
-
-        /// We test that we can read and write a value from the on-chain contract.
-        #[ink_e2e::test]
-        async fn it_works(mut client: ink_e2e::Client<C, E>) -> E2EResult<()> {
-            // Given
-            let mut constructor = ContractsRef::new(false);
-            let contract = client
-                .instantiate("contracts", &ink_e2e::bob(), &mut constructor)
-                .submit()
-                .await
-                .expect("instantiate failed");
-            let mut call_builder = contract.call_builder::<Contracts>();
-
-            let get = call_builder.get();
-            let get_result = client.call(&ink_e2e::bob(), &get).dry_run().await?;
-            assert!(matches!(get_result.return_value(), false));
-
-            // When
-            let flip = call_builder.flip();
-            let _flip_result = client
-                .call(&ink_e2e::bob(), &flip)
-                .submit()
-                .await
-                .expect("flip failed");
-
-            // Then
-            let get = call_builder.get();
-            let get_result = client.call(&ink_e2e::bob(), &get).dry_run().await?;
-            assert!(matches!(get_result.return_value(), true));
+            assert_eq!(get_result.return_value(), signer);
 
             Ok(())
         }
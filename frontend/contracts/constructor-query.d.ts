@@ -1,7 +1,7 @@
 // Generated by dedot cli
 
 import type { GenericSubstrateApi } from "dedot/types";
-import type { Result } from "dedot/codecs";
+import type { AccountId32Like, Result } from "dedot/codecs";
 import type {
   GenericConstructorQuery,
   GenericConstructorQueryCall,
@@ -15,6 +15,8 @@ export interface ConstructorQuery<ChainApi extends GenericSubstrateApi>
   extends GenericConstructorQuery<ChainApi> {
   /**
    *
+   * @param {AccountId32Like} nftContractAddress
+   * @param {AccountId32Like} attestationSigner
    * @param {ConstructorCallOptions} options
    *
    * @selector 0x9bae9d5e
@@ -22,6 +24,8 @@ export interface ConstructorQuery<ChainApi extends GenericSubstrateApi>
   new: GenericConstructorQueryCall<
     ChainApi,
     (
+      nftContractAddress: AccountId32Like,
+      attestationSigner: AccountId32Like,
       options?: ConstructorCallOptions,
     ) => Promise<
       GenericConstructorCallResult<[], ContractInstantiateResult<ChainApi>>
@@ -1,6 +1,7 @@
 // Generated by dedot cli
 
 import type { GenericSubstrateApi } from "dedot/types";
+import type { AccountId32Like } from "dedot/codecs";
 import type {
   GenericConstructorTx,
   GenericConstructorTxCall,
@@ -12,6 +13,8 @@ export interface ConstructorTx<ChainApi extends GenericSubstrateApi>
   extends GenericConstructorTx<ChainApi> {
   /**
    *
+   * @param {AccountId32Like} nftContractAddress
+   * @param {AccountId32Like} attestationSigner
    * @param {ConstructorTxOptions} options
    *
    * @selector 0x9bae9d5e
@@ -19,6 +22,8 @@ export interface ConstructorTx<ChainApi extends GenericSubstrateApi>
   new: GenericConstructorTxCall<
     ChainApi,
     (
+      nftContractAddress: AccountId32Like,
+      attestationSigner: AccountId32Like,
       options: ConstructorTxOptions,
     ) => GenericInstantiateSubmittableExtrinsic<ChainApi>
   >;
@@ -11,7 +11,7 @@ export interface ContractEvents<ChainApi extends GenericSubstrateApi>
   extends GenericContractEvents<ChainApi> {
   /**
    *
-   * @signature_topic: 0x7f569b8d80d7eb685bfebc5751d7f87fbf97d284f26472be03d69c60c5602575
+   * @signature_topic: 0x8925efa6f9a94ec4ae55995c164010ef995494b397e1928a0c2a8851afb34905
    **/
   CourseCreated: GenericContractEvent<
     "CourseCreated",
@@ -31,6 +31,27 @@ export interface ContractEvents<ChainApi extends GenericSubstrateApi>
        * @indexed: false
        **/
       title: Bytes;
+      /**
+       * Carried into the classroom, which caps live occupancy at the same number
+       *
+       * @indexed: false
+       **/
+      maxStudents: number;
+      /**
+       *
+       * @indexed: false
+       **/
+      startTime: bigint;
+      /**
+       *
+       * @indexed: false
+       **/
+      endTime: bigint;
+      /**
+       *
+       * @indexed: false
+       **/
+      metadataHash: Bytes;
     }
   >;
 
@@ -1,7 +1,12 @@
 // Generated by dedot cli
 
 import type { GenericSubstrateApi } from "dedot/types";
-import type { BytesLike, Result, AccountId32Like } from "dedot/codecs";
+import type {
+  BytesLike,
+  Result,
+  AccountId32Like,
+  AccountId32,
+} from "dedot/codecs";
 import type {
   GenericContractQuery,
   GenericContractQueryCall,
@@ -12,6 +17,7 @@ import type {
 import type {
   ContractsEduverseError,
   InkPrimitivesLangError,
+  ContractsEduverseAttendanceAttestation,
   ContractsEduverseCourse,
 } from "./types";
 
@@ -26,6 +32,8 @@ export interface ContractQuery<ChainApi extends GenericSubstrateApi>
    * @param {bigint} endTime
    * @param {bigint} price
    * @param {BytesLike} metadataHash
+   * @param {number} minAttendanceMinutes
+   * @param {number} minSessionsAttended
    * @param {ContractCallOptions} options
    *
    * @selector 0xc75902bc
@@ -40,6 +48,8 @@ export interface ContractQuery<ChainApi extends GenericSubstrateApi>
       endTime: bigint,
       price: bigint,
       metadataHash: BytesLike,
+      minAttendanceMinutes: number,
+      minSessionsAttended: number,
       options?: ContractCallOptions,
     ) => Promise<
       GenericContractCallResult<
@@ -69,6 +79,32 @@ export interface ContractQuery<ChainApi extends GenericSubstrateApi>
     >
   >;
 
+  /**
+   * Completion still comes from the teacher, but for courses with an attendance
+   * minimum it also needs the server's attestation that the student met it.
+   *
+   * @param {number} courseId
+   * @param {AccountId32Like} student
+   * @param {ContractsEduverseAttendanceAttestation | undefined} attestation
+   * @param {ContractCallOptions} options
+   *
+   * @selector 0xb143bac4
+   **/
+  completeCourse: GenericContractQueryCall<
+    ChainApi,
+    (
+      courseId: number,
+      student: AccountId32Like,
+      attestation: ContractsEduverseAttendanceAttestation | undefined,
+      options?: ContractCallOptions,
+    ) => Promise<
+      GenericContractCallResult<
+        Result<[], ContractsEduverseError>,
+        ContractCallResult<ChainApi>
+      >
+    >
+  >;
+
   /**
    * Verify if a student is enrolled in a course
    *
@@ -89,6 +125,44 @@ export interface ContractQuery<ChainApi extends GenericSubstrateApi>
     >
   >;
 
+  /**
+   * Check that an attendance attestation for the student was signed by the server
+   *
+   * @param {number} courseId
+   * @param {AccountId32Like} student
+   * @param {ContractsEduverseAttendanceAttestation} attestation
+   * @param {ContractCallOptions} options
+   *
+   * @selector 0x28f102c7
+   **/
+  verifyAttestation: GenericContractQueryCall<
+    ChainApi,
+    (
+      courseId: number,
+      student: AccountId32Like,
+      attestation: ContractsEduverseAttendanceAttestation,
+      options?: ContractCallOptions,
+    ) => Promise<
+      GenericContractCallResult<boolean, ContractCallResult<ChainApi>>
+    >
+  >;
+
+  /**
+   * Get the server key attestations have to be signed with
+   *
+   * @param {ContractCallOptions} options
+   *
+   * @selector 0x27d863ba
+   **/
+  getAttestationSigner: GenericContractQueryCall<
+    ChainApi,
+    (
+      options?: ContractCallOptions,
+    ) => Promise<
+      GenericContractCallResult<AccountId32, ContractCallResult<ChainApi>>
+    >
+  >;
+
   /**
    * Get course details
    *
@@ -1,13 +1,14 @@
 // Generated by dedot cli
 
 import type { GenericSubstrateApi } from "dedot/types";
-import type { BytesLike } from "dedot/codecs";
+import type { BytesLike, AccountId32Like } from "dedot/codecs";
 import type {
   GenericContractTx,
   GenericContractTxCall,
   ContractTxOptions,
   ContractSubmittableExtrinsic,
 } from "dedot/contracts";
+import type { ContractsEduverseAttendanceAttestation } from "./types";
 
 export interface ContractTx<ChainApi extends GenericSubstrateApi>
   extends GenericContractTx<ChainApi> {
@@ -20,6 +21,8 @@ export interface ContractTx<ChainApi extends GenericSubstrateApi>
    * @param {bigint} endTime
    * @param {bigint} price
    * @param {BytesLike} metadataHash
+   * @param {number} minAttendanceMinutes
+   * @param {number} minSessionsAttended
    * @param {ContractTxOptions} options
    *
    * @selector 0xc75902bc
@@ -34,6 +37,8 @@ export interface ContractTx<ChainApi extends GenericSubstrateApi>
       endTime: bigint,
       price: bigint,
       metadataHash: BytesLike,
+      minAttendanceMinutes: number,
+      minSessionsAttended: number,
       options: ContractTxOptions,
     ) => ContractSubmittableExtrinsic<ChainApi>
   >;
@@ -52,4 +57,25 @@ export interface ContractTx<ChainApi extends GenericSubstrateApi>
       options: ContractTxOptions,
     ) => ContractSubmittableExtrinsic<ChainApi>
   >;
+
+  /**
+   * Completion still comes from the teacher, but for courses with an attendance
+   * minimum it also needs the server's attestation that the student met it.
+   *
+   * @param {number} courseId
+   * @param {AccountId32Like} student
+   * @param {ContractsEduverseAttendanceAttestation | undefined} attestation
+   * @param {ContractTxOptions} options
+   *
+   * @selector 0xb143bac4
+   **/
+  completeCourse: GenericContractTxCall<
+    ChainApi,
+    (
+      courseId: number,
+      student: AccountId32Like,
+      attestation: ContractsEduverseAttendanceAttestation | undefined,
+      options: ContractTxOptions,
+    ) => ContractSubmittableExtrinsic<ChainApi>
+  >;
 }
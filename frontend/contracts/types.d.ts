@@ -1,6 +1,6 @@
 // Generated by dedot cli
 
-import type { AccountId32, Bytes } from "dedot/codecs";
+import type { AccountId32, Bytes, FixedBytes } from "dedot/codecs";
 
 export type InkStorageLazyMapping = {};
 
@@ -16,6 +16,8 @@ export type ContractsEduverseCourse = {
   price: bigint;
   active: boolean;
   metadataHash: Bytes;
+  minAttendanceMinutes: number;
+  minSessionsAttended: number;
 };
 
 export type InkStorageTraitsImplsResolverKey = {};
@@ -30,6 +32,9 @@ export type ContractsEduverse = {
   studentEnrollments: InkStorageLazyMapping;
   teacherCourses: InkStorageLazyMapping;
   courseStudents: InkStorageLazyMapping;
+  courseCompletions: InkStorageLazyMapping;
+  nftContract: AccountId32;
+  attestationSigner: AccountId32;
 };
 
 export type InkPrimitivesLangError = "CouldNotReadInput";
@@ -42,6 +47,19 @@ export type ContractsEduverseError =
   | "InsufficientPayment"
   | "Unauthorized"
   | "CourseNotActive"
-  | "InvalidTime";
+  | "InvalidTime"
+  | "NFTMintingFailed"
+  | "AttestationRequired"
+  | "InvalidAttestation"
+  | "InsufficientAttendance";
+
+/**
+ * The classroom server's signed statement of how much of a course a student attended.
+ **/
+export type ContractsEduverseAttendanceAttestation = {
+  minutesAttended: number;
+  sessionsAttended: number;
+  signature: FixedBytes<64>;
+};
 
 export type InkEnvNoChainExtension = null;
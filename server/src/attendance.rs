@@ -0,0 +1,377 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mediasoup::prelude::MediaKind;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::identity::{AccountIdentity, ParticipantId};
+use crate::roles::{Permission, Role};
+use crate::room_manager::RoomManager;
+use crate::user::User;
+
+// Reports of finished sessions, a JSON and a CSV file per session
+const ATTENDANCE_DIR: &str = "attendance";
+// How often the room tick samples who's present, on camera and has their mic on
+pub(crate) const SAMPLE_SECS: u64 = 1;
+// Embedded database with every account's totals per course across finished sessions,
+// one tree per course. Attendance attestations are signed over these.
+const ATTENDANCE_DB_PATH: &str = "attendance_db";
+
+static ATTENDANCE_DB: OnceLock<sled::Db> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttendanceEventKind {
+    Join,
+    Leave,
+    // Socket dropped, the user still holds their place until the grace period runs out
+    Disconnect,
+    Reconnect,
+}
+
+#[derive(Clone, Serialize)]
+pub struct AttendanceEvent {
+    pub(crate) kind: AttendanceEventKind,
+    // Unix seconds
+    pub(crate) timestamp: u64,
+    // Only with multi-device logins
+    pub(crate) device: Option<String>,
+}
+
+/// One account's attendance in a session. Time is counted once per account,
+/// however many devices it's signed in from.
+#[derive(Clone, Default, Serialize)]
+pub struct AttendanceRecord {
+    pub(crate) role: Option<Role>,
+    pub(crate) events: Vec<AttendanceEvent>,
+    pub(crate) seconds_present: u64,
+    // A live video producer that isn't a screen share
+    pub(crate) seconds_on_camera: u64,
+    // A live audio producer, whether or not anything is said into it
+    pub(crate) seconds_mic_on: u64,
+}
+
+impl AttendanceRecord {
+    fn first_joined(&self) -> Option<u64> {
+        self.events
+            .iter()
+            .find(|event| event.kind == AttendanceEventKind::Join)
+            .map(|event| event.timestamp)
+    }
+
+    fn last_left(&self) -> Option<u64> {
+        self.events
+            .iter()
+            .rev()
+            .find(|event| event.kind == AttendanceEventKind::Leave)
+            .map(|event| event.timestamp)
+    }
+
+    fn joins(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|event| event.kind == AttendanceEventKind::Join)
+            .count()
+    }
+}
+
+/// Who was in a room from the moment the first person joined until the last one left.
+#[derive(Clone, Serialize)]
+pub struct AttendanceSession {
+    pub(crate) room_id: u32,
+    // Unix seconds
+    pub(crate) started_at: u64,
+    pub(crate) ended_at: Option<u64>,
+    pub(crate) records: BTreeMap<AccountIdentity, AttendanceRecord>,
+}
+
+/// An account's attendance summed over every finished session of a course.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct AttendanceTotals {
+    pub(crate) seconds_present: u64,
+    // Sessions the account was present in for any time at all
+    pub(crate) sessions: u32,
+}
+
+/// Accounts seen in one sample of the room.
+#[derive(Default)]
+pub(crate) struct AttendanceSample {
+    pub(crate) present: HashSet<AccountIdentity>,
+    pub(crate) on_camera: HashSet<AccountIdentity>,
+    pub(crate) mic_on: HashSet<AccountIdentity>,
+}
+
+impl AttendanceSession {
+    pub(crate) fn new(room_id: u32) -> Self {
+        AttendanceSession {
+            room_id,
+            started_at: now_secs(),
+            ended_at: None,
+            records: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn record_event(
+        &mut self,
+        user_id: &ParticipantId,
+        role: Option<Role>,
+        kind: AttendanceEventKind,
+    ) {
+        let record = self.records.entry(user_id.account().clone()).or_default();
+        if role.is_some() {
+            record.role = role;
+        }
+        record.events.push(AttendanceEvent {
+            kind,
+            timestamp: now_secs(),
+            device: user_id.device().map(str::to_string),
+        });
+    }
+
+    pub(crate) fn end(&mut self) {
+        self.ended_at = Some(now_secs());
+    }
+
+    pub(crate) fn add_sample(&mut self, sample: &AttendanceSample, seconds: u64) {
+        for account in sample.present.iter() {
+            let record = self.records.entry(account.clone()).or_default();
+            record.seconds_present += seconds;
+            if sample.on_camera.contains(account) {
+                record.seconds_on_camera += seconds;
+            }
+            if sample.mic_on.contains(account) {
+                record.seconds_mic_on += seconds;
+            }
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let records: Vec<_> = self
+            .records
+            .iter()
+            .map(|(account, record)| {
+                serde_json::json!({
+                    "account": account,
+                    "role": record.role,
+                    "first_joined": record.first_joined(),
+                    "last_left": record.last_left(),
+                    "joins": record.joins(),
+                    "seconds_present": record.seconds_present,
+                    "seconds_on_camera": record.seconds_on_camera,
+                    "seconds_mic_on": record.seconds_mic_on,
+                    "events": record.events
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "room_id": self.room_id,
+            "started_at": self.started_at,
+            "ended_at": self.ended_at,
+            "attendees": records
+        })
+    }
+
+    // One row per account, the full event log is only in the JSON export
+    fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "account,role,first_joined,last_left,joins,seconds_present,seconds_on_camera,seconds_mic_on\n",
+        );
+        for (account, record) in self.records.iter() {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{}",
+                account,
+                record.role.map(|role| role.as_str()).unwrap_or(""),
+                record
+                    .first_joined()
+                    .map(|t| t.to_string())
+                    .unwrap_or_default(),
+                record
+                    .last_left()
+                    .map(|t| t.to_string())
+                    .unwrap_or_default(),
+                record.joins(),
+                record.seconds_present,
+                record.seconds_on_camera,
+                record.seconds_mic_on
+            );
+        }
+        csv
+    }
+}
+
+/// Opens the attendance store, call once at startup.
+pub fn init() -> Result<(), Box<dyn Error>> {
+    let db = sled::open(ATTENDANCE_DB_PATH)?;
+    ATTENDANCE_DB
+        .set(db)
+        .map_err(|_| "Attendance store already initialized")?;
+    Ok(())
+}
+
+fn course_totals(course_id: u32) -> Result<sled::Tree, Box<dyn Error + Send + Sync>> {
+    let db = ATTENDANCE_DB
+        .get()
+        .ok_or("Attendance store not initialized")?;
+    Ok(db.open_tree(format!("course_{}", course_id))?)
+}
+
+// Rooms are keyed by course id, so a finished session counts towards that course
+fn add_to_totals(session: &AttendanceSession) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let totals = course_totals(session.room_id)?;
+    for (account, record) in session.records.iter() {
+        if record.seconds_present == 0 {
+            continue;
+        }
+        totals.update_and_fetch(account.as_bytes(), |current| {
+            let mut account_totals: AttendanceTotals = current
+                .and_then(|value| serde_json::from_slice(value).ok())
+                .unwrap_or_default();
+            account_totals.seconds_present += record.seconds_present;
+            account_totals.sessions += 1;
+            serde_json::to_vec(&account_totals).ok()
+        })?;
+    }
+    Ok(())
+}
+
+/// What an account attended of a course, over the sessions that have ended.
+pub(crate) fn totals(
+    course_id: u32,
+    account: &AccountIdentity,
+) -> Result<AttendanceTotals, Box<dyn Error + Send + Sync>> {
+    match course_totals(course_id)?.get(account.as_bytes())? {
+        Some(value) => Ok(serde_json::from_slice(&value)?),
+        None => Ok(AttendanceTotals::default()),
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Adds a join, leave, disconnect or reconnect to the room's current session.
+/// Breakout rooms count towards the classroom they were split from.
+pub(crate) async fn record(
+    room_id: u32,
+    user_id: &ParticipantId,
+    role: Option<Role>,
+    kind: AttendanceEventKind,
+) {
+    let room_manager = RoomManager::instance();
+    let course_id = room_manager.course_of(room_id).await;
+    room_manager
+        .record_attendance(course_id, user_id, role, kind)
+        .await;
+}
+
+/// Credits everyone in the room with `SAMPLE_SECS` of presence, camera and mic time,
+/// going by their connection and producers right now.
+pub(crate) async fn sample(room_id: u32) {
+    let room_manager = RoomManager::instance();
+    let Some(users) = room_manager.room_users(room_id).await else {
+        return;
+    };
+    let screen_producers = room_manager.screen_producers(room_id).await;
+
+    let mut sample = AttendanceSample::default();
+    for user_arc in users {
+        let user = user_arc.lock().await;
+        // Dropped connections don't count until they're back
+        if user.reconnecting {
+            continue;
+        }
+        let Some(user_id) = user.id.as_ref() else {
+            continue;
+        };
+        let account = user_id.account();
+        sample.present.insert(account.clone());
+        if user
+            .live_producers(MediaKind::Video)
+            .iter()
+            .any(|producer_id| !screen_producers.contains(producer_id))
+        {
+            sample.on_camera.insert(account.clone());
+        }
+        if !user.live_producers(MediaKind::Audio).is_empty() {
+            sample.mic_on.insert(account.clone());
+        }
+    }
+
+    let course_id = room_manager.course_of(room_id).await;
+    room_manager
+        .add_attendance_sample(course_id, &sample, SAMPLE_SECS)
+        .await;
+}
+
+/// Called after someone left for good. Once the room is empty the session is over,
+/// and its report is written to disk.
+pub(crate) async fn end_session_if_empty(room_id: u32) {
+    let room_manager = RoomManager::instance();
+    let room_id = room_manager.course_of(room_id).await;
+    let Some(session) = room_manager.end_attendance_if_empty(room_id).await else {
+        return;
+    };
+    if let Err(e) = add_to_totals(&session) {
+        eprintln!(
+            "Failed to update attendance totals for course {}: {}",
+            room_id, e
+        );
+    }
+    if let Err(e) = export(&session).await {
+        eprintln!(
+            "Failed to write attendance report for room {}: {}",
+            room_id, e
+        );
+    }
+}
+
+async fn export(session: &AttendanceSession) -> Result<(), Box<dyn Error + Send + Sync>> {
+    tokio::fs::create_dir_all(ATTENDANCE_DIR).await?;
+    let path = format!(
+        "{}/room_{}_{}",
+        ATTENDANCE_DIR, session.room_id, session.started_at
+    );
+    let json = serde_json::to_vec_pretty(&session.to_json())?;
+    tokio::fs::write(format!("{}.json", path), json).await?;
+    tokio::fs::write(format!("{}.csv", path), session.to_csv()).await?;
+    println!(
+        "Attendance for room {} written to {}.json",
+        session.room_id, path
+    );
+    Ok(())
+}
+
+/// Sends the teacher the current session's attendance so far.
+pub async fn attendance_report(user_arc: Arc<Mutex<User>>) {
+    let room_id = {
+        let user = user_arc.lock().await;
+        if !user.can(Permission::ManageRoom) {
+            user.reject_action("attendance_report", "not_permitted");
+            return;
+        }
+        let Some(room_id) = user.room_id else {
+            return;
+        };
+        room_id
+    };
+
+    let room_manager = RoomManager::instance();
+    let course_id = room_manager.course_of(room_id).await;
+    let report = room_manager
+        .attendance_session(course_id)
+        .await
+        .map(|session| session.to_json());
+    let report_message = serde_json::json!({
+        "type": "attendance_report",
+        "report": report
+    });
+    user_arc.lock().await.send(report_message.to_string());
+}
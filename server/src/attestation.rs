@@ -0,0 +1,214 @@
+use std::env;
+use std::sync::Arc;
+
+use codec::Encode;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sp_core::{sr25519, ByteArray, Pair};
+use tokio::sync::Mutex;
+
+use crate::attendance;
+use crate::identity::AccountIdentity;
+use crate::polls::{self, PollScore};
+use crate::roles::Permission;
+use crate::room_manager::RoomManager;
+use crate::user::User;
+use crate::ws_payload::AttestationPayload;
+
+// Has to match `ATTESTATION_DOMAIN` in the course contract
+const ATTESTATION_DOMAIN: &[u8] = b"eduverse-attendance";
+
+lazy_static! {
+    // The server's sr25519 key as a secret URI or hex seed, the contract is deployed with
+    // its public key. Without one the server doesn't hand out attestations.
+    static ref ATTESTATION_KEY: Option<sr25519::Pair> =
+        match env::var("EDUVERSE_ATTESTATION_KEY") {
+            Ok(secret) => match sr25519::Pair::from_string(&secret, None) {
+                Ok(pair) => Some(pair),
+                Err(e) => {
+                    eprintln!("Invalid EDUVERSE_ATTESTATION_KEY: {:?}", e);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+    // Share of a course's quizzes a student has to get right before they're attested, 0 for none
+    static ref MIN_QUIZ_PERCENT: usize = env::var("EDUVERSE_MIN_QUIZ_PERCENT")
+        .ok()
+        .and_then(|percent| percent.parse().ok())
+        .unwrap_or(0);
+}
+
+/// The server's word on how much of a course a student attended, for `complete_course`.
+#[derive(Serialize)]
+pub struct AttendanceAttestation {
+    pub(crate) course_id: u32,
+    pub(crate) student: AccountIdentity,
+    pub(crate) minutes_attended: u32,
+    pub(crate) sessions_attended: u32,
+    // Not part of the signature, for the teacher deciding on completion
+    pub(crate) quiz_score: PollScore,
+    pub(crate) signer: AccountIdentity,
+    // sr25519 signature over `message`, hex encoded
+    pub(crate) signature: String,
+}
+
+/// The account attestations are signed with, if the server has a key.
+pub fn signer() -> Option<AccountIdentity> {
+    let pair = ATTESTATION_KEY.as_ref()?;
+    AccountIdentity::from_bytes(pair.public().as_slice()).ok()
+}
+
+/// The bytes that get signed, the same as `attestation_message` in the course contract:
+/// the domain prefix, then the SCALE encoded (course id, student, minutes, sessions).
+pub fn message(
+    course_id: u32,
+    student: &AccountIdentity,
+    minutes_attended: u32,
+    sessions_attended: u32,
+) -> Vec<u8> {
+    let mut message = ATTESTATION_DOMAIN.to_vec();
+    (
+        course_id,
+        student.as_bytes(),
+        minutes_attended,
+        sessions_attended,
+    )
+        .encode_to(&mut message);
+    message
+}
+
+/// Whether the student got enough of the course's quizzes right to be attested.
+/// Courses without quizzes have nothing to fail.
+pub(crate) fn passes_quizzes(score: &PollScore, min_percent: usize) -> bool {
+    score.correct * 100 >= score.quizzes * min_percent
+}
+
+/// Signs what the student attended of the course, over the sessions that have ended.
+/// Students below the server's quiz minimum don't get one.
+pub(crate) fn attest(
+    course_id: u32,
+    student: &AccountIdentity,
+) -> Result<AttendanceAttestation, &'static str> {
+    let pair = ATTESTATION_KEY.as_ref().ok_or("attestation_unavailable")?;
+    let signer = signer().ok_or("attestation_unavailable")?;
+    let totals = attendance::totals(course_id, student).map_err(|e| {
+        eprintln!(
+            "Failed to read attendance of {} in course {}: {}",
+            student, course_id, e
+        );
+        "attendance_unavailable"
+    })?;
+    let quiz_score = polls::score(course_id, student).map_err(|e| {
+        eprintln!(
+            "Failed to read poll scores of {} in course {}: {}",
+            student, course_id, e
+        );
+        "poll_unavailable"
+    })?;
+    if !passes_quizzes(&quiz_score, *MIN_QUIZ_PERCENT) {
+        return Err("insufficient_quiz_score");
+    }
+
+    let minutes_attended = u32::try_from(totals.seconds_present / 60).unwrap_or(u32::MAX);
+    let signature = pair.sign(&message(
+        course_id,
+        student,
+        minutes_attended,
+        totals.sessions,
+    ));
+    Ok(AttendanceAttestation {
+        course_id,
+        student: student.clone(),
+        minutes_attended,
+        sessions_attended: totals.sessions,
+        quiz_score,
+        signer,
+        signature: hex::encode(signature),
+    })
+}
+
+/// Students get an attestation for themselves, the teacher for any student.
+pub async fn attendance_attestation(user_arc: Arc<Mutex<User>>, payload: AttestationPayload) {
+    let user = user_arc.lock().await;
+    let (Some(room_id), Some(user_id)) = (user.room_id, user.id.as_ref()) else {
+        return;
+    };
+    // Asked from a breakout room it's still the classroom's course
+    let course_id = RoomManager::instance().course_of(room_id).await;
+    let student = payload.student.unwrap_or_else(|| user_id.account().clone());
+    if &student != user_id.account() && !user.can(Permission::ManageRoom) {
+        user.reject_action("attendance_attestation", "not_permitted");
+        return;
+    }
+
+    match attest(course_id, &student) {
+        Ok(attestation) => {
+            let attestation_message = serde_json::json!({
+                "type": "attendance_attestation",
+                "attestation": attestation
+            });
+            user.send(attestation_message.to_string());
+        }
+        Err(reason) => user.reject_action("attendance_attestation", reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_is_domain_then_scale_encoded_fields() {
+        let student = AccountIdentity::from([9; 32]);
+        let mut expected = b"eduverse-attendance".to_vec();
+        expected.extend_from_slice(&[7, 0, 0, 0]);
+        expected.extend_from_slice(&[9; 32]);
+        expected.extend_from_slice(&[0x2c, 0x01, 0, 0]);
+        expected.extend_from_slice(&[3, 0, 0, 0]);
+        assert_eq!(message(7, &student, 300, 3), expected);
+    }
+
+    #[test]
+    fn every_field_is_covered_by_the_message() {
+        let student = AccountIdentity::from([9; 32]);
+        let base = message(7, &student, 300, 3);
+        assert_ne!(base, message(8, &student, 300, 3));
+        assert_ne!(base, message(7, &AccountIdentity::from([8; 32]), 300, 3));
+        assert_ne!(base, message(7, &student, 301, 3));
+        assert_ne!(base, message(7, &student, 300, 4));
+    }
+
+    #[test]
+    fn signatures_verify_against_the_signer_account() {
+        let (pair, _) = sr25519::Pair::generate();
+        let signer = AccountIdentity::from_bytes(pair.public().as_slice()).unwrap();
+        let student = AccountIdentity::from([9; 32]);
+        let signature = pair.sign(&message(7, &student, 300, 3));
+        assert!(sr25519::Pair::verify(
+            &signature,
+            message(7, &student, 300, 3),
+            &signer.public_key()
+        ));
+        assert!(!sr25519::Pair::verify(
+            &signature,
+            message(7, &student, 600, 3),
+            &signer.public_key()
+        ));
+    }
+
+    #[test]
+    fn quiz_minimum_is_a_share_of_the_quizzes() {
+        let score = |quizzes, correct| PollScore {
+            polls: quizzes,
+            answered: quizzes,
+            quizzes,
+            correct,
+        };
+        assert!(passes_quizzes(&score(0, 0), 80));
+        assert!(passes_quizzes(&score(4, 0), 0));
+        assert!(passes_quizzes(&score(5, 4), 80));
+        assert!(!passes_quizzes(&score(5, 3), 80));
+        assert!(passes_quizzes(&score(3, 3), 100));
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::attendance;
+use crate::chat;
+use crate::identity::ParticipantId;
+use crate::roles::{Permission, Role};
+use crate::room_manager::RoomManager;
+use crate::user::User;
+use crate::ws_payload::{
+    AssignBreakoutPayload, BreakoutBroadcastPayload, StartBreakoutsPayload, VisitBreakoutPayload,
+};
+
+const MAX_BREAKOUTS: usize = 50;
+// How long breakouts run when the teacher doesn't say, and the longest they can
+const DEFAULT_BREAKOUT_SECS: u64 = 900;
+const MAX_BREAKOUT_SECS: u64 = 3 * 3600;
+// Everyone gets a heads-up this long before they're brought back
+const ENDING_WARNING: Duration = Duration::from_secs(60);
+// Who can split the room, visit breakouts and talk to all of them
+const BREAKOUT_PERMISSION: Permission = Permission::ManageRoom;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakoutAssignment {
+    Random,
+    // Students sitting next to each other end up together
+    BySeat,
+    // The teacher's own groups, anyone left out stays in the main room
+    Manual,
+}
+
+/// The breakout rooms a classroom is split into, kept on the main room.
+#[derive(Clone, Debug, Serialize)]
+pub struct BreakoutSession {
+    pub(crate) rooms: Vec<u32>,
+    // Where each student was sent, teachers visiting don't count
+    pub(crate) assignments: HashMap<ParticipantId, u32>,
+    // Unix millis, clients count down to it
+    pub(crate) ends_at: u64,
+    #[serde(skip)]
+    pub(crate) generation: u64,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// The main room the teacher is in, if they may run breakouts
+async fn teacher_course(user_arc: &Arc<Mutex<User>>, action: &str) -> Option<u32> {
+    let user = user_arc.lock().await;
+    if !user.can(BREAKOUT_PERMISSION) {
+        user.reject_action(action, "not_permitted");
+        return None;
+    }
+    let room_id = user.room_id?;
+    Some(RoomManager::instance().course_of(room_id).await)
+}
+
+// Splits students into `count` groups
+fn group(
+    mut students: Vec<(ParticipantId, (i32, i32))>,
+    count: usize,
+    assignment: BreakoutAssignment,
+) -> Vec<Vec<ParticipantId>> {
+    match assignment {
+        BreakoutAssignment::BySeat => students.sort_by_key(|(_, (x, y))| (*y, *x)),
+        _ => students.shuffle(&mut rand::thread_rng()),
+    }
+    let total = students.len();
+    let mut groups = vec![vec![]; count];
+    for (index, (user_id, _)) in students.into_iter().enumerate() {
+        // Contiguous runs, so seat neighbours stay together
+        groups[index * count / total].push(user_id);
+    }
+    groups
+}
+
+// Manual groups may only hold students in the room, each in at most one group,
+// and no group may be empty
+fn valid_groups(groups: &[Vec<ParticipantId>], students: &[(ParticipantId, (i32, i32))]) -> bool {
+    let mut seen = HashSet::new();
+    groups.iter().all(|group| !group.is_empty())
+        && groups
+            .iter()
+            .flatten()
+            .all(|user_id| students.iter().any(|(id, _)| id == user_id) && seen.insert(user_id))
+}
+
+/// Splits the main room into breakout rooms, each with its own router and chat,
+/// and sends the students off to them.
+pub async fn start_breakouts(user_arc: Arc<Mutex<User>>, payload: StartBreakoutsPayload) {
+    let Some(course_id) = teacher_course(&user_arc, "start_breakouts").await else {
+        return;
+    };
+    let room_manager = RoomManager::instance();
+    if room_manager.breakouts(course_id).await.is_some() {
+        user_arc
+            .lock()
+            .await
+            .reject_action("start_breakouts", "breakouts_running");
+        return;
+    }
+
+    let mut students = vec![];
+    for student_arc in room_manager.room_users(course_id).await.unwrap_or_default() {
+        let student = student_arc.lock().await;
+        if let (Some(user_id), Some(Role::Student)) = (student.id.clone(), student.role) {
+            students.push((user_id, student.coordinates));
+        }
+    }
+    let groups = match payload.assignment {
+        BreakoutAssignment::Manual => {
+            if !valid_groups(&payload.groups, &students) {
+                user_arc
+                    .lock()
+                    .await
+                    .reject_action("start_breakouts", "invalid_groups");
+                return;
+            }
+            payload.groups
+        }
+        _ => {
+            // No empty rooms
+            let count = payload.count.unwrap_or(0).min(students.len());
+            if count == 0 {
+                user_arc
+                    .lock()
+                    .await
+                    .reject_action("start_breakouts", "no_students");
+                return;
+            }
+            group(students, count, payload.assignment)
+        }
+    };
+    if groups.is_empty() || groups.len() > MAX_BREAKOUTS {
+        user_arc
+            .lock()
+            .await
+            .reject_action("start_breakouts", "invalid_count");
+        return;
+    }
+
+    let rooms = match room_manager
+        .create_breakout_rooms(course_id, groups.len())
+        .await
+    {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            eprintln!("Failed to open breakout rooms for {}: {}", course_id, e);
+            user_arc
+                .lock()
+                .await
+                .reject_action("start_breakouts", "breakouts_unavailable");
+            return;
+        }
+    };
+    let duration = Duration::from_secs(
+        payload
+            .duration_secs
+            .unwrap_or(DEFAULT_BREAKOUT_SECS)
+            .clamp(60, MAX_BREAKOUT_SECS),
+    );
+    let assignments: HashMap<ParticipantId, u32> = groups
+        .iter()
+        .zip(rooms.iter())
+        .flat_map(|(group, room_id)| group.iter().map(|user_id| (user_id.clone(), *room_id)))
+        .collect();
+    let session = BreakoutSession {
+        rooms: rooms.clone(),
+        assignments,
+        ends_at: now_millis() + duration.as_millis() as u64,
+        generation: 0,
+    };
+    // Someone else started breakouts while the rooms were being opened
+    let Some(session) = room_manager.start_breakouts(course_id, session).await else {
+        for room_id in rooms {
+            room_manager.close_room(room_id).await;
+        }
+        user_arc
+            .lock()
+            .await
+            .reject_action("start_breakouts", "breakouts_running");
+        return;
+    };
+
+    for (user_id, room_id) in session.assignments.iter() {
+        let Some(student_arc) = room_manager.find_user(course_id, user_id).await else {
+            continue;
+        };
+        if let Err(reason) = User::move_to_room(student_arc, *room_id).await {
+            eprintln!(
+                "Couldn't send {} to breakout {}: {}",
+                user_id, room_id, reason
+            );
+        }
+    }
+
+    let started_message = serde_json::json!({
+        "type": "breakouts_started",
+        "breakouts": session,
+        "duration_secs": duration.as_secs()
+    });
+    broadcast_all(course_id, &session.rooms, started_message.to_string()).await;
+
+    // Only ends these breakouts, if they were closed and restarted in the meantime it's left alone
+    let generation = session.generation;
+    tokio::spawn(async move {
+        if let Some(until_warning) = duration.checked_sub(ENDING_WARNING) {
+            tokio::time::sleep(until_warning).await;
+            let Some(session) = RoomManager::instance().breakouts(course_id).await else {
+                return;
+            };
+            if session.generation != generation {
+                return;
+            }
+            let ending_message = serde_json::json!({
+                "type": "breakouts_ending",
+                "seconds_left": ENDING_WARNING.as_secs()
+            });
+            broadcast_all(course_id, &session.rooms, ending_message.to_string()).await;
+        }
+        tokio::time::sleep(duration.min(ENDING_WARNING)).await;
+        end_breakouts(course_id, Some(generation), "expired").await;
+    });
+}
+
+/// Brings everyone back early.
+pub async fn close_breakouts(user_arc: Arc<Mutex<User>>) {
+    let Some(course_id) = teacher_course(&user_arc, "close_breakouts").await else {
+        return;
+    };
+    end_breakouts(course_id, None, "closed").await;
+}
+
+// Moves everyone in a breakout, visiting teachers included, back to the main room
+// and closes the breakout rooms
+async fn end_breakouts(course_id: u32, generation: Option<u64>, reason: &str) {
+    let room_manager = RoomManager::instance();
+    let Some(session) = room_manager.end_breakouts(course_id, generation).await else {
+        return;
+    };
+    for room_id in session.rooms.iter() {
+        for user_arc in room_manager.room_users(*room_id).await.unwrap_or_default() {
+            if let Err(reason) = User::move_to_room(user_arc, course_id).await {
+                eprintln!(
+                    "Couldn't bring a user back from breakout {}: {}",
+                    room_id, reason
+                );
+            }
+        }
+        room_manager.close_room(*room_id).await;
+    }
+
+    let ended_message = serde_json::json!({
+        "type": "breakouts_ended",
+        "reason": reason
+    });
+    room_manager
+        .broadcast_message(None, course_id, ended_message.to_string())
+        .await;
+    // Students may have left for good while they were away
+    attendance::end_session_if_empty(course_id).await;
+}
+
+/// The teacher drops into a breakout, or with no room id back into the main room.
+pub async fn visit_breakout(user_arc: Arc<Mutex<User>>, payload: VisitBreakoutPayload) {
+    let Some(course_id) = teacher_course(&user_arc, "visit_breakout").await else {
+        return;
+    };
+    let target = payload.room_id.unwrap_or(course_id);
+    let is_breakout = RoomManager::instance()
+        .breakouts(course_id)
+        .await
+        .is_some_and(|session| session.rooms.contains(&target));
+    if target != course_id && !is_breakout {
+        user_arc
+            .lock()
+            .await
+            .reject_action("visit_breakout", "room_not_found");
+        return;
+    }
+    if let Err(reason) = User::move_to_room(user_arc.clone(), target).await {
+        user_arc
+            .lock()
+            .await
+            .reject_action("visit_breakout", reason);
+    }
+}
+
+/// Sends a student to a breakout, e.g. someone who joined after the split.
+pub async fn assign_breakout(user_arc: Arc<Mutex<User>>, payload: AssignBreakoutPayload) {
+    let Some(course_id) = teacher_course(&user_arc, "assign_breakout").await else {
+        return;
+    };
+    let room_manager = RoomManager::instance();
+    let Some(session) = room_manager.breakouts(course_id).await else {
+        user_arc
+            .lock()
+            .await
+            .reject_action("assign_breakout", "no_breakouts");
+        return;
+    };
+    if !session.rooms.contains(&payload.room_id) {
+        user_arc
+            .lock()
+            .await
+            .reject_action("assign_breakout", "room_not_found");
+        return;
+    }
+    let Some(target_arc) = room_manager
+        .find_in_course(course_id, &payload.target)
+        .await
+    else {
+        user_arc
+            .lock()
+            .await
+            .reject_action("assign_breakout", "target_not_found");
+        return;
+    };
+
+    match User::move_to_room(target_arc, payload.room_id).await {
+        Ok(()) => {
+            room_manager
+                .assign_breakout(course_id, payload.target, payload.room_id)
+                .await;
+        }
+        Err(reason) => user_arc
+            .lock()
+            .await
+            .reject_action("assign_breakout", reason),
+    }
+}
+
+/// A message from the teacher to every breakout at once.
+pub async fn breakout_broadcast(user_arc: Arc<Mutex<User>>, payload: BreakoutBroadcastPayload) {
+    let Some(course_id) = teacher_course(&user_arc, "breakout_broadcast").await else {
+        return;
+    };
+    let content = match chat::check_content(&payload.content) {
+        Ok(content) => content,
+        Err(reason) => {
+            user_arc
+                .lock()
+                .await
+                .reject_action("breakout_broadcast", reason);
+            return;
+        }
+    };
+    let Some(session) = RoomManager::instance().breakouts(course_id).await else {
+        user_arc
+            .lock()
+            .await
+            .reject_action("breakout_broadcast", "no_breakouts");
+        return;
+    };
+
+    let sender = user_arc.lock().await.id.clone();
+    let announcement_message = serde_json::json!({
+        "type": "breakout_announcement",
+        "sender": sender,
+        "content": content
+    });
+    broadcast_all(course_id, &session.rooms, announcement_message.to_string()).await;
+}
+
+async fn broadcast_all(course_id: u32, rooms: &[u32], message: String) {
+    let room_manager = RoomManager::instance();
+    for room_id in std::iter::once(&course_id).chain(rooms.iter()) {
+        room_manager
+            .broadcast_message(None, *room_id, message.clone())
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::AccountIdentity;
+
+    fn participant(byte: u8) -> ParticipantId {
+        AccountIdentity::from([byte; 32]).into()
+    }
+
+    fn students(bytes: &[u8]) -> Vec<(ParticipantId, (i32, i32))> {
+        bytes
+            .iter()
+            .map(|byte| (participant(*byte), (0, 0)))
+            .collect()
+    }
+
+    #[test]
+    fn manual_groups_may_leave_students_out() {
+        let groups = vec![vec![participant(1)], vec![participant(2), participant(3)]];
+        assert!(valid_groups(&groups, &students(&[1, 2, 3, 4])));
+    }
+
+    #[test]
+    fn manual_groups_reject_duplicates() {
+        let groups = vec![vec![participant(1)], vec![participant(2), participant(1)]];
+        assert!(!valid_groups(&groups, &students(&[1, 2])));
+        let groups = vec![vec![participant(1), participant(1)]];
+        assert!(!valid_groups(&groups, &students(&[1])));
+    }
+
+    #[test]
+    fn manual_groups_reject_empty_groups() {
+        let groups = vec![vec![participant(1)], vec![]];
+        assert!(!valid_groups(&groups, &students(&[1, 2])));
+    }
+
+    #[test]
+    fn manual_groups_reject_non_members() {
+        let groups = vec![vec![participant(1), participant(9)]];
+        assert!(!valid_groups(&groups, &students(&[1, 2])));
+    }
+}
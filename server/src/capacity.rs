@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::identity::AccountIdentity;
+use crate::roles::Role;
+use crate::room_manager::RoomManager;
+use crate::user::User;
+
+lazy_static! {
+    // Co-teachers and TAs in a room at once, the teacher always gets in on top
+    static ref MAX_STAFF: u32 = count_from_env("EDUVERSE_MAX_STAFF", 10);
+    static ref MAX_OBSERVERS: u32 = count_from_env("EDUVERSE_MAX_OBSERVERS", 20);
+}
+
+fn count_from_env(name: &str, default: u32) -> u32 {
+    env::var(name)
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(default)
+}
+
+/// What the contract said about the course when it was created.
+#[derive(Clone, Debug)]
+pub struct CourseParams {
+    pub(crate) teacher: AccountIdentity,
+    // 0 means no limit
+    pub(crate) max_students: u32,
+    // Unix millis, as the contract keeps them
+    pub(crate) start_time: u64,
+    pub(crate) end_time: u64,
+    pub(crate) metadata_hash: Vec<u8>,
+}
+
+/// Who's in a classroom and its breakout rooms right now. Every account counts once,
+/// however many devices it's signed in from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Occupancy {
+    pub(crate) students: u32,
+    // Teacher, co-teachers and TAs
+    pub(crate) staff: u32,
+    pub(crate) observers: u32,
+}
+
+impl Occupancy {
+    pub(crate) fn count(seated: &HashMap<AccountIdentity, Role>) -> Self {
+        let mut occupancy = Occupancy::default();
+        for role in seated.values() {
+            match role {
+                Role::Student => occupancy.students += 1,
+                Role::Observer => occupancy.observers += 1,
+                Role::Teacher | Role::CoTeacher | Role::TeachingAssistant => occupancy.staff += 1,
+            }
+        }
+        occupancy
+    }
+}
+
+/// How many of each a room takes. Students are capped by the course's `max_students`,
+/// staff and observers by server settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Capacity {
+    // `None` for courses without a limit
+    pub(crate) students: Option<u32>,
+    pub(crate) staff: u32,
+    pub(crate) observers: u32,
+}
+
+impl Capacity {
+    pub(crate) fn for_course(course: &CourseParams) -> Self {
+        Capacity {
+            students: (course.max_students > 0).then_some(course.max_students),
+            staff: *MAX_STAFF,
+            observers: *MAX_OBSERVERS,
+        }
+    }
+
+    /// Whether one more account with this role fits. The teacher is never turned away
+    /// from their own course.
+    pub(crate) fn admits(&self, occupancy: &Occupancy, role: Role) -> Result<(), &'static str> {
+        let full = match role {
+            Role::Teacher => false,
+            Role::Student => self
+                .students
+                .is_some_and(|students| occupancy.students >= students),
+            Role::CoTeacher | Role::TeachingAssistant => occupancy.staff >= self.staff,
+            Role::Observer => occupancy.observers >= self.observers,
+        };
+        if full {
+            return Err("room_full");
+        }
+        Ok(())
+    }
+}
+
+/// Where a classroom is in its schedule, and whether anyone is in it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomPhase {
+    // Before the course's start time
+    Upcoming,
+    // Running, but nobody is in
+    Open,
+    Live,
+    // Past the course's end time, the room stays open for stragglers
+    Ended,
+}
+
+impl RoomPhase {
+    pub(crate) fn of(course: &CourseParams, occupancy: &Occupancy) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        if now < course.start_time {
+            RoomPhase::Upcoming
+        } else if course.end_time > 0 && now > course.end_time {
+            RoomPhase::Ended
+        } else if *occupancy == Occupancy::default() {
+            RoomPhase::Open
+        } else {
+            RoomPhase::Live
+        }
+    }
+}
+
+/// One classroom in the room listing.
+#[derive(Clone, Debug, Serialize)]
+pub struct RoomSummary {
+    pub(crate) course_id: u32,
+    pub(crate) name: String,
+    pub(crate) teacher: AccountIdentity,
+    pub(crate) start_time: u64,
+    pub(crate) end_time: u64,
+    // Hex, clients resolve the course content from it
+    pub(crate) metadata_hash: String,
+    pub(crate) state: RoomPhase,
+    pub(crate) occupancy: Occupancy,
+    pub(crate) capacity: Capacity,
+    pub(crate) locked: bool,
+    pub(crate) waiting_room: bool,
+    // Split into breakout rooms right now
+    pub(crate) breakouts: bool,
+}
+
+/// Every open classroom with its live occupancy. Works before joining, so clients can
+/// show which rooms have space.
+pub async fn list_rooms(user_arc: Arc<Mutex<User>>) {
+    let rooms = RoomManager::instance().room_listing().await;
+    let list_message = serde_json::json!({
+        "type": "room_list",
+        "rooms": rooms
+    });
+    user_arc.lock().await.send(list_message.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn course(max_students: u32, start_time: u64, end_time: u64) -> CourseParams {
+        CourseParams {
+            teacher: AccountIdentity::from([1; 32]),
+            max_students,
+            start_time,
+            end_time,
+            metadata_hash: vec![],
+        }
+    }
+
+    fn capacity() -> Capacity {
+        Capacity {
+            students: Some(2),
+            staff: 1,
+            observers: 1,
+        }
+    }
+
+    #[test]
+    fn counts_each_account_by_role() {
+        let seated = HashMap::from([
+            (AccountIdentity::from([1; 32]), Role::Teacher),
+            (AccountIdentity::from([2; 32]), Role::TeachingAssistant),
+            (AccountIdentity::from([3; 32]), Role::Student),
+            (AccountIdentity::from([4; 32]), Role::Student),
+            (AccountIdentity::from([5; 32]), Role::Observer),
+        ]);
+        let occupancy = Occupancy::count(&seated);
+        assert_eq!(
+            occupancy,
+            Occupancy {
+                students: 2,
+                staff: 2,
+                observers: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn each_role_is_capped_on_its_own() {
+        let occupancy = Occupancy {
+            students: 2,
+            staff: 0,
+            observers: 0,
+        };
+        assert_eq!(
+            capacity().admits(&occupancy, Role::Student),
+            Err("room_full")
+        );
+        assert_eq!(
+            capacity().admits(&occupancy, Role::TeachingAssistant),
+            Ok(())
+        );
+        assert_eq!(capacity().admits(&occupancy, Role::Observer), Ok(()));
+
+        let occupancy = Occupancy {
+            students: 1,
+            staff: 1,
+            observers: 1,
+        };
+        assert_eq!(capacity().admits(&occupancy, Role::Student), Ok(()));
+        assert_eq!(
+            capacity().admits(&occupancy, Role::CoTeacher),
+            Err("room_full")
+        );
+        assert_eq!(
+            capacity().admits(&occupancy, Role::Observer),
+            Err("room_full")
+        );
+    }
+
+    #[test]
+    fn the_teacher_always_gets_in() {
+        let occupancy = Occupancy {
+            students: 2,
+            staff: 5,
+            observers: 1,
+        };
+        assert_eq!(capacity().admits(&occupancy, Role::Teacher), Ok(()));
+    }
+
+    #[test]
+    fn courses_without_max_students_take_any_number() {
+        let capacity = Capacity::for_course(&course(0, 0, 0));
+        assert_eq!(capacity.students, None);
+        let occupancy = Occupancy {
+            students: 10_000,
+            staff: 0,
+            observers: 0,
+        };
+        assert_eq!(capacity.admits(&occupancy, Role::Student), Ok(()));
+        assert_eq!(Capacity::for_course(&course(30, 0, 0)).students, Some(30));
+    }
+
+    #[test]
+    fn phase_follows_the_schedule_then_occupancy() {
+        let empty = Occupancy::default();
+        let busy = Occupancy {
+            students: 1,
+            ..Occupancy::default()
+        };
+        assert_eq!(
+            RoomPhase::of(&course(0, u64::MAX, 0), &busy),
+            RoomPhase::Upcoming
+        );
+        assert_eq!(RoomPhase::of(&course(0, 0, 1), &busy), RoomPhase::Ended);
+        assert_eq!(RoomPhase::of(&course(0, 0, 0), &empty), RoomPhase::Open);
+        assert_eq!(RoomPhase::of(&course(0, 0, 0), &busy), RoomPhase::Live);
+    }
+}
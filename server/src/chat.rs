@@ -0,0 +1,448 @@
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::identity::ParticipantId;
+use crate::metrics;
+
+// Embedded database holding every room's chat log, one tree per room
+const CHAT_DB_PATH: &str = "chat_db";
+// Older messages are dropped once a room's log grows past this
+const HISTORY_LIMIT: usize = 1000;
+// How many recent messages come with `room_joined`
+pub(crate) const JOIN_SNAPSHOT_SIZE: usize = 50;
+// Upper bound for one `chat_history` page
+pub(crate) const MAX_PAGE_SIZE: usize = 100;
+
+// Longest message accepted, in characters
+pub(crate) const MAX_MESSAGE_LENGTH: usize = 1000;
+// Token bucket per user: bursts of up to this many messages...
+const RATE_LIMIT_BURST: f64 = 5.0;
+// ...refilled at this many messages per second
+const RATE_LIMIT_PER_SEC: f64 = 1.0;
+
+static CHAT_DB: OnceLock<sled::Db> = OnceLock::new();
+
+lazy_static! {
+    static ref CHAT_FILTER: ChatFilter = ChatFilter::load(
+        &env::var("EDUVERSE_CHAT_FILTER").unwrap_or_else(|_| "chat_filter.json".to_string())
+    );
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatScope {
+    // One participant, named by `target`
+    Direct,
+    // Users within the sender's view radius, who also see them move
+    #[default]
+    Proximity,
+    // Everyone standing in the sender's zone, e.g. their table
+    Zone,
+    // The whole room, shown prominently. Teachers and co-teachers only.
+    Announcement,
+}
+
+impl ChatScope {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ChatScope::Direct => "direct",
+            ChatScope::Proximity => "proximity",
+            ChatScope::Zone => "zone",
+            ChatScope::Announcement => "announcement",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    // Increasing, also across restarts, so it orders the log
+    pub(crate) id: u64,
+    pub(crate) sender: ParticipantId,
+    pub(crate) scope: ChatScope,
+    // Recipient of a direct message
+    #[serde(default)]
+    pub(crate) target: Option<ParticipantId>,
+    // Zone a zone message was sent in
+    #[serde(default)]
+    pub(crate) zone_id: Option<String>,
+    pub(crate) content: String,
+    // Unix milliseconds, server clock
+    pub(crate) timestamp: u64,
+    // Who the message was delivered to besides the sender, `None` for the whole room.
+    // History only hands a message to someone who could have seen it live.
+    pub(crate) audience: Option<Vec<ParticipantId>>,
+}
+
+impl ChatMessage {
+    pub(crate) fn is_visible_to(&self, user_id: &ParticipantId) -> bool {
+        match self.audience.as_ref() {
+            None => true,
+            Some(audience) => {
+                self.sender.account() == user_id.account()
+                    || audience.iter().any(|id| id.account() == user_id.account())
+            }
+        }
+    }
+
+    /// What clients get, live or from history. The audience stays on the server.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        // Announcements get their own message type so clients can make them stand out
+        let message_type = match self.scope {
+            ChatScope::Announcement => "announcement",
+            _ => "message",
+        };
+        serde_json::json!({
+            "type": message_type,
+            "id": self.id,
+            "sender": self.sender,
+            "scope": self.scope,
+            "target": self.target,
+            "zone_id": self.zone_id,
+            "content": self.content,
+            "timestamp": self.timestamp
+        })
+    }
+}
+
+/// Token bucket limiting how fast one user can send messages.
+pub(crate) struct RateLimiter {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new() -> Self {
+        RateLimiter {
+            tokens: RATE_LIMIT_BURST,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there is one.
+    pub(crate) fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * RATE_LIMIT_PER_SEC).min(RATE_LIMIT_BURST);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FilterMode {
+    // Matches are masked and the message goes through
+    #[default]
+    Replace,
+    // Any match and the message is refused
+    Reject,
+}
+
+// Format of the filter file, e.g.
+// {"mode": "replace", "words": ["darn"], "patterns": ["(?i)free\\s+v-?bucks"]}
+#[derive(Default, Deserialize)]
+struct ChatFilterConfig {
+    #[serde(default)]
+    mode: FilterMode,
+    // Matched as whole words, ignoring case
+    #[serde(default)]
+    words: Vec<String>,
+    // Regular expressions, matched as written
+    #[serde(default)]
+    patterns: Vec<String>,
+    // Stands in for a match, masked with `*` when missing
+    #[serde(default)]
+    replacement: Option<String>,
+}
+
+struct ChatFilter {
+    mode: FilterMode,
+    patterns: Vec<Regex>,
+    replacement: Option<String>,
+}
+
+impl ChatFilter {
+    // A missing or broken filter file means no filtering, it shouldn't take chat down
+    fn load(path: &str) -> Self {
+        let config = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                eprintln!("Invalid chat filter {}: {}", path, e);
+                ChatFilterConfig::default()
+            }),
+            Err(_) => ChatFilterConfig::default(),
+        };
+        Self::from_config(config)
+    }
+
+    fn from_config(config: ChatFilterConfig) -> Self {
+        let word_patterns = config
+            .words
+            .iter()
+            .map(|word| format!(r"(?i)\b{}\b", regex::escape(word)));
+        let patterns = word_patterns
+            .chain(config.patterns)
+            .filter_map(|pattern| match Regex::new(&pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    eprintln!("Skipping chat filter pattern {}: {}", pattern, e);
+                    None
+                }
+            })
+            .collect();
+
+        ChatFilter {
+            mode: config.mode,
+            patterns,
+            replacement: config.replacement,
+        }
+    }
+
+    fn apply(&self, content: &str) -> Result<String, &'static str> {
+        let mut filtered = content.to_string();
+        for pattern in self.patterns.iter() {
+            if !pattern.is_match(&filtered) {
+                continue;
+            }
+            if self.mode == FilterMode::Reject {
+                return Err("message_filtered");
+            }
+            filtered = pattern
+                .replace_all(&filtered, |captures: &regex::Captures| {
+                    match self.replacement.as_ref() {
+                        Some(replacement) => replacement.clone(),
+                        None => "*".repeat(captures[0].chars().count()),
+                    }
+                })
+                .into_owned();
+        }
+        Ok(filtered)
+    }
+}
+
+/// Length limits and the word filter. Returns the content as it should be sent.
+pub(crate) fn check_content(content: &str) -> Result<String, &'static str> {
+    let content = content.trim();
+    if content.is_empty() {
+        return Err("empty_message");
+    }
+    if content.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err("message_too_long");
+    }
+    CHAT_FILTER.apply(content)
+}
+
+/// Opens the chat store, call once at startup.
+pub fn init() -> Result<(), Box<dyn Error>> {
+    let db = sled::open(CHAT_DB_PATH)?;
+    CHAT_DB
+        .set(db)
+        .map_err(|_| "Chat store already initialized")?;
+    Ok(())
+}
+
+fn room_log(room_id: u32) -> Result<sled::Tree, Box<dyn Error + Send + Sync>> {
+    let db = CHAT_DB.get().ok_or("Chat store not initialized")?;
+    Ok(db.open_tree(format!("room_{}", room_id))?)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Where a message goes, as worked out by `RoomManager::chat_delivery`.
+pub(crate) struct Delivery {
+    pub(crate) target: Option<ParticipantId>,
+    pub(crate) zone_id: Option<String>,
+    // Everyone who gets the message besides the sender, `None` for the whole room
+    pub(crate) recipients: Option<HashSet<ParticipantId>>,
+}
+
+/// Gives the message an id and timestamp and appends it to the room's log.
+pub(crate) fn record(
+    room_id: u32,
+    sender: ParticipantId,
+    scope: ChatScope,
+    content: String,
+    delivery: &Delivery,
+) -> Result<ChatMessage, Box<dyn Error + Send + Sync>> {
+    let db = CHAT_DB.get().ok_or("Chat store not initialized")?;
+    let log = room_log(room_id)?;
+    let message = ChatMessage {
+        id: db.generate_id()?,
+        sender,
+        scope,
+        target: delivery.target.clone(),
+        zone_id: delivery.zone_id.clone(),
+        content,
+        timestamp: now_millis(),
+        audience: delivery
+            .recipients
+            .as_ref()
+            .map(|recipients| recipients.iter().cloned().collect()),
+    };
+
+    // Big-endian keys keep the tree sorted by id
+    log.insert(message.id.to_be_bytes(), serde_json::to_vec(&message)?)?;
+    while log.len() > HISTORY_LIMIT {
+        log.pop_min()?;
+    }
+    metrics::CHAT_MESSAGES.inc(scope.as_str());
+    Ok(message)
+}
+
+/// Up to `limit` of the latest messages older than `before` that `user_id` may see,
+/// oldest first, and whether there are more before them.
+pub(crate) fn history(
+    room_id: u32,
+    user_id: &ParticipantId,
+    before: Option<u64>,
+    limit: usize,
+) -> Result<(Vec<ChatMessage>, bool), Box<dyn Error + Send + Sync>> {
+    let log = room_log(room_id)?;
+    let entries = match before {
+        Some(before) => log.range(..before.to_be_bytes()),
+        None => log.iter(),
+    };
+
+    let mut messages = vec![];
+    let mut has_more = false;
+    for entry in entries.rev() {
+        let (_, value) = entry?;
+        let message: ChatMessage = serde_json::from_slice(&value)?;
+        if !message.is_visible_to(user_id) {
+            continue;
+        }
+        if messages.len() == limit {
+            has_more = true;
+            break;
+        }
+        messages.push(message);
+    }
+    messages.reverse();
+    Ok((messages, has_more))
+}
+
+/// Removes a message from the room's log. Returns whether it was there.
+pub(crate) fn delete(room_id: u32, message_id: u64) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let log = room_log(room_id)?;
+    Ok(log.remove(message_id.to_be_bytes())?.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::identity::AccountIdentity;
+
+    fn participant(byte: u8, device: &str) -> ParticipantId {
+        ParticipantId::new(AccountIdentity::from([byte; 32]), Some(device.to_string()))
+    }
+
+    fn filter(mode: FilterMode, replacement: Option<&str>) -> ChatFilter {
+        ChatFilter::from_config(ChatFilterConfig {
+            mode,
+            words: vec!["darn".to_string()],
+            patterns: vec![r"(?i)free\s+v-?bucks".to_string(), "(".to_string()],
+            replacement: replacement.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn rate_limiter_allows_a_burst_then_refills() {
+        let mut limiter = RateLimiter::new();
+        for _ in 0..RATE_LIMIT_BURST as usize {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+
+        limiter.last_refill -= Duration::from_secs(2);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+    }
+
+    #[test]
+    fn rate_limiter_refill_is_capped_at_the_burst() {
+        let mut limiter = RateLimiter::new();
+        limiter.last_refill -= Duration::from_secs(60);
+        for _ in 0..RATE_LIMIT_BURST as usize {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+    }
+
+    #[test]
+    fn filter_masks_whole_words_ignoring_case() {
+        let filter = filter(FilterMode::Replace, None);
+        assert_eq!(
+            filter.apply("Darn it, darn"),
+            Ok("**** it, ****".to_string())
+        );
+        assert_eq!(filter.apply("darnation"), Ok("darnation".to_string()));
+        assert_eq!(
+            filter.apply("get FREE  vbucks"),
+            Ok("get ************".to_string())
+        );
+    }
+
+    #[test]
+    fn filter_uses_the_configured_replacement() {
+        let filter = filter(FilterMode::Replace, Some("[removed]"));
+        assert_eq!(filter.apply("oh darn"), Ok("oh [removed]".to_string()));
+    }
+
+    #[test]
+    fn filter_rejects_matches_in_reject_mode() {
+        let filter = filter(FilterMode::Reject, None);
+        assert_eq!(filter.apply("oh darn"), Err("message_filtered"));
+        assert_eq!(filter.apply("all fine"), Ok("all fine".to_string()));
+    }
+
+    #[test]
+    fn content_must_be_non_empty_and_short_enough() {
+        assert_eq!(check_content("   "), Err("empty_message"));
+        let too_long = "a".repeat(MAX_MESSAGE_LENGTH + 1);
+        assert_eq!(check_content(&too_long), Err("message_too_long"));
+    }
+
+    #[test]
+    fn scoped_messages_are_visible_to_sender_and_audience_only() {
+        let message = ChatMessage {
+            id: 1,
+            sender: participant(1, "laptop"),
+            scope: ChatScope::Direct,
+            target: Some(participant(2, "phone")),
+            zone_id: None,
+            content: "hi".to_string(),
+            timestamp: 0,
+            audience: Some(vec![participant(2, "phone")]),
+        };
+        assert!(message.is_visible_to(&participant(1, "tablet")));
+        assert!(message.is_visible_to(&participant(2, "laptop")));
+        assert!(!message.is_visible_to(&participant(3, "laptop")));
+
+        let announcement = ChatMessage {
+            scope: ChatScope::Announcement,
+            target: None,
+            audience: None,
+            ..message
+        };
+        assert!(announcement.is_visible_to(&participant(3, "laptop")));
+    }
+}
@@ -0,0 +1,436 @@
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+// Maps are exported from Tiled as JSON. `course_<id>.json` is used when present,
+// otherwise every room falls back to `default.json`.
+const MAP_DIR: &str = "maps";
+// Used when no map file can be loaded, matches the old 0..100 spawn area
+const FALLBACK_SIZE: i32 = 100;
+
+// Subset of the Tiled JSON format (https://doc.mapeditor.org/en/stable/reference/json-map-format/)
+#[derive(Deserialize)]
+struct TiledMap {
+    width: i32,
+    height: i32,
+    tilewidth: i32,
+    tileheight: i32,
+    layers: Vec<TiledLayer>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum TiledLayer {
+    #[serde(rename = "tilelayer")]
+    Tiles {
+        name: String,
+        #[serde(default)]
+        data: Vec<u32>,
+        #[serde(default)]
+        properties: Vec<TiledProperty>,
+    },
+    #[serde(rename = "objectgroup")]
+    Objects {
+        #[serde(default)]
+        objects: Vec<TiledObject>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct TiledProperty {
+    name: String,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct TiledObject {
+    #[serde(default)]
+    id: u32,
+    #[serde(default)]
+    name: String,
+    // Tiled 1.9 exported the object type as "class", every other version uses "type"
+    #[serde(rename = "type", alias = "class", default)]
+    kind: String,
+    x: f64,
+    y: f64,
+    #[serde(default)]
+    width: f64,
+    #[serde(default)]
+    height: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct TileRect {
+    pub(crate) x: i32,
+    pub(crate) y: i32,
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+}
+
+impl TileRect {
+    fn from_pixels(object: &TiledObject, tile_width: i32, tile_height: i32) -> Self {
+        let (tile_width, tile_height) = (tile_width as f64, tile_height as f64);
+        let x = (object.x / tile_width).floor() as i32;
+        let y = (object.y / tile_height).floor() as i32;
+        // Point objects have no size but still cover the tile they sit on
+        let right = ((object.x + object.width) / tile_width).ceil() as i32;
+        let bottom = ((object.y + object.height) / tile_height).ceil() as i32;
+        TileRect {
+            x,
+            y,
+            width: (right - x).max(1),
+            height: (bottom - y).max(1),
+        }
+    }
+
+    pub fn contains(&self, (x, y): (i32, i32)) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneKind {
+    // Heard by the whole room, for roles with stage access
+    Stage,
+    // Occupants only hear each other, whatever the distance
+    PrivateTable,
+    // No audio in or out
+    Silent,
+    // Like the stage, but only for the teacher and co-teachers
+    Podium,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Zone {
+    pub(crate) id: String,
+    pub(crate) kind: ZoneKind,
+    pub(crate) area: TileRect,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ClassroomMap {
+    pub(crate) name: String,
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+    // Row-major, true where an avatar can't stand (walls, desks, ...).
+    // Clients render collisions from the same Tiled file, so it's not sent over the wire.
+    #[serde(skip)]
+    blocked: Vec<bool>,
+    pub(crate) spawn_points: Vec<(i32, i32)>,
+    pub(crate) seats: Vec<(i32, i32)>,
+    pub(crate) podium: Option<TileRect>,
+    // Audio zones, they shouldn't overlap but if they do the first one wins
+    pub(crate) zones: Vec<Zone>,
+}
+
+impl ClassroomMap {
+    /// Loads the map for a course, falling back to the default map and then to an open floor.
+    pub fn load_for_course(course_id: u32) -> Self {
+        let candidates = [
+            format!("course_{}.json", course_id),
+            "default.json".to_string(),
+        ];
+
+        for file_name in candidates.iter() {
+            let path = Path::new(MAP_DIR).join(file_name);
+            if !path.exists() {
+                continue;
+            }
+            match Self::from_file(&path) {
+                Ok(map) => return map,
+                Err(e) => eprintln!("Failed to load map {}: {}", path.display(), e),
+            }
+        }
+
+        println!("No map found for course {}, using an open floor", course_id);
+        Self::open_floor(FALLBACK_SIZE, FALLBACK_SIZE)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let contents = fs::read_to_string(path)?;
+        Self::from_tiled_json(name, &contents)
+    }
+
+    pub fn from_tiled_json(name: String, json: &str) -> Result<Self, Box<dyn Error>> {
+        let tiled: TiledMap = serde_json::from_str(json)?;
+        if tiled.width <= 0 || tiled.height <= 0 || tiled.tilewidth <= 0 || tiled.tileheight <= 0 {
+            return Err("Map dimensions must be positive".into());
+        }
+
+        let mut map = ClassroomMap {
+            name,
+            width: tiled.width,
+            height: tiled.height,
+            blocked: vec![false; (tiled.width * tiled.height) as usize],
+            spawn_points: vec![],
+            seats: vec![],
+            podium: None,
+            zones: vec![],
+        };
+
+        for layer in tiled.layers {
+            match layer {
+                TiledLayer::Tiles {
+                    name,
+                    data,
+                    properties,
+                } => {
+                    let collides = name == "collision"
+                        || properties.iter().any(|p| {
+                            p.name == "collides" && p.value == serde_json::Value::Bool(true)
+                        });
+                    if !collides {
+                        continue;
+                    }
+                    if data.len() != map.blocked.len() {
+                        return Err(format!("Collision layer {} has the wrong size", name).into());
+                    }
+                    for (blocked, gid) in map.blocked.iter_mut().zip(data) {
+                        *blocked |= gid != 0;
+                    }
+                }
+                TiledLayer::Objects { objects } => {
+                    for object in objects {
+                        let rect =
+                            TileRect::from_pixels(&object, tiled.tilewidth, tiled.tileheight);
+                        let tile = (rect.x, rect.y);
+                        let zone_kind = match object.kind.as_str() {
+                            "spawn" => {
+                                map.spawn_points.push(tile);
+                                None
+                            }
+                            "seat" => {
+                                map.seats.push(tile);
+                                None
+                            }
+                            "podium" => {
+                                map.podium = Some(rect);
+                                Some(ZoneKind::Podium)
+                            }
+                            "stage" => Some(ZoneKind::Stage),
+                            "table" | "private_table" => Some(ZoneKind::PrivateTable),
+                            "silent" => Some(ZoneKind::Silent),
+                            _ => None,
+                        };
+                        if let Some(kind) = zone_kind {
+                            let id = if object.name.is_empty() {
+                                format!("{}-{}", object.kind, object.id)
+                            } else {
+                                object.name
+                            };
+                            map.zones.push(Zone {
+                                id,
+                                kind,
+                                area: rect,
+                            });
+                        }
+                    }
+                }
+                TiledLayer::Other => {}
+            }
+        }
+
+        // Drop markers that were placed on a wall by mistake
+        let (spawn_points, seats) = (map.spawn_points.clone(), map.seats.clone());
+        map.spawn_points = spawn_points
+            .into_iter()
+            .filter(|&t| map.is_walkable(t))
+            .collect();
+        map.seats = seats.into_iter().filter(|&t| map.is_walkable(t)).collect();
+
+        Ok(map)
+    }
+
+    /// An obstacle-free map of the given size with no spawn points, seats or podium.
+    pub fn open_floor(width: i32, height: i32) -> Self {
+        ClassroomMap {
+            name: "open_floor".to_string(),
+            width,
+            height,
+            blocked: vec![false; (width * height) as usize],
+            spawn_points: vec![],
+            seats: vec![],
+            podium: None,
+            zones: vec![],
+        }
+    }
+
+    pub fn in_bounds(&self, (x, y): (i32, i32)) -> bool {
+        x >= 0 && y >= 0 && x < self.width && y < self.height
+    }
+
+    pub fn is_walkable(&self, tile: (i32, i32)) -> bool {
+        self.in_bounds(tile) && !self.blocked[(tile.1 * self.width + tile.0) as usize]
+    }
+
+    pub fn zone_at(&self, tile: (i32, i32)) -> Option<&Zone> {
+        self.zones.iter().find(|zone| zone.area.contains(tile))
+    }
+
+    /// Whether `to` is exactly one tile horizontally or vertically away from `from`.
+    pub fn is_adjacent(from: (i32, i32), to: (i32, i32)) -> bool {
+        let x_displacement = (from.0 - to.0).abs();
+        let y_displacement = (from.1 - to.1).abs();
+        (x_displacement == 1 && y_displacement == 0) || (x_displacement == 0 && y_displacement == 1)
+    }
+
+    /// A valid step moves exactly one tile horizontally or vertically onto a walkable tile.
+    pub fn is_valid_step(&self, from: (i32, i32), to: (i32, i32)) -> bool {
+        Self::is_adjacent(from, to) && self.is_walkable(to)
+    }
+
+    /// Picks a free spawn tile: a random unoccupied spawn point if there is one,
+    /// otherwise the nearest free walkable tile to the spawn points.
+    pub fn find_spawn(&self, occupied: &HashSet<(i32, i32)>) -> Option<(i32, i32)> {
+        let mut free_spawns: Vec<(i32, i32)> = self
+            .spawn_points
+            .iter()
+            .copied()
+            .filter(|tile| !occupied.contains(tile))
+            .collect();
+        free_spawns.shuffle(&mut rand::thread_rng());
+        if let Some(tile) = free_spawns.first() {
+            return Some(*tile);
+        }
+
+        let mut starts = self.spawn_points.clone();
+        if starts.is_empty() {
+            // No designated spawns, start from a random walkable tile
+            let mut walkable: Vec<(i32, i32)> = (0..self.height)
+                .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+                .filter(|&tile| self.is_walkable(tile))
+                .collect();
+            walkable.shuffle(&mut rand::thread_rng());
+            starts.extend(walkable.first());
+        }
+
+        self.nearest_free_tile(&starts, occupied)
+    }
+
+    // Breadth-first search over walkable tiles
+    fn nearest_free_tile(
+        &self,
+        starts: &[(i32, i32)],
+        occupied: &HashSet<(i32, i32)>,
+    ) -> Option<(i32, i32)> {
+        let mut visited: HashSet<(i32, i32)> = starts.iter().copied().collect();
+        let mut queue: VecDeque<(i32, i32)> = starts.iter().copied().collect();
+
+        while let Some(tile) = queue.pop_front() {
+            if self.is_walkable(tile) && !occupied.contains(&tile) {
+                return Some(tile);
+            }
+            for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                let next = (tile.0 + dx, tile.1 + dy);
+                if self.is_walkable(next) && visited.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4x3 tiles of 32px: a wall down column 1 of the top two rows, a private table
+    // top right, a stage along the bottom row with the podium overlapping its end
+    const MAP_JSON: &str = r#"{
+        "width": 4, "height": 3, "tilewidth": 32, "tileheight": 32,
+        "layers": [
+            {"type": "tilelayer", "name": "collision", "data": [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]},
+            {"type": "objectgroup", "objects": [
+                {"id": 1, "type": "spawn", "x": 32, "y": 0},
+                {"id": 2, "type": "spawn", "x": 0, "y": 64},
+                {"id": 3, "name": "group-a", "class": "table", "x": 64, "y": 0, "width": 64, "height": 64},
+                {"id": 4, "type": "stage", "x": 0, "y": 64, "width": 128, "height": 32},
+                {"id": 5, "name": "lectern", "type": "podium", "x": 96, "y": 64, "width": 32, "height": 32}
+            ]},
+            {"type": "imagelayer", "name": "background"}
+        ]
+    }"#;
+
+    fn map() -> ClassroomMap {
+        ClassroomMap::from_tiled_json("test".to_string(), MAP_JSON).unwrap()
+    }
+
+    fn zone_id(map: &ClassroomMap, tile: (i32, i32)) -> Option<&str> {
+        map.zone_at(tile).map(|zone| zone.id.as_str())
+    }
+
+    #[test]
+    fn zones_cover_their_tiles() {
+        let map = map();
+        assert_eq!(
+            map.zones[0].area,
+            TileRect {
+                x: 2,
+                y: 0,
+                width: 2,
+                height: 2
+            }
+        );
+        assert_eq!(map.zones[0].kind, ZoneKind::PrivateTable);
+        for tile in [(2, 0), (3, 0), (2, 1), (3, 1)] {
+            assert_eq!(zone_id(&map, tile), Some("group-a"));
+        }
+        assert_eq!(zone_id(&map, (0, 0)), None);
+        assert_eq!(zone_id(&map, (4, 0)), None);
+    }
+
+    #[test]
+    fn unnamed_zones_are_named_after_kind_and_id() {
+        let map = map();
+        assert_eq!(zone_id(&map, (0, 2)), Some("stage-4"));
+        assert_eq!(map.zone_at((0, 2)).unwrap().kind, ZoneKind::Stage);
+    }
+
+    #[test]
+    fn first_zone_wins_where_they_overlap() {
+        let map = map();
+        assert_eq!(zone_id(&map, (3, 2)), Some("stage-4"));
+        assert_eq!(
+            map.podium,
+            Some(TileRect {
+                x: 3,
+                y: 2,
+                width: 1,
+                height: 1
+            })
+        );
+        assert!(map
+            .zones
+            .iter()
+            .any(|zone| zone.id == "lectern" && zone.kind == ZoneKind::Podium));
+    }
+
+    #[test]
+    fn walls_block_steps_and_drop_markers() {
+        let map = map();
+        assert_eq!(map.spawn_points, vec![(0, 2)]);
+        assert!(map.is_valid_step((0, 0), (0, 1)));
+        assert!(!map.is_valid_step((0, 0), (1, 0)));
+        assert!(!map.is_valid_step((0, 1), (1, 2)));
+        assert!(!map.is_valid_step((0, 0), (-1, 0)));
+    }
+
+    #[test]
+    fn collision_layer_must_match_the_map_size() {
+        let json = MAP_JSON.replace("0, 0, 0, 0, 0, 0]", "0, 0, 0, 0, 0]");
+        assert!(ClassroomMap::from_tiled_json("test".to_string(), &json).is_err());
+    }
+}
@@ -1,17 +1,44 @@
-use sp_core::{Blake2Hasher, Hasher};
+use lazy_static::lazy_static;
+use sp_core::hashing::blake2_256;
+use std::env;
 use std::error::Error;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use subxt::ext::codec::{Decode, Encode};
+use subxt::ext::codec::{Decode, DecodeAll};
 use subxt::{OnlineClient, PolkadotConfig};
 use tokio::sync::Mutex;
 
+use crate::capacity::CourseParams;
+use crate::identity::AccountIdentity;
+use crate::metrics;
 use crate::room_manager::RoomManager;
-use sp_core::crypto::Ss58Codec;
 
 #[subxt::subxt(runtime_metadata_path = "metadata.scale")]
 pub mod node_runtime {}
 
+lazy_static! {
+    // The chain the course contract lives on and its address there. A redeployed
+    // contract gets a new address, see "Upgrading the course contract" in the README.
+    static ref CHAIN_URL: String = env::var("EDUVERSE_CHAIN_URL")
+        .unwrap_or_else(|_| "wss://rpc2.paseo.popnetwork.xyz".to_string());
+    static ref CONTRACT_ADDRESS: String = env::var("EDUVERSE_CONTRACT_ADDRESS")
+        .unwrap_or_else(|_| "13CWQ2shoC3xjeEFUYsfbQT1gCUwpbJWtNJHhL4egjWheLAy".to_string());
+    // ink's signature topic, the hash of the event's name and field types. Changing an
+    // event's fields changes its topic.
+    static ref COURSE_CREATED_TOPIC: [u8; 32] =
+        blake2_256(b"CourseCreated(u32,AccountId,Vec<u8>,u32,Timestamp,Timestamp,Vec<u8>)");
+    static ref STUDENT_ENROLLED_TOPIC: [u8; 32] = blake2_256(b"StudentEnrolled(u32,AccountId)");
+}
+
+// Set while finalized blocks are coming in, new courses and enrollments are missed otherwise
+static CHAIN_CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the server is following the chain, for the readiness check.
+pub(crate) fn chain_connected() -> bool {
+    CHAIN_CONNECTED.load(Ordering::Relaxed)
+}
+
 pub struct ContractClient {
     client: Arc<OnlineClient<PolkadotConfig>>,
     contract_address: subxt::config::polkadot::AccountId32,
@@ -61,10 +88,10 @@ impl ContractClient {
     // }
 }
 
-async fn create_room_websocket(teacher: String, course_id: u32, title: String) {
+async fn create_room_websocket(course_id: u32, title: String, course: CourseParams) {
     println!("i was called as a result of smart contract call");
     let _ = RoomManager::instance()
-        .add_room_from_contract(teacher, course_id, title.clone())
+        .add_room_from_contract(course_id, title.clone(), course)
         .await;
     println!("Room created: {} - {}", course_id, title);
 }
@@ -74,11 +101,7 @@ pub async fn listening_for_course_creations() -> Result<(), Box<dyn Error + Send
     println!("Listening for course creations...");
 
     let contract_client = Arc::new(Mutex::new(
-        ContractClient::new(
-            "wss://rpc2.paseo.popnetwork.xyz",
-            "13CWQ2shoC3xjeEFUYsfbQT1gCUwpbJWtNJHhL4egjWheLAy",
-        )
-        .await?,
+        ContractClient::new(&CHAIN_URL, &CONTRACT_ADDRESS).await?,
     ));
 
     let mut blocks_sub = contract_client
@@ -90,45 +113,100 @@ pub async fn listening_for_course_creations() -> Result<(), Box<dyn Error + Send
         .await?;
 
     println!("Subscription to finalized blocks established.");
+    CHAIN_CONNECTED.store(true, Ordering::Relaxed);
 
     while let Some(block_result) = blocks_sub.next().await {
         match block_result {
             Ok(new_block) => {
+                CHAIN_CONNECTED.store(true, Ordering::Relaxed);
                 let block_number = new_block.header().number;
                 // println!("Processing block #{}", block_number);
 
                 match new_block.events().await {
                     Ok(events) => {
-                        for event in events.iter() {
-                            if let Ok(event_details) = event {
-                                if let Ok(Some(contract_event)) = event_details.as_event::<node_runtime::contracts::events::ContractEmitted>() {
-                                    if contract_event.contract == contract_client.lock().await.contract_address {
-                                        match decode_course_created_event(&contract_event.data) {
-                                            Ok(course_created) => {
-                                                println!("CourseCreated event: {:?}", course_created);
-                                                let teacher_address = hex::encode(course_created.teacher);
-                                                let title = String::from_utf8_lossy(&course_created.title).to_string();
-                                                create_room_websocket(
-                                                    teacher_address,
-                                                    course_created.course_id,
-                                                    title,
-                                                ).await;
-                                                println!("Room created for course: {}", course_created.course_id);
-                                            }
-                                            Err(e) => println!("Error decoding CourseCreated event: {:?}", e),
+                        for event_details in events.iter().flatten() {
+                            let Ok(Some(contract_event)) = event_details.as_event::<node_runtime::contracts::events::ContractEmitted>() else {
+                                continue;
+                            };
+                            if contract_event.contract != contract_client.lock().await.contract_address {
+                                continue;
+                            }
+                            // ink puts the event's signature topic first, the payload alone can't tell events apart
+                            match event_details.topics().first().map(|topic| topic.0) {
+                                Some(topic) if topic == *COURSE_CREATED_TOPIC => {
+                                    match decode_course_created_event(&contract_event.data) {
+                                        Ok(course_created) => {
+                                            println!("CourseCreated event: {:?}", course_created);
+                                            let teacher_address = AccountIdentity::from(course_created.teacher);
+                                            let title = String::from_utf8_lossy(&course_created.title).to_string();
+                                            let course = CourseParams {
+                                                teacher: teacher_address,
+                                                max_students: course_created.max_students,
+                                                start_time: course_created.start_time,
+                                                end_time: course_created.end_time,
+                                                metadata_hash: course_created.metadata_hash,
+                                            };
+                                            create_room_websocket(
+                                                course_created.course_id,
+                                                title,
+                                                course,
+                                            ).await;
+                                            println!("Room created for course: {}", course_created.course_id);
+                                        }
+                                        Err(e) => {
+                                            metrics::CHAIN_DECODE_ERRORS.inc();
+                                            println!("Error decoding CourseCreated event: {:?}", e);
+                                        }
+                                    }
+                                }
+                                Some(topic) if topic == *STUDENT_ENROLLED_TOPIC => {
+                                    match decode_student_enrolled_event(&contract_event.data) {
+                                        Ok(student_enrolled) => {
+                                            println!("StudentEnrolled event: {:?}", student_enrolled);
+                                            RoomManager::instance()
+                                                .record_enrollment(
+                                                    student_enrolled.course_id,
+                                                    AccountIdentity::from(student_enrolled.student),
+                                                )
+                                                .await;
+                                        }
+                                        Err(e) => {
+                                            metrics::CHAIN_DECODE_ERRORS.inc();
+                                            println!("Error decoding StudentEnrolled event: {:?}", e);
                                         }
                                     }
                                 }
+                                // Events we don't index
+                                _ => {}
                             }
                         }
                     }
                     Err(e) => println!("Error fetching events for block {}: {:?}", block_number, e),
                 }
+
+                metrics::CHAIN_LAST_BLOCK.set(block_number as u64);
+                // The subscription can fall behind if handling blocks is slower than the chain
+                match contract_client
+                    .lock()
+                    .await
+                    .client
+                    .blocks()
+                    .at_latest()
+                    .await
+                {
+                    Ok(head) => metrics::CHAIN_LAG_BLOCKS
+                        .set((head.number() as u64).saturating_sub(block_number as u64)),
+                    Err(e) => println!("Error fetching the finalized head: {:?}", e),
+                }
+            }
+            Err(e) => {
+                CHAIN_CONNECTED.store(false, Ordering::Relaxed);
+                println!("Error receiving block: {:?}", e);
             }
-            Err(e) => println!("Error receiving block: {:?}", e),
         }
     }
 
+    CHAIN_CONNECTED.store(false, Ordering::Relaxed);
     Ok(())
 }
 
@@ -153,19 +231,59 @@ struct CourseCreated {
     course_id: u32,
     teacher: [u8; 32],
     title: Vec<u8>,
+    max_students: u32,
+    // Unix millis
+    start_time: u64,
+    end_time: u64,
+    metadata_hash: Vec<u8>,
+}
+
+#[derive(Debug, Decode)]
+struct StudentEnrolled {
+    course_id: u32,
+    student: [u8; 32],
 }
 
+// Both decoders insist on consuming the whole payload, a truncated or padded event is an error
 fn decode_course_created_event(
-    data: &[u8],
+    mut data: &[u8],
 ) -> Result<CourseCreated, Box<dyn Error + Send + Sync + 'static>> {
-    let mut decoder = &data[..];
-    CourseCreated::decode(&mut decoder).map_err(|e| e.into())
+    CourseCreated::decode_all(&mut data).map_err(|e| e.into())
 }
 
-fn get_selector(name: &str) -> [u8; 4] {
-    let mut result = Blake2Hasher::hash(name.as_bytes());
+fn decode_student_enrolled_event(
+    mut data: &[u8],
+) -> Result<StudentEnrolled, Box<dyn Error + Send + Sync + 'static>> {
+    StudentEnrolled::decode_all(&mut data).map_err(|e| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use subxt::ext::codec::Encode;
+
+    #[test]
+    fn signature_topics_match_the_contract_metadata() {
+        // signature_topic of each event in frontend/contract.json
+        assert_eq!(
+            hex::encode(*COURSE_CREATED_TOPIC),
+            "8925efa6f9a94ec4ae55995c164010ef995494b397e1928a0c2a8851afb34905"
+        );
+        assert_eq!(
+            hex::encode(*STUDENT_ENROLLED_TOPIC),
+            "4157fe48e6b2f8fb4a518ad7cd2fb7cc1b4c72fe285ed50083148a2e284cfe99"
+        );
+    }
+
+    #[test]
+    fn decoding_rejects_leftover_bytes() {
+        let mut data = (7u32, [1u8; 32]).encode();
+        let enrolled = decode_student_enrolled_event(&data).unwrap();
+        assert_eq!(enrolled.course_id, 7);
+        assert_eq!(enrolled.student, [1u8; 32]);
 
-    let mut selector = [0u8; 4];
-    selector.copy_from_slice(&result[0..4]);
-    selector
+        data.push(0);
+        assert!(decode_student_enrolled_event(&data).is_err());
+        assert!(decode_course_created_event(&data[..10]).is_err());
+    }
 }
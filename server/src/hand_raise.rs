@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::sync::Mutex;
+
+use crate::identity::ParticipantId;
+use crate::roles::Permission;
+use crate::room_manager::RoomManager;
+use crate::session::duration_from_env;
+use crate::user::User;
+use crate::ws_payload::{GrantFloorPayload, LowerHandPayload};
+
+// A teacher can't hand out the floor for longer than this in one go
+const MAX_FLOOR_SECS: u64 = 600;
+// Who can see the queue and hand out the floor
+pub(crate) const STAFF_PERMISSION: Permission = Permission::Moderate;
+
+lazy_static! {
+    // How long a student keeps the floor unless the teacher says otherwise
+    pub(crate) static ref FLOOR_TIMEOUT: Duration =
+        duration_from_env("EDUVERSE_FLOOR_TIMEOUT_SECS", 120);
+}
+
+/// Puts the sender's hand up and tells them where they are in the queue.
+pub async fn raise_hand(user_arc: Arc<Mutex<User>>) {
+    let user = user_arc.lock().await;
+    let (Some(room_id), Some(user_id)) = (user.room_id, user.id.clone()) else {
+        return;
+    };
+    // Nothing to say without a microphone
+    if !user.can(Permission::Produce) {
+        user.reject_action("raise_hand", "not_permitted");
+        return;
+    }
+    match RoomManager::instance().raise_hand(room_id, &user_id).await {
+        Ok(position) => {
+            let raised_message = serde_json::json!({
+                "type": "hand_raised",
+                "position": position
+            });
+            user.send(raised_message.to_string());
+        }
+        Err(reason) => {
+            user.reject_action("raise_hand", reason);
+            return;
+        }
+    }
+    drop(user);
+
+    send_queue(room_id).await;
+}
+
+/// Lowers the sender's own hand, or with `target` set and staff rights, someone else's.
+pub async fn lower_hand(user_arc: Arc<Mutex<User>>, payload: LowerHandPayload) {
+    let (room_id, user_id) = {
+        let user = user_arc.lock().await;
+        let (Some(room_id), Some(user_id)) = (user.room_id, user.id.clone()) else {
+            return;
+        };
+        let target = payload.target.unwrap_or_else(|| user_id.clone());
+        if target != user_id && !user.can(STAFF_PERMISSION) {
+            user.reject_action("lower_hand", "not_permitted");
+            return;
+        }
+        (room_id, target)
+    };
+
+    let room_manager = RoomManager::instance();
+    if !room_manager.lower_hand(room_id, &user_id).await {
+        return;
+    }
+    let lowered_message = serde_json::json!({
+        "type": "hand_lowered"
+    });
+    room_manager
+        .send_to_users(
+            room_id,
+            &HashSet::from([user_id]),
+            lowered_message.to_string(),
+        )
+        .await;
+    send_queue(room_id).await;
+}
+
+/// Gives a student the floor: they're heard room-wide until the teacher revokes it
+/// or the grant times out.
+pub async fn grant_floor(user_arc: Arc<Mutex<User>>, payload: GrantFloorPayload) {
+    let room_id = {
+        let user = user_arc.lock().await;
+        if !user.can(STAFF_PERMISSION) {
+            user.reject_action("grant_floor", "not_permitted");
+            return;
+        }
+        let Some(room_id) = user.room_id else {
+            return;
+        };
+        room_id
+    };
+
+    let room_manager = RoomManager::instance();
+    let (floor, previous) = match room_manager.grant_floor(room_id, payload.target).await {
+        Ok(granted) => granted,
+        Err(reason) => {
+            user_arc.lock().await.reject_action("grant_floor", reason);
+            return;
+        }
+    };
+    let duration = payload
+        .duration_secs
+        .map(|secs| Duration::from_secs(secs.clamp(1, MAX_FLOOR_SECS)))
+        .unwrap_or(*FLOOR_TIMEOUT);
+
+    if let Some(previous) = previous.filter(|previous| previous != &floor.holder) {
+        broadcast_floor_ended(room_id, &previous, "replaced").await;
+    }
+    let granted_message = serde_json::json!({
+        "type": "floor_granted",
+        "user_id": floor.holder,
+        "duration_secs": duration.as_secs()
+    });
+    room_manager
+        .broadcast_message(None, room_id, granted_message.to_string())
+        .await;
+    send_queue(room_id).await;
+    room_manager.refresh_audio_routing(room_id).await;
+
+    // Only ends this grant, if the floor changed hands in the meantime it's left alone
+    let grant = floor.grant;
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        end_floor(room_id, Some(grant), "expired").await;
+    });
+}
+
+pub async fn revoke_floor(user_arc: Arc<Mutex<User>>) {
+    let room_id = {
+        let user = user_arc.lock().await;
+        if !user.can(STAFF_PERMISSION) {
+            user.reject_action("revoke_floor", "not_permitted");
+            return;
+        }
+        let Some(room_id) = user.room_id else {
+            return;
+        };
+        room_id
+    };
+    end_floor(room_id, None, "revoked").await;
+}
+
+/// Takes a user who's leaving out of the queue and off the floor.
+pub(crate) async fn user_left(room_id: u32, user_id: &ParticipantId) {
+    let room_manager = RoomManager::instance();
+    if room_manager.lower_hand(room_id, user_id).await {
+        send_queue(room_id).await;
+    }
+    if room_manager.floor_holder(room_id).await.as_ref() == Some(user_id) {
+        end_floor(room_id, None, "left").await;
+    }
+}
+
+// Drops the student back to proximity audio
+async fn end_floor(room_id: u32, grant: Option<u64>, reason: &str) {
+    let room_manager = RoomManager::instance();
+    let Some(holder) = room_manager.end_floor(room_id, grant).await else {
+        return;
+    };
+    broadcast_floor_ended(room_id, &holder, reason).await;
+    room_manager.refresh_audio_routing(room_id).await;
+}
+
+async fn broadcast_floor_ended(room_id: u32, holder: &ParticipantId, reason: &str) {
+    let ended_message = serde_json::json!({
+        "type": "floor_ended",
+        "user_id": holder,
+        "reason": reason
+    });
+    RoomManager::instance()
+        .broadcast_message(None, room_id, ended_message.to_string())
+        .await;
+}
+
+/// Sends the current queue to everyone who may see it.
+pub(crate) async fn send_queue(room_id: u32) {
+    let room_manager = RoomManager::instance();
+    let queue_message = serde_json::json!({
+        "type": "hand_queue",
+        "user_ids": room_manager.hand_queue(room_id).await
+    });
+    let staff = room_manager
+        .users_with_permission(room_id, STAFF_PERMISSION)
+        .await;
+    room_manager
+        .send_to_users(room_id, &staff, queue_message.to_string())
+        .await;
+}
@@ -0,0 +1,400 @@
+use std::env;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::event_listener;
+use crate::metrics;
+use crate::room_manager::RoomManager;
+
+// Only GETs without a body come in, anything longer isn't for us
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+const MAX_HEADERS: usize = 32;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    // Where the HTTP API listens, next to the WebSocket server
+    pub(crate) static ref HTTP_ADDR: String =
+        env::var("EDUVERSE_HTTP_ADDR").unwrap_or_else(|_| "127.0.0.1:8081".to_string());
+    // Comma separated origins the frontend is served from, `*` for any. Without it
+    // no CORS headers are sent and browsers keep other sites from reading the API.
+    static ref CORS_ORIGINS: Vec<String> = env::var("EDUVERSE_CORS_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|origin| origin.trim().to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        let body = match body {
+            serde_json::Value::Null => String::new(),
+            body => body.to_string(),
+        };
+        Response {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn error(status: u16, reason: &str) -> Self {
+        Response::json(status, serde_json::json!({ "error": reason }))
+    }
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Serves the read-only HTTP API: room discovery for the frontend, health checks
+/// for whatever runs the server and metrics for Prometheus. One request per connection.
+pub async fn serve(listener: TcpListener) {
+    serve_for(listener, &CORS_ORIGINS).await;
+}
+
+async fn serve_for(listener: TcpListener, cors_origins: &'static [String]) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, cors_origins).await {
+                eprintln!("Error serving an HTTP request: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, cors_origins: &[String]) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; MAX_REQUEST_BYTES];
+    let mut read = 0;
+    let (method, path, origin) = loop {
+        let n = match timeout(REQUEST_TIMEOUT, stream.read(&mut buffer[read..])).await {
+            Ok(n) => n?,
+            // Slow or idle client, just hang up
+            Err(_) => return Ok(()),
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        read += n;
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buffer[..read]) {
+            Ok(httparse::Status::Complete(_)) => {
+                let origin = request
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("origin"))
+                    .and_then(|header| std::str::from_utf8(header.value).ok())
+                    .map(str::to_string);
+                break (
+                    request.method.unwrap_or_default().to_string(),
+                    request.path.unwrap_or_default().to_string(),
+                    origin,
+                );
+            }
+            Ok(httparse::Status::Partial) if read < buffer.len() => continue,
+            Ok(httparse::Status::Partial) => {
+                let response = Response::error(413, "request_too_large");
+                return write_response(&mut stream, response, None).await;
+            }
+            Err(_) => {
+                let response = Response::error(400, "bad_request");
+                return write_response(&mut stream, response, None).await;
+            }
+        }
+    };
+
+    let response = match method.as_str() {
+        // CORS preflight, the headers are added to every response
+        "OPTIONS" => Response::json(204, serde_json::Value::Null),
+        "GET" => route(&path).await,
+        _ => Response::error(405, "method_not_allowed"),
+    };
+    let allowed = allowed_origin(cors_origins, origin.as_deref());
+    write_response(&mut stream, response, allowed).await
+}
+
+async fn route(path: &str) -> Response {
+    // Query strings aren't used by anything yet
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["rooms"] => {
+            let rooms = RoomManager::instance().room_listing().await;
+            Response::json(200, serde_json::json!(rooms))
+        }
+        ["rooms", room_id] => {
+            let Ok(room_id) = room_id.parse::<u32>() else {
+                return Response::error(404, "room_not_found");
+            };
+            match RoomManager::instance().room_summary(room_id).await {
+                Some(summary) => Response::json(200, serde_json::json!(summary)),
+                None => Response::error(404, "room_not_found"),
+            }
+        }
+        ["healthz"] => Response::json(200, serde_json::json!({ "status": "ok" })),
+        ["readyz"] => readiness().await,
+        ["metrics"] => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics::render().await,
+        },
+        _ => Response::error(404, "not_found"),
+    }
+}
+
+// Ready once rooms can be opened and new courses are being picked up from the chain
+async fn readiness() -> Response {
+    let room_manager = RoomManager::instance();
+    let workers = room_manager.live_workers().await;
+    let chain_connected = event_listener::chain_connected();
+    let ready = workers > 0 && chain_connected;
+    let body = serde_json::json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "workers": workers,
+        "chain_connected": chain_connected,
+        "rooms": room_manager.room_count().await
+    });
+    Response::json(if ready { 200 } else { 503 }, body)
+}
+
+// The origin to allow, if the request's is on the list
+fn allowed_origin(cors_origins: &[String], origin: Option<&str>) -> Option<String> {
+    if cors_origins.iter().any(|allowed| allowed == "*") {
+        return Some("*".to_string());
+    }
+    let origin = origin?;
+    cors_origins
+        .iter()
+        .any(|allowed| allowed == origin)
+        .then(|| origin.to_string())
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    response: Response,
+    allowed_origin: Option<String>,
+) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        status_text(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    if let Some(allowed) = allowed_origin {
+        head.push_str(&format!("Access-Control-Allow-Origin: {}\r\n", allowed));
+        head.push_str("Access-Control-Allow-Methods: GET, OPTIONS\r\n");
+        head.push_str("Access-Control-Allow-Headers: Content-Type\r\n");
+        head.push_str("Access-Control-Max-Age: 600\r\n");
+        if allowed != "*" {
+            head.push_str("Vary: Origin\r\n");
+        }
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRONTEND: &str = "https://app.eduverse.example";
+
+    struct Reply {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Reply {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        fn json(&self) -> serde_json::Value {
+            serde_json::from_str(&self.body).unwrap()
+        }
+    }
+
+    // Sends raw bytes to a fresh server allowing `cors_origins` and reads back the response
+    async fn exchange(cors_origins: &'static [String], request: &[u8]) -> Reply {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_for(listener, cors_origins));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw).await.unwrap();
+
+        let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .unwrap()
+            .split(' ')
+            .nth(1)
+            .unwrap()
+            .parse()
+            .unwrap();
+        let headers = lines
+            .filter_map(|line| line.split_once(": "))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Reply {
+            status,
+            headers,
+            body: body.to_string(),
+        }
+    }
+
+    async fn get(path: &str) -> Reply {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        exchange(&[], request.as_bytes()).await
+    }
+
+    fn origins(origins: &[&str]) -> &'static [String] {
+        let origins: Vec<String> = origins.iter().map(|origin| origin.to_string()).collect();
+        Box::leak(origins.into_boxed_slice())
+    }
+
+    #[tokio::test]
+    async fn lists_rooms() {
+        let reply = get("/rooms").await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.header("Content-Type"), Some("application/json"));
+        assert!(reply.json().is_array());
+        // Trailing slashes and query strings don't change the route
+        assert_eq!(get("/rooms/?phase=live").await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn unknown_rooms_are_not_found() {
+        for path in ["/rooms/424242", "/rooms/not-a-number", "/rooms/-1"] {
+            let reply = get(path).await;
+            assert_eq!(reply.status, 404, "{}", path);
+            assert_eq!(reply.json()["error"], "room_not_found");
+        }
+    }
+
+    #[tokio::test]
+    async fn health_checks() {
+        let reply = get("/healthz").await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.json()["status"], "ok");
+
+        // No media workers and no chain in tests
+        let reply = get("/readyz").await;
+        assert_eq!(reply.status, 503);
+        let body = reply.json();
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(body["workers"], 0);
+        assert_eq!(body["chain_connected"], false);
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_are_not_found() {
+        for path in ["/", "/room", "/rooms/1/users", "/healthz/extra"] {
+            let reply = get(path).await;
+            assert_eq!(reply.status, 404, "{}", path);
+            assert_eq!(reply.json()["error"], "not_found");
+        }
+    }
+
+    #[tokio::test]
+    async fn only_get_and_preflight_are_allowed() {
+        for method in ["POST", "PUT", "DELETE"] {
+            let request = format!("{} /rooms HTTP/1.1\r\nHost: localhost\r\n\r\n", method);
+            let reply = exchange(&[], request.as_bytes()).await;
+            assert_eq!(reply.status, 405, "{}", method);
+            assert_eq!(reply.json()["error"], "method_not_allowed");
+        }
+
+        let reply = exchange(&[], b"OPTIONS /rooms HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(reply.status, 204);
+        assert_eq!(reply.body, "");
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_turned_away() {
+        let reply = exchange(&[], b"GET /rooms HTTP/1.1\r\nBad Header\r\n\r\n").await;
+        assert_eq!(reply.status, 400);
+        assert_eq!(reply.json()["error"], "bad_request");
+
+        let reply = exchange(&[], b"\x00\x01\x02 nonsense\r\n\r\n").await;
+        assert_eq!(reply.status, 400);
+
+        // Never finishes its headers within the size limit
+        let mut request = b"GET /rooms HTTP/1.1\r\nX-Padding: ".to_vec();
+        // Exactly fills the buffer, so nothing is left unread when the server hangs up
+        request.resize(MAX_REQUEST_BYTES, b'a');
+        let reply = exchange(&[], &request).await;
+        assert_eq!(reply.status, 413);
+        assert_eq!(reply.json()["error"], "request_too_large");
+    }
+
+    #[tokio::test]
+    async fn no_cors_headers_unless_configured() {
+        let request = format!("GET /healthz HTTP/1.1\r\nOrigin: {}\r\n\r\n", FRONTEND);
+        let reply = exchange(&[], request.as_bytes()).await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.header("Access-Control-Allow-Origin"), None);
+    }
+
+    #[tokio::test]
+    async fn cors_allows_only_configured_origins() {
+        let allowed = origins(&[FRONTEND, "http://localhost:3000"]);
+
+        let request = format!("OPTIONS /rooms HTTP/1.1\r\nOrigin: {}\r\n\r\n", FRONTEND);
+        let reply = exchange(allowed, request.as_bytes()).await;
+        assert_eq!(reply.header("Access-Control-Allow-Origin"), Some(FRONTEND));
+        assert_eq!(
+            reply.header("Access-Control-Allow-Methods"),
+            Some("GET, OPTIONS")
+        );
+        assert_eq!(reply.header("Vary"), Some("Origin"));
+
+        let request = b"GET /rooms HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n";
+        let reply = exchange(allowed, request).await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.header("Access-Control-Allow-Origin"), None);
+
+        let reply = exchange(allowed, b"GET /rooms HTTP/1.1\r\n\r\n").await;
+        assert_eq!(reply.header("Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn a_wildcard_allows_every_origin() {
+        let any = origins(&["*"]);
+        assert_eq!(allowed_origin(any, Some(FRONTEND)), Some("*".to_string()));
+        assert_eq!(allowed_origin(any, None), Some("*".to_string()));
+        assert_eq!(allowed_origin(&[], Some(FRONTEND)), None);
+    }
+}
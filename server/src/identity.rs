@@ -0,0 +1,282 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sp_core::crypto::{AccountId32, Ss58AddressFormat, Ss58Codec};
+use sp_core::sr25519;
+
+// What most wallets emit unless told about a specific network
+const GENERIC_SUBSTRATE_PREFIX: u16 = 42;
+
+lazy_static! {
+    // Network prefix used to display accounts, the contract lives on a prefix 0 chain
+    static ref SS58_PREFIX: u16 = env::var("EDUVERSE_SS58_PREFIX")
+        .ok()
+        .and_then(|prefix| prefix.parse().ok())
+        .unwrap_or(0);
+}
+
+/// The one way the server refers to an account. Parses SS58, hex (with or without `0x`)
+/// and raw bytes, compares by the underlying 32-byte key and displays as SS58.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AccountIdentity(AccountId32);
+
+impl AccountIdentity {
+    pub fn parse(input: &str) -> Result<Self, &'static str> {
+        let input = input.trim();
+        let hex_part = input
+            .strip_prefix("0x")
+            .or_else(|| input.strip_prefix("0X"))
+            .unwrap_or(input);
+        if hex_part.len() == 64 && hex_part.chars().all(|c| c.is_ascii_hexdigit()) {
+            let bytes = hex::decode(hex_part).map_err(|_| "Invalid hex account")?;
+            return Self::from_bytes(&bytes);
+        }
+
+        let (account, format) =
+            AccountId32::from_ss58check_with_version(input).map_err(|_| "Invalid SS58 account")?;
+        let prefix = format.prefix();
+        if prefix != *SS58_PREFIX && prefix != GENERIC_SUBSTRATE_PREFIX {
+            return Err("SS58 account is for another network");
+        }
+        Ok(AccountIdentity(account))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| "Account must be 32 bytes")?;
+        Ok(Self::from(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_ref()
+    }
+
+    /// Accounts are sr25519 public keys, which is what join and moderation signatures use.
+    pub fn public_key(&self) -> sr25519::Public {
+        sr25519::Public::from_raw(*self.as_bytes())
+    }
+
+    pub fn to_ss58(&self) -> String {
+        self.0
+            .to_ss58check_with_version(Ss58AddressFormat::custom(*SS58_PREFIX))
+    }
+}
+
+impl From<[u8; 32]> for AccountIdentity {
+    fn from(bytes: [u8; 32]) -> Self {
+        AccountIdentity(AccountId32::from(bytes))
+    }
+}
+
+impl FromStr for AccountIdentity {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for AccountIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_ss58())
+    }
+}
+
+impl fmt::Debug for AccountIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AccountIdentity({})", self.to_ss58())
+    }
+}
+
+// On the wire accounts are always SS58 strings
+impl Serialize for AccountIdentity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AccountIdentity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        Self::parse(&input).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Who a connection is inside a room: the account, plus a device id when the same account
+/// may be signed in from several devices at once. Displayed as the SS58 account,
+/// followed by `/<device>` when there is one.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ParticipantId {
+    account: AccountIdentity,
+    device: Option<String>,
+}
+
+impl ParticipantId {
+    pub fn new(account: AccountIdentity, device: Option<String>) -> Self {
+        ParticipantId { account, device }
+    }
+
+    pub fn account(&self) -> &AccountIdentity {
+        &self.account
+    }
+
+    pub fn device(&self) -> Option<&str> {
+        self.device.as_deref()
+    }
+
+    /// Device ids are chosen by the client, so they're kept short and URL safe.
+    pub fn is_valid_device(device: &str) -> bool {
+        !device.is_empty()
+            && device.len() <= 32
+            && device
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+}
+
+impl From<AccountIdentity> for ParticipantId {
+    fn from(account: AccountIdentity) -> Self {
+        ParticipantId::new(account, None)
+    }
+}
+
+impl FromStr for ParticipantId {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((account, device)) => {
+                if !Self::is_valid_device(device) {
+                    return Err("Invalid device id");
+                }
+                Ok(ParticipantId::new(
+                    AccountIdentity::parse(account)?,
+                    Some(device.to_string()),
+                ))
+            }
+            None => Ok(AccountIdentity::parse(s)?.into()),
+        }
+    }
+}
+
+impl fmt::Display for ParticipantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.device.as_ref() {
+            Some(device) => write!(f, "{}/{}", self.account, device),
+            None => write!(f, "{}", self.account),
+        }
+    }
+}
+
+impl fmt::Debug for ParticipantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ParticipantId({})", self)
+    }
+}
+
+impl Serialize for ParticipantId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ParticipantId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        input.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Alice's well-known development key
+    const ALICE_HEX: &str = "d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d";
+    const ALICE_POLKADOT: &str = "15oF4uVJwmo4TdGW7VfQxNLavjCXviqxT9S1MgbjMNHr6Sp5";
+    const ALICE_GENERIC: &str = "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY";
+    const ALICE_KUSAMA: &str = "HNZata7iMYWmk5RvZRTiAsSDhV8366zq2YGb3tLH5Upf74F";
+
+    fn alice() -> AccountIdentity {
+        AccountIdentity::from_bytes(&hex::decode(ALICE_HEX).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parses_hex_with_or_without_prefix() {
+        assert_eq!(AccountIdentity::parse(ALICE_HEX), Ok(alice()));
+        assert_eq!(
+            AccountIdentity::parse(&format!("0x{}", ALICE_HEX)),
+            Ok(alice())
+        );
+        assert_eq!(
+            AccountIdentity::parse(&format!(" 0X{} ", ALICE_HEX.to_uppercase())),
+            Ok(alice())
+        );
+    }
+
+    #[test]
+    fn parses_ss58_for_this_network_and_generic_substrate() {
+        assert_eq!(AccountIdentity::parse(ALICE_POLKADOT), Ok(alice()));
+        assert_eq!(AccountIdentity::parse(ALICE_GENERIC), Ok(alice()));
+    }
+
+    #[test]
+    fn rejects_other_networks_and_garbage() {
+        assert_eq!(
+            AccountIdentity::parse(ALICE_KUSAMA),
+            Err("SS58 account is for another network")
+        );
+        assert_eq!(
+            AccountIdentity::parse(&ALICE_POLKADOT[..ALICE_POLKADOT.len() - 1]),
+            Err("Invalid SS58 account")
+        );
+        assert_eq!(
+            AccountIdentity::parse(&ALICE_HEX[2..]),
+            Err("Invalid SS58 account")
+        );
+        assert_eq!(
+            AccountIdentity::from_bytes(&[0u8; 31]),
+            Err("Account must be 32 bytes")
+        );
+    }
+
+    #[test]
+    fn displays_as_ss58_and_round_trips() {
+        assert_eq!(alice().to_string(), ALICE_POLKADOT);
+        assert_eq!(alice().to_string().parse(), Ok(alice()));
+        assert_eq!(
+            serde_json::to_value(alice()).unwrap(),
+            serde_json::json!(ALICE_POLKADOT)
+        );
+    }
+
+    #[test]
+    fn participant_ids_carry_an_optional_device() {
+        let plain: ParticipantId = ALICE_GENERIC.parse().unwrap();
+        assert_eq!(plain, ParticipantId::from(alice()));
+        assert_eq!(plain.device(), None);
+
+        let with_device: ParticipantId = format!("{}/laptop-2", ALICE_HEX).parse().unwrap();
+        assert_eq!(with_device.account(), &alice());
+        assert_eq!(with_device.device(), Some("laptop-2"));
+        assert_eq!(
+            with_device.to_string(),
+            format!("{}/laptop-2", ALICE_POLKADOT)
+        );
+        assert_ne!(plain, with_device);
+    }
+
+    #[test]
+    fn device_ids_are_short_and_url_safe() {
+        assert!(ParticipantId::is_valid_device("phone_1"));
+        assert!(!ParticipantId::is_valid_device(""));
+        assert!(!ParticipantId::is_valid_device("a/b"));
+        assert!(!ParticipantId::is_valid_device(&"a".repeat(33)));
+        assert_eq!(
+            format!("{}/", ALICE_HEX).parse::<ParticipantId>(),
+            Err("Invalid device id")
+        );
+    }
+}
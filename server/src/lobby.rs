@@ -0,0 +1,248 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::identity::{AccountIdentity, ParticipantId};
+use crate::moderation;
+use crate::roles::{Permission, Role};
+use crate::room_manager::{RoomManager, UserHandle};
+use crate::user::User;
+use crate::ws_payload::{LobbyDecisionPayload, WaitingRoomPayload};
+
+// Who skips the waiting room, sees who's in it and lets them in
+pub(crate) const ADMIT_PERMISSION: Permission = Permission::ManageRoom;
+
+/// Someone who signed in and is waiting to be let into the room.
+#[derive(Clone)]
+pub struct LobbyEntry {
+    pub(crate) user_id: ParticipantId,
+    pub(crate) role: Role,
+    pub(crate) user: UserHandle,
+    // Unix seconds
+    pub(crate) since: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AdmissionDecision {
+    Admit,
+    Deny,
+}
+
+#[derive(Serialize)]
+struct AdmissionEntry<'a> {
+    timestamp: u64,
+    room_id: u32,
+    teacher: &'a AccountIdentity,
+    decision: AdmissionDecision,
+    user_id: &'a ParticipantId,
+    role: Role,
+    waited_secs: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Parks an authenticated user in the room's lobby. They see nothing of the room
+/// until a teacher lets them in.
+pub(crate) async fn wait(
+    user_arc: Arc<Mutex<User>>,
+    room_id: u32,
+    user_id: ParticipantId,
+    role: Role,
+) {
+    {
+        let mut user = user_arc.lock().await;
+        if user.id.as_ref().is_some_and(|id| id != &user_id) {
+            user.reject_join(room_id, "identity_mismatch");
+            return;
+        }
+        let entry = LobbyEntry {
+            user_id: user_id.clone(),
+            role,
+            user: user_arc.clone(),
+            since: now_secs(),
+        };
+        if !RoomManager::instance().add_to_lobby(room_id, entry).await {
+            user.reject_join(room_id, "already_waiting");
+            return;
+        }
+        user.id = Some(user_id);
+        user.waiting_in = Some(room_id);
+        let waiting_message = serde_json::json!({
+            "type": "lobby_waiting",
+            "course_id": room_id
+        });
+        user.send(waiting_message.to_string());
+    }
+    send_pending(room_id).await;
+}
+
+/// Takes a user out of the lobby when they leave or drop before being let in.
+/// Returns whether they were waiting.
+pub(crate) async fn leave(user_arc: &Arc<Mutex<User>>) -> bool {
+    let (room_id, user_id) = {
+        let mut user = user_arc.lock().await;
+        let Some(room_id) = user.waiting_in.take() else {
+            return false;
+        };
+        // Never let in, so the identity they waited with doesn't stick to the connection
+        (room_id, user.id.take())
+    };
+    if let Some(user_id) = user_id {
+        RoomManager::instance()
+            .take_from_lobby(room_id, Some(&user_id))
+            .await;
+    }
+    send_pending(room_id).await;
+    true
+}
+
+/// Turns the waiting room on or off. Turning it off lets in everyone still waiting.
+pub async fn set_waiting_room(user_arc: Arc<Mutex<User>>, payload: WaitingRoomPayload) {
+    let (room_id, teacher_id) = {
+        let user = user_arc.lock().await;
+        if !user.can(ADMIT_PERMISSION) {
+            user.reject_action("set_waiting_room", "not_permitted");
+            return;
+        }
+        let (Some(room_id), Some(teacher_id)) = (user.room_id, user.id.clone()) else {
+            return;
+        };
+        (room_id, teacher_id)
+    };
+
+    let room_manager = RoomManager::instance();
+    room_manager
+        .set_waiting_room(room_id, payload.enabled)
+        .await;
+    let waiting_room_message = serde_json::json!({
+        "type": "waiting_room",
+        "enabled": payload.enabled
+    });
+    let staff = room_manager
+        .users_with_permission(room_id, ADMIT_PERMISSION)
+        .await;
+    room_manager
+        .send_to_users(room_id, &staff, waiting_room_message.to_string())
+        .await;
+    if !payload.enabled {
+        decide(room_id, &teacher_id, None, AdmissionDecision::Admit).await;
+    }
+}
+
+/// Lets in one waiting user, or everyone when no target is given.
+pub async fn admit(user_arc: Arc<Mutex<User>>, payload: LobbyDecisionPayload) {
+    let Some((room_id, teacher_id)) = teacher_of(&user_arc, "lobby_admit").await else {
+        return;
+    };
+    decide(
+        room_id,
+        &teacher_id,
+        payload.target.as_ref(),
+        AdmissionDecision::Admit,
+    )
+    .await;
+}
+
+/// Turns away one waiting user, or everyone when no target is given.
+pub async fn deny(user_arc: Arc<Mutex<User>>, payload: LobbyDecisionPayload) {
+    let Some((room_id, teacher_id)) = teacher_of(&user_arc, "lobby_deny").await else {
+        return;
+    };
+    decide(
+        room_id,
+        &teacher_id,
+        payload.target.as_ref(),
+        AdmissionDecision::Deny,
+    )
+    .await;
+}
+
+async fn teacher_of(user_arc: &Arc<Mutex<User>>, action: &str) -> Option<(u32, ParticipantId)> {
+    let user = user_arc.lock().await;
+    if !user.can(ADMIT_PERMISSION) {
+        user.reject_action(action, "not_permitted");
+        return None;
+    }
+    let (Some(room_id), Some(teacher_id)) = (user.room_id, user.id.clone()) else {
+        return None;
+    };
+    Some((room_id, teacher_id))
+}
+
+async fn decide(
+    room_id: u32,
+    teacher_id: &ParticipantId,
+    target: Option<&ParticipantId>,
+    decision: AdmissionDecision,
+) {
+    let entries = RoomManager::instance()
+        .take_from_lobby(room_id, target)
+        .await;
+    for entry in entries {
+        let log_entry = AdmissionEntry {
+            timestamp: now_secs(),
+            room_id,
+            teacher: teacher_id.account(),
+            decision,
+            user_id: &entry.user_id,
+            role: entry.role,
+            waited_secs: now_secs().saturating_sub(entry.since),
+        };
+        let file_name = format!("room_{}_admissions.log", room_id);
+        if let Err(e) = moderation::append_audit_line(&file_name, &log_entry).await {
+            eprintln!("Failed to log admission for room {}: {}", room_id, e);
+        }
+
+        // They may have left in the meantime
+        let still_waiting = {
+            let mut user = entry.user.lock().await;
+            let waiting = user.waiting_in == Some(room_id);
+            user.waiting_in = None;
+            if waiting && decision == AdmissionDecision::Deny {
+                // Free to try again, as someone else if need be
+                user.id = None;
+                user.reject_join(room_id, "admission_denied");
+            }
+            waiting
+        };
+        if still_waiting && decision == AdmissionDecision::Admit {
+            User::enter_room(entry.user, room_id, entry.user_id, entry.role).await;
+        }
+    }
+    send_pending(room_id).await;
+}
+
+/// Sends who's waiting to everyone in the room who can let them in.
+pub(crate) async fn send_pending(room_id: u32) {
+    let room_manager = RoomManager::instance();
+    let pending: Vec<_> = room_manager
+        .lobby(room_id)
+        .await
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "user_id": entry.user_id,
+                "role": entry.role,
+                "since": entry.since
+            })
+        })
+        .collect();
+    let pending_message = serde_json::json!({
+        "type": "lobby_pending",
+        "users": pending
+    });
+    let staff = room_manager
+        .users_with_permission(room_id, ADMIT_PERMISSION)
+        .await;
+    room_manager
+        .send_to_users(room_id, &staff, pending_message.to_string())
+        .await;
+}
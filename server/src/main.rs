@@ -1,29 +1,64 @@
 use crate::room_manager::RoomManager;
 use crate::user::User;
 use event_listener::listening_for_course_creations;
+use futures_util::StreamExt;
 use std::error::Error;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 use tokio_tungstenite::accept_async;
 
+mod attendance;
+mod attestation;
+mod breakout;
+mod capacity;
+mod chat;
+mod classroom_map;
 mod event_listener;
+mod hand_raise;
+mod http_api;
+mod identity;
+mod lobby;
+mod metrics;
+mod moderation;
+mod polls;
+mod presentation;
+mod roles;
 mod room_manager;
+mod room_tick;
+mod session;
 mod stream_types;
 mod user;
+mod whiteboard;
 mod ws_payload;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Initialize the RoomManager
     RoomManager::instance().initialize().await?;
+    // Chat history survives restarts in an embedded store
+    chat::init()?;
+    // Polls and their answers too, they're exported after the session
+    polls::init()?;
+    // Running attendance totals, what attestations are signed over
+    attendance::init()?;
+    // Board snapshots, loaded again when the room comes back after a restart
+    whiteboard::init()?;
+    match attestation::signer() {
+        Some(signer) => println!("Signing attendance attestations as {}", signer),
+        None => println!("No EDUVERSE_ATTESTATION_KEY set, attendance attestations are off"),
+    }
 
     let addr = "127.0.0.1:8080";
     let listener = TcpListener::bind(&addr).await?;
     println!("WebSocket server listening on: {}", addr);
+    // Room discovery and health checks, plain HTTP on its own port
+    let http_listener = TcpListener::bind(http_api::HTTP_ADDR.as_str()).await?;
+    println!("HTTP API listening on: {}", *http_api::HTTP_ADDR);
 
     // Spawn the event listener as a separate task
     let event_listener_handle = tokio::spawn(listening_for_course_creations());
+    let http_handle = tokio::spawn(http_api::serve(http_listener));
 
     // Handle WebSocket connections
     let server_handle = tokio::spawn(async move {
@@ -32,8 +67,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 match accept_async(stream).await {
                     Ok(ws_stream) => {
                         println!("got a new client connection");
-                        let user = Arc::new(Mutex::new(User::new(ws_stream)));
-                        User::handle_ws_actions(user).await
+                        let (outgoing, incoming) = ws_stream.split();
+                        let user = Arc::new(Mutex::new(User::new(outgoing)));
+                        User::handle_ws_actions(user, incoming).await
                     }
                     Err(e) => eprintln!("Error during the WebSocket handshake: {:?}", e),
                 }
@@ -44,6 +80,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     tokio::select! {
         _ = server_handle => println!("WebSocket server task completed"),
         _ = event_listener_handle => println!("Event listener task completed"),
+        _ = http_handle => println!("HTTP API task completed"),
     }
 
     Ok(())
@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+use crate::room_manager::RoomManager;
+
+// Seconds, from a handful of users in memory to a full room behind slow sockets
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+// Messages still waiting for a socket, anything in the hundreds is a client that can't keep up
+const QUEUE_DEPTH_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 1000.0];
+
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub(crate) const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub(crate) fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub(crate) const fn new() -> Self {
+        Gauge(AtomicU64::new(0))
+    }
+
+    pub(crate) fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A counter per label value, e.g. per rejection reason.
+pub(crate) struct LabeledCounter(Mutex<BTreeMap<String, u64>>);
+
+impl LabeledCounter {
+    fn new() -> Self {
+        LabeledCounter(Mutex::new(BTreeMap::new()))
+    }
+
+    pub(crate) fn inc(&self, label: &str) {
+        *self.0.lock().entry(label.to_string()).or_default() += 1;
+    }
+}
+
+pub(crate) struct Histogram {
+    bounds: &'static [f64],
+    // One per bound, not cumulative, plus one for everything above the last
+    buckets: Vec<AtomicU64>,
+    // f64 bits
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + value).to_bits())
+            });
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+lazy_static! {
+    // Time to hand a message to everyone in a room, lock waits included
+    pub(crate) static ref BROADCAST_SECONDS: Histogram = Histogram::new(LATENCY_BUCKETS);
+    // Sampled every time a socket writer takes a message off its queue
+    pub(crate) static ref SEND_QUEUE_DEPTH: Histogram = Histogram::new(QUEUE_DEPTH_BUCKETS);
+    pub(crate) static ref JOIN_FAILURES: LabeledCounter = LabeledCounter::new();
+    // By scope
+    pub(crate) static ref CHAT_MESSAGES: LabeledCounter = LabeledCounter::new();
+}
+
+pub(crate) static REJECTED_MOVES: Counter = Counter::new();
+// Chain indexing, updated by the event listener
+pub(crate) static CHAIN_LAST_BLOCK: Gauge = Gauge::new();
+pub(crate) static CHAIN_LAG_BLOCKS: Gauge = Gauge::new();
+pub(crate) static CHAIN_DECODE_ERRORS: Counter = Counter::new();
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_labeled(out: &mut String, name: &str, label: &str, counter: &LabeledCounter) {
+    for (value, count) in counter.0.lock().iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(value), count);
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (bound, bucket) in histogram.bounds.iter().zip(histogram.buckets.iter()) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    cumulative += histogram.buckets[histogram.bounds.len()].load(Ordering::Relaxed);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+    let sum = f64::from_bits(histogram.sum.load(Ordering::Relaxed));
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(
+        out,
+        "{}_count {}",
+        name,
+        histogram.count.load(Ordering::Relaxed)
+    );
+}
+
+/// Everything in the Prometheus text format. Room and media gauges are read
+/// from the rooms at scrape time, the rest is counted as it happens.
+pub(crate) async fn render() -> String {
+    let room_manager = RoomManager::instance();
+    let mut out = String::new();
+
+    let rooms = room_manager.users_per_room().await;
+    header(
+        &mut out,
+        "eduverse_rooms",
+        "gauge",
+        "Open rooms, breakout rooms included.",
+    );
+    let _ = writeln!(out, "eduverse_rooms {}", rooms.len());
+    header(
+        &mut out,
+        "eduverse_room_users",
+        "gauge",
+        "Users connected to each room.",
+    );
+    for (room_id, users) in rooms.iter() {
+        let _ = writeln!(out, "eduverse_room_users{{room=\"{}\"}} {}", room_id, users);
+    }
+
+    let workers = room_manager.media_by_worker().await;
+    header(
+        &mut out,
+        "eduverse_worker_producers",
+        "gauge",
+        "Producers on each media worker.",
+    );
+    for (worker_id, (producers, _)) in workers.iter() {
+        let _ = writeln!(
+            out,
+            "eduverse_worker_producers{{worker=\"{}\"}} {}",
+            worker_id, producers
+        );
+    }
+    header(
+        &mut out,
+        "eduverse_worker_consumers",
+        "gauge",
+        "Consumers on each media worker.",
+    );
+    for (worker_id, (_, consumers)) in workers.iter() {
+        let _ = writeln!(
+            out,
+            "eduverse_worker_consumers{{worker=\"{}\"}} {}",
+            worker_id, consumers
+        );
+    }
+
+    header(
+        &mut out,
+        "eduverse_broadcast_seconds",
+        "histogram",
+        "Time to send a message to everyone in a room.",
+    );
+    write_histogram(&mut out, "eduverse_broadcast_seconds", &BROADCAST_SECONDS);
+    header(
+        &mut out,
+        "eduverse_send_queue_depth",
+        "histogram",
+        "Messages queued for a WebSocket when its writer picks up the next one.",
+    );
+    write_histogram(&mut out, "eduverse_send_queue_depth", &SEND_QUEUE_DEPTH);
+
+    header(
+        &mut out,
+        "eduverse_join_failures_total",
+        "counter",
+        "Rejected joins by reason.",
+    );
+    write_labeled(
+        &mut out,
+        "eduverse_join_failures_total",
+        "reason",
+        &JOIN_FAILURES,
+    );
+    header(
+        &mut out,
+        "eduverse_rejected_moves_total",
+        "counter",
+        "Movement corrections sent back to clients.",
+    );
+    let _ = writeln!(
+        out,
+        "eduverse_rejected_moves_total {}",
+        REJECTED_MOVES.get()
+    );
+    header(
+        &mut out,
+        "eduverse_chat_messages_total",
+        "counter",
+        "Chat messages sent, by scope.",
+    );
+    write_labeled(
+        &mut out,
+        "eduverse_chat_messages_total",
+        "scope",
+        &CHAT_MESSAGES,
+    );
+
+    header(
+        &mut out,
+        "eduverse_chain_last_block",
+        "gauge",
+        "Last finalized block the indexer processed.",
+    );
+    let _ = writeln!(out, "eduverse_chain_last_block {}", CHAIN_LAST_BLOCK.get());
+    header(
+        &mut out,
+        "eduverse_chain_lag_blocks",
+        "gauge",
+        "Blocks between the finalized head and the last one processed.",
+    );
+    let _ = writeln!(out, "eduverse_chain_lag_blocks {}", CHAIN_LAG_BLOCKS.get());
+    header(
+        &mut out,
+        "eduverse_chain_decode_errors_total",
+        "counter",
+        "Contract events that couldn't be decoded.",
+    );
+    let _ = writeln!(
+        out,
+        "eduverse_chain_decode_errors_total {}",
+        CHAIN_DECODE_ERRORS.get()
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_are_escaped() {
+        let counter = LabeledCounter::new();
+        counter.inc("say \"hi\"");
+        counter.inc("two\nlines");
+        counter.inc("back\\slash");
+        counter.inc("two\nlines");
+
+        let mut out = String::new();
+        write_labeled(&mut out, "test_total", "reason", &counter);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"test_total{reason="back\\slash"} 1"#,
+                r#"test_total{reason="say \"hi\""} 1"#,
+                r#"test_total{reason="two\nlines"} 2"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[1.0, 5.0]);
+        for value in [0.5, 1.0, 3.0, 10.0] {
+            histogram.observe(value);
+        }
+
+        let mut out = String::new();
+        write_histogram(&mut out, "test_seconds", &histogram);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"test_seconds_bucket{le="1"} 2"#,
+                r#"test_seconds_bucket{le="5"} 3"#,
+                r#"test_seconds_bucket{le="+Inf"} 4"#,
+                "test_seconds_sum 14.5",
+                "test_seconds_count 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_histograms_still_have_every_series() {
+        let histogram = Histogram::new(QUEUE_DEPTH_BUCKETS);
+        let mut out = String::new();
+        write_histogram(&mut out, "test_depth", &histogram);
+        assert_eq!(out.lines().count(), QUEUE_DEPTH_BUCKETS.len() + 3);
+        assert!(out.contains("test_depth_bucket{le=\"+Inf\"} 0\n"));
+        assert!(out.contains("test_depth_sum 0\n"));
+        assert!(out.contains("test_depth_count 0\n"));
+    }
+
+    #[tokio::test]
+    async fn render_describes_every_series() {
+        let out = render().await;
+        for name in ["eduverse_broadcast_seconds", "eduverse_send_queue_depth"] {
+            assert!(out.contains(&format!("# TYPE {} histogram\n", name)));
+            assert!(out.contains(&format!("{}_bucket{{le=\"+Inf\"}} ", name)));
+            assert!(out.contains(&format!("{}_sum ", name)));
+            assert!(out.contains(&format!("{}_count ", name)));
+        }
+        assert!(out.contains("# TYPE eduverse_join_failures_total counter\n"));
+        assert!(out.contains("# TYPE eduverse_rooms gauge\n"));
+        // Every sample line is a name, optional labels and a number
+        for line in out.lines().filter(|line| !line.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use mediasoup::prelude::MediaKind;
+use parking_lot::Mutex as SyncMutex;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::identity::{AccountIdentity, ParticipantId};
+use crate::roles::{Permission, Role};
+use crate::room_manager::RoomManager;
+use crate::user::{verify_signature, User};
+use crate::ws_payload::ModerationPayload;
+
+// Append-only JSON-lines files, one per room for moderation and one for admissions
+const AUDIT_DIR: &str = "audit";
+// Signed actions older than this are rejected, so a captured signature can't be replayed later
+const MAX_SIGNATURE_AGE_SECS: u64 = 60;
+
+lazy_static! {
+    // Signatures already acted on, with their `issued_at`. Within the age window a
+    // signature is only good once, after it the age check turns it away anyway.
+    static ref SEEN_SIGNATURES: SyncMutex<HashMap<String, u64>> = SyncMutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    MuteUser,
+    StopVideo,
+    // Chat only, separate from the audio mute
+    MuteChat,
+    UnmuteChat,
+    Kick,
+    Ban,
+    LockRoom,
+    UnlockRoom,
+    MuteAll,
+    // Put an account on the room's delegation list with `role`
+    Delegate,
+    RevokeDelegation,
+}
+
+impl ModerationAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::MuteUser => "mute_user",
+            ModerationAction::StopVideo => "stop_video",
+            ModerationAction::MuteChat => "mute_chat",
+            ModerationAction::UnmuteChat => "unmute_chat",
+            ModerationAction::Kick => "kick",
+            ModerationAction::Ban => "ban",
+            ModerationAction::LockRoom => "lock_room",
+            ModerationAction::UnlockRoom => "unlock_room",
+            ModerationAction::MuteAll => "mute_all",
+            ModerationAction::Delegate => "delegate",
+            ModerationAction::RevokeDelegation => "revoke_delegation",
+        }
+    }
+
+    fn required_permission(&self) -> Permission {
+        match self {
+            ModerationAction::MuteUser
+            | ModerationAction::StopVideo
+            | ModerationAction::MuteChat
+            | ModerationAction::UnmuteChat
+            | ModerationAction::Kick => Permission::Moderate,
+            ModerationAction::Ban
+            | ModerationAction::LockRoom
+            | ModerationAction::UnlockRoom
+            | ModerationAction::MuteAll => Permission::ManageRoom,
+            ModerationAction::Delegate | ModerationAction::RevokeDelegation => Permission::Delegate,
+        }
+    }
+
+    fn needs_target(&self) -> bool {
+        matches!(
+            self,
+            ModerationAction::MuteUser
+                | ModerationAction::StopVideo
+                | ModerationAction::MuteChat
+                | ModerationAction::UnmuteChat
+                | ModerationAction::Kick
+                | ModerationAction::Ban
+                | ModerationAction::Delegate
+                | ModerationAction::RevokeDelegation
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp: u64,
+    room_id: u32,
+    teacher: &'a AccountIdentity,
+    action: ModerationAction,
+    target: Option<&'a AccountIdentity>,
+    role: Option<Role>,
+    // The exact message the teacher signed, and the sr25519 signature over it
+    message_signed: &'a str,
+    signature: &'a str,
+}
+
+/// The message a teacher signs to authorize a moderation action.
+pub fn signed_message(room_id: u32, payload: &ModerationPayload) -> String {
+    format!(
+        "eduverse-moderation:{}:{}:{}:{}:{}",
+        room_id,
+        payload.action.as_str(),
+        payload
+            .target
+            .as_ref()
+            .map(|target| target.to_string())
+            .unwrap_or_default(),
+        payload.role.map(|role| role.as_str()).unwrap_or(""),
+        payload.issued_at
+    )
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Checks that the sender's role allows the action and that they signed it, applies it,
+/// and records it in the room's audit log.
+pub async fn moderate(teacher_arc: Arc<Mutex<User>>, payload: ModerationPayload) {
+    let (room_id, teacher_id) = {
+        let teacher = teacher_arc.lock().await;
+        match (teacher.room_id, teacher.id.clone()) {
+            (Some(room_id), Some(teacher_id)) => (room_id, teacher_id),
+            _ => return,
+        }
+    };
+
+    if let Err(reason) = authorize(room_id, &teacher_id, &payload).await {
+        eprintln!(
+            "Rejected {} from {} in room {}: {}",
+            payload.action.as_str(),
+            teacher_id,
+            room_id,
+            reason
+        );
+        let reject_message = serde_json::json!({
+            "type": "moderation_rejected",
+            "action": payload.action,
+            "target": payload.target,
+            "reason": reason
+        });
+        teacher_arc.lock().await.send(reject_message.to_string());
+        return;
+    }
+
+    let message_signed = signed_message(room_id, &payload);
+    let entry = AuditEntry {
+        timestamp: now_secs(),
+        room_id,
+        teacher: teacher_id.account(),
+        action: payload.action,
+        target: payload.target.as_ref(),
+        role: payload.role,
+        message_signed: &message_signed,
+        signature: &payload.signature,
+    };
+    // Nothing gets applied unless it could be recorded first
+    let file_name = format!("room_{}.log", room_id);
+    if let Err(e) = append_audit_line(&file_name, &entry).await {
+        eprintln!("Failed to write audit log for room {}: {}", room_id, e);
+        let reject_message = serde_json::json!({
+            "type": "moderation_rejected",
+            "action": payload.action,
+            "target": payload.target,
+            "reason": "audit_log_unavailable"
+        });
+        teacher_arc.lock().await.send(reject_message.to_string());
+        return;
+    }
+
+    apply(room_id, &teacher_id, &payload).await;
+
+    let moderation_message = serde_json::json!({
+        "type": "moderation",
+        "action": payload.action,
+        "target": payload.target,
+        "role": payload.role
+    });
+    RoomManager::instance()
+        .broadcast_message(None, room_id, moderation_message.to_string())
+        .await;
+}
+
+async fn authorize(
+    room_id: u32,
+    teacher_id: &ParticipantId,
+    payload: &ModerationPayload,
+) -> Result<(), &'static str> {
+    let room_manager = RoomManager::instance();
+    let allowed = room_manager
+        .user_role(room_id, teacher_id)
+        .await
+        .map(|role| role.can(payload.action.required_permission()))
+        .unwrap_or(false);
+    if !allowed {
+        return Err("not_permitted");
+    }
+    if payload.action.needs_target() && payload.target.is_none() {
+        return Err("missing_target");
+    }
+    if let Some(target_id) = payload.target.as_ref() {
+        // Nobody moderates the teacher, including the teacher themselves by accident
+        if room_manager.resolve_role(room_id, target_id).await == Some(Role::Teacher) {
+            return Err("target_protected");
+        }
+    }
+    if payload.action == ModerationAction::Delegate
+        && !payload
+            .role
+            .map(|role| role.is_delegable())
+            .unwrap_or(false)
+    {
+        return Err("invalid_role");
+    }
+    if now_secs().abs_diff(payload.issued_at) > MAX_SIGNATURE_AGE_SECS {
+        return Err("stale_signature");
+    }
+    verify_signature(
+        teacher_id.account(),
+        payload.signature.clone(),
+        signed_message(room_id, payload),
+    )?;
+    mark_signature_used(&payload.signature, payload.issued_at)
+}
+
+// Remembers a verified signature, or refuses it if it was used before
+fn mark_signature_used(signature: &str, issued_at: u64) -> Result<(), &'static str> {
+    let now = now_secs();
+    let mut seen = SEEN_SIGNATURES.lock();
+    seen.retain(|_, issued_at| now.abs_diff(*issued_at) <= MAX_SIGNATURE_AGE_SECS);
+    if seen.insert(signature.to_string(), issued_at).is_some() {
+        return Err("replayed_signature");
+    }
+    Ok(())
+}
+
+async fn apply(room_id: u32, teacher_id: &ParticipantId, payload: &ModerationPayload) {
+    let room_manager = RoomManager::instance();
+    // Actions target an account, which covers every device it's signed in from.
+    // Kicks and bans reach into the breakout rooms too.
+    let targets = match (payload.target.as_ref(), payload.action) {
+        (Some(target_id), ModerationAction::Kick | ModerationAction::Ban) => {
+            room_manager
+                .find_account_in_course(room_id, target_id)
+                .await
+        }
+        (Some(target_id), _) => room_manager.find_account_users(room_id, target_id).await,
+        (None, _) => vec![],
+    };
+
+    match payload.action {
+        ModerationAction::MuteUser => {
+            for target in targets {
+                target.lock().await.force_pause(MediaKind::Audio).await;
+            }
+        }
+        ModerationAction::StopVideo => {
+            for target in targets {
+                target.lock().await.force_pause(MediaKind::Video).await;
+            }
+        }
+        ModerationAction::MuteChat | ModerationAction::UnmuteChat => {
+            let Some(target_id) = payload.target.as_ref() else {
+                return;
+            };
+            let muted = payload.action == ModerationAction::MuteChat;
+            room_manager.set_chat_muted(room_id, target_id, muted).await;
+            let muted_message = serde_json::json!({
+                "type": "chat_muted",
+                "muted": muted
+            });
+            for target in targets {
+                target.lock().await.send(muted_message.to_string());
+            }
+        }
+        ModerationAction::Kick | ModerationAction::Ban => {
+            if payload.action == ModerationAction::Ban {
+                if let Some(target_id) = payload.target.as_ref() {
+                    room_manager.ban_user(room_id, target_id).await;
+                }
+            }
+            for target in targets {
+                let kicked_message = serde_json::json!({
+                    "type": "kicked",
+                    "reason": payload.action
+                });
+                target.lock().await.send(kicked_message.to_string());
+                User::handle_leave_room(target).await;
+            }
+        }
+        ModerationAction::LockRoom => room_manager.set_locked(room_id, true).await,
+        ModerationAction::UnlockRoom => room_manager.set_locked(room_id, false).await,
+        ModerationAction::MuteAll => {
+            let users = room_manager.room_users(room_id).await.unwrap_or_default();
+            for user in users {
+                let mut user = user.lock().await;
+                if user.id.as_ref().map(|id| id.account()) != Some(teacher_id.account()) {
+                    user.force_pause(MediaKind::Audio).await;
+                }
+            }
+        }
+        ModerationAction::Delegate | ModerationAction::RevokeDelegation => {
+            let Some(target_id) = payload.target.as_ref() else {
+                return;
+            };
+            let delegated = match payload.action {
+                ModerationAction::Delegate => payload.role,
+                _ => None,
+            };
+            room_manager
+                .set_delegation(room_id, target_id, delegated)
+                .await;
+
+            // Someone already in the room switches role right away; losing a delegation
+            // drops them back to whatever the chain says, which may be nothing
+            let role = room_manager.resolve_role(room_id, target_id).await;
+            for target in targets {
+                match role {
+                    Some(role) => target.lock().await.assign_role(room_id, role).await,
+                    None => {
+                        let kicked_message = serde_json::json!({
+                            "type": "kicked",
+                            "reason": "delegation_revoked"
+                        });
+                        target.lock().await.send(kicked_message.to_string());
+                        User::handle_leave_room(target).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Appends an entry as one JSON line to a file in the audit directory.
+pub(crate) async fn append_audit_line(
+    file_name: &str,
+    entry: &impl Serialize,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tokio::fs::create_dir_all(AUDIT_DIR).await?;
+    let path = format!("{}/{}", AUDIT_DIR, file_name);
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,778 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+};
+use tokio::sync::Mutex;
+
+use crate::identity::AccountIdentity;
+use crate::roles::Permission;
+use crate::room_manager::RoomManager;
+use crate::user::User;
+use crate::ws_payload::{AnswerPollPayload, ClosePollPayload, CreatePollPayload};
+
+// Embedded database holding every room's polls with their answers, one tree per room.
+// Kept after the session so results can be exported and counted towards completion.
+const POLL_DB_PATH: &str = "poll_db";
+const MAX_QUESTION_LENGTH: usize = 500;
+const MAX_OPTIONS: usize = 10;
+const MAX_OPTION_LENGTH: usize = 200;
+const MAX_FREE_TEXT_LENGTH: usize = 500;
+// Answer window when the teacher doesn't set one, and the longest they can set
+const DEFAULT_POLL_SECS: u64 = 60;
+const MAX_POLL_SECS: u64 = 3600;
+
+static POLL_DB: OnceLock<sled::Db> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PollKind {
+    SingleChoice,
+    MultipleChoice,
+    FreeText,
+    Numeric,
+}
+
+/// An answer, or for quizzes the correct one. Choices are indices into the poll's options.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum PollAnswer {
+    SingleChoice(usize),
+    MultipleChoice(Vec<usize>),
+    FreeText(String),
+    Numeric(f64),
+}
+
+impl PollAnswer {
+    fn kind(&self) -> PollKind {
+        match self {
+            PollAnswer::SingleChoice(_) => PollKind::SingleChoice,
+            PollAnswer::MultipleChoice(_) => PollKind::MultipleChoice,
+            PollAnswer::FreeText(_) => PollKind::FreeText,
+            PollAnswer::Numeric(_) => PollKind::Numeric,
+        }
+    }
+
+    // Checks the answer fits the poll and puts it in a canonical form,
+    // so equal answers compare equal
+    fn normalize(self, poll_kind: PollKind, option_count: usize) -> Result<Self, &'static str> {
+        if self.kind() != poll_kind {
+            return Err("wrong_answer_kind");
+        }
+        match self {
+            PollAnswer::SingleChoice(choice) if choice >= option_count => Err("invalid_option"),
+            PollAnswer::MultipleChoice(mut choices) => {
+                if choices.iter().any(|choice| *choice >= option_count) {
+                    return Err("invalid_option");
+                }
+                choices.sort_unstable();
+                choices.dedup();
+                Ok(PollAnswer::MultipleChoice(choices))
+            }
+            PollAnswer::FreeText(text) => {
+                let text = text.trim();
+                if text.is_empty() {
+                    return Err("empty_answer");
+                }
+                if text.chars().count() > MAX_FREE_TEXT_LENGTH {
+                    return Err("answer_too_long");
+                }
+                Ok(PollAnswer::FreeText(text.to_string()))
+            }
+            PollAnswer::Numeric(value) if !value.is_finite() => Err("invalid_number"),
+            answer => Ok(answer),
+        }
+    }
+
+    fn matches(&self, correct: &PollAnswer, tolerance: f64) -> bool {
+        match (self, correct) {
+            (PollAnswer::FreeText(answer), PollAnswer::FreeText(correct)) => {
+                answer.to_lowercase() == correct.to_lowercase()
+            }
+            (PollAnswer::Numeric(answer), PollAnswer::Numeric(correct)) => {
+                (answer - correct).abs() <= tolerance
+            }
+            _ => self == correct,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PollResponse {
+    pub(crate) answer: PollAnswer,
+    pub(crate) answered_at: u64,
+    // Only set for quizzes
+    pub(crate) correct: Option<bool>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Poll {
+    pub(crate) id: u64,
+    pub(crate) room_id: u32,
+    pub(crate) created_by: AccountIdentity,
+    pub(crate) question: String,
+    pub(crate) kind: PollKind,
+    // Only for single and multiple choice
+    pub(crate) options: Vec<String>,
+    // Makes the poll a quiz
+    pub(crate) correct: Option<PollAnswer>,
+    // How far off a numeric answer can be and still count as correct
+    #[serde(default)]
+    pub(crate) tolerance: f64,
+    // Unix millis
+    pub(crate) opened_at: u64,
+    pub(crate) closes_at: u64,
+    pub(crate) closed: bool,
+    // Latest answer per account, students can change their mind until the poll closes
+    pub(crate) responses: BTreeMap<AccountIdentity, PollResponse>,
+}
+
+impl Poll {
+    pub(crate) fn is_open(&self) -> bool {
+        !self.closed && now_millis() < self.closes_at
+    }
+
+    /// What students see: the question, and once it's closed the results and the answer.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let mut poll = serde_json::json!({
+            "id": self.id,
+            "question": self.question,
+            "kind": self.kind,
+            "options": self.options,
+            "quiz": self.correct.is_some(),
+            "opened_at": self.opened_at,
+            "closes_at": self.closes_at,
+            "closed": self.closed
+        });
+        if self.closed {
+            poll["correct"] = serde_json::json!(self.correct);
+            poll["results"] = self.results();
+        }
+        poll
+    }
+
+    /// Aggregated answers: counts per option, per distinct text, or stats for numbers.
+    pub(crate) fn results(&self) -> serde_json::Value {
+        let answers = self.responses.values().map(|response| &response.answer);
+        let breakdown = match self.kind {
+            PollKind::SingleChoice | PollKind::MultipleChoice => {
+                let mut counts = vec![0u32; self.options.len()];
+                for answer in answers {
+                    let choices = match answer {
+                        PollAnswer::SingleChoice(choice) => std::slice::from_ref(choice),
+                        PollAnswer::MultipleChoice(choices) => choices.as_slice(),
+                        _ => &[],
+                    };
+                    for choice in choices {
+                        if let Some(count) = counts.get_mut(*choice) {
+                            *count += 1;
+                        }
+                    }
+                }
+                serde_json::json!({ "counts": counts })
+            }
+            PollKind::FreeText => {
+                let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+                for answer in answers {
+                    if let PollAnswer::FreeText(text) = answer {
+                        *counts.entry(text.to_lowercase()).or_default() += 1;
+                    }
+                }
+                serde_json::json!({ "counts": counts })
+            }
+            PollKind::Numeric => {
+                let values: Vec<f64> = answers
+                    .filter_map(|answer| match answer {
+                        PollAnswer::Numeric(value) => Some(*value),
+                        _ => None,
+                    })
+                    .collect();
+                let mean =
+                    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64);
+                serde_json::json!({
+                    "mean": mean,
+                    "min": values.iter().copied().reduce(f64::min),
+                    "max": values.iter().copied().reduce(f64::max)
+                })
+            }
+        };
+        let correct = self.correct.as_ref().map(|_| {
+            self.responses
+                .values()
+                .filter(|response| response.correct == Some(true))
+                .count()
+        });
+        serde_json::json!({
+            "responses": self.responses.len(),
+            "correct": correct,
+            "breakdown": breakdown
+        })
+    }
+}
+
+/// How one student did across a course's polls, for completion eligibility.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct PollScore {
+    // Polls and quizzes that were run in the room, and how many the student answered
+    pub(crate) polls: usize,
+    pub(crate) answered: usize,
+    // Quizzes only
+    pub(crate) quizzes: usize,
+    pub(crate) correct: usize,
+}
+
+/// Opens the poll store, call once at startup.
+pub fn init() -> Result<(), Box<dyn Error>> {
+    let db = sled::open(POLL_DB_PATH)?;
+    POLL_DB
+        .set(db)
+        .map_err(|_| "Poll store already initialized")?;
+    Ok(())
+}
+
+// Polls from breakout rooms are kept with the classroom's, whose room id is the course id
+fn course_polls(course_id: u32) -> Result<sled::Tree, Box<dyn Error + Send + Sync>> {
+    let db = POLL_DB.get().ok_or("Poll store not initialized")?;
+    Ok(db.open_tree(format!("room_{}", course_id))?)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn create(
+    course_id: u32,
+    room_id: u32,
+    created_by: AccountIdentity,
+    payload: CreatePollPayload,
+) -> Result<Poll, &'static str> {
+    let question = payload.question.trim().to_string();
+    if question.is_empty() || question.chars().count() > MAX_QUESTION_LENGTH {
+        return Err("invalid_question");
+    }
+    let has_options = matches!(
+        payload.kind,
+        PollKind::SingleChoice | PollKind::MultipleChoice
+    );
+    let options: Vec<String> = payload
+        .options
+        .iter()
+        .map(|option| option.trim().to_string())
+        .collect();
+    if has_options
+        && (options.len() < 2
+            || options.len() > MAX_OPTIONS
+            || options
+                .iter()
+                .any(|option| option.is_empty() || option.chars().count() > MAX_OPTION_LENGTH))
+    {
+        return Err("invalid_options");
+    }
+    if !has_options && !options.is_empty() {
+        return Err("invalid_options");
+    }
+    let correct = match payload.correct {
+        Some(correct) => Some(correct.normalize(payload.kind, options.len())?),
+        None => None,
+    };
+    let tolerance = payload.tolerance.unwrap_or(0.0);
+    if !tolerance.is_finite() || tolerance < 0.0 {
+        return Err("invalid_tolerance");
+    }
+    let duration_secs = payload
+        .duration_secs
+        .unwrap_or(DEFAULT_POLL_SECS)
+        .clamp(1, MAX_POLL_SECS);
+
+    let store = || -> Result<Poll, Box<dyn Error + Send + Sync>> {
+        let db = POLL_DB.get().ok_or("Poll store not initialized")?;
+        let opened_at = now_millis();
+        let poll = Poll {
+            id: db.generate_id()?,
+            room_id,
+            created_by,
+            question,
+            kind: payload.kind,
+            options,
+            correct,
+            tolerance,
+            opened_at,
+            closes_at: opened_at + duration_secs * 1000,
+            closed: false,
+            responses: BTreeMap::new(),
+        };
+        // Big-endian keys keep the tree sorted by id
+        course_polls(course_id)?.insert(poll.id.to_be_bytes(), serde_json::to_vec(&poll)?)?;
+        Ok(poll)
+    };
+    store().map_err(|e| {
+        eprintln!("Failed to store poll in room {}: {}", room_id, e);
+        "poll_unavailable"
+    })
+}
+
+// Reads, changes and writes back one poll atomically, `change` can refuse with a reason
+fn update(
+    course_id: u32,
+    poll_id: u64,
+    change: impl Fn(&mut Poll) -> Result<(), &'static str>,
+) -> Result<Poll, &'static str> {
+    let polls = course_polls(course_id).map_err(|e| {
+        eprintln!("Failed to open polls for course {}: {}", course_id, e);
+        "poll_unavailable"
+    })?;
+    let key = poll_id.to_be_bytes();
+    let result = polls.transaction(|tx| -> ConflictableTransactionResult<Poll, &'static str> {
+        let value = tx
+            .get(key)?
+            .ok_or(ConflictableTransactionError::Abort("poll_not_found"))?;
+        let mut poll: Poll = serde_json::from_slice(&value)
+            .map_err(|_| ConflictableTransactionError::Abort("poll_unavailable"))?;
+        change(&mut poll).map_err(ConflictableTransactionError::Abort)?;
+        let value = serde_json::to_vec(&poll)
+            .map_err(|_| ConflictableTransactionError::Abort("poll_unavailable"))?;
+        tx.insert(&key[..], value)?;
+        Ok(poll)
+    });
+    result.map_err(|e| match e {
+        TransactionError::Abort(reason) => reason,
+        TransactionError::Storage(e) => {
+            eprintln!(
+                "Failed to update poll {} in course {}: {}",
+                poll_id, course_id, e
+            );
+            "poll_unavailable"
+        }
+    })
+}
+
+fn answer(
+    course_id: u32,
+    room_id: u32,
+    poll_id: u64,
+    account: &AccountIdentity,
+    answer: PollAnswer,
+) -> Result<Poll, &'static str> {
+    update(course_id, poll_id, |poll| {
+        // Only the room the poll was opened in gets to answer it
+        if poll.room_id != room_id {
+            return Err("poll_not_found");
+        }
+        if !poll.is_open() {
+            return Err("poll_closed");
+        }
+        let answer = answer.clone().normalize(poll.kind, poll.options.len())?;
+        let correct = poll
+            .correct
+            .as_ref()
+            .map(|correct| answer.matches(correct, poll.tolerance));
+        poll.responses.insert(
+            account.clone(),
+            PollResponse {
+                answer,
+                answered_at: now_millis(),
+                correct,
+            },
+        );
+        Ok(())
+    })
+}
+
+fn close(course_id: u32, poll_id: u64) -> Result<Poll, &'static str> {
+    update(course_id, poll_id, |poll| {
+        if poll.closed {
+            return Err("poll_closed");
+        }
+        poll.closed = true;
+        // Closed early by the teacher, the window ends now
+        poll.closes_at = poll.closes_at.min(now_millis());
+        Ok(())
+    })
+}
+
+/// Every poll run in the course, breakout rooms included, oldest first.
+pub(crate) fn list(course_id: u32) -> Result<Vec<Poll>, Box<dyn Error + Send + Sync>> {
+    let mut polls = vec![];
+    for entry in course_polls(course_id)?.iter() {
+        let (_, value) = entry?;
+        polls.push(serde_json::from_slice(&value)?);
+    }
+    Ok(polls)
+}
+
+/// Polls students in the room can still answer, for join and reconnect snapshots.
+pub(crate) async fn open_polls(room_id: u32) -> Vec<serde_json::Value> {
+    let course_id = RoomManager::instance().course_of(room_id).await;
+    match list(course_id) {
+        Ok(polls) => polls
+            .iter()
+            .filter(|poll| poll.room_id == room_id && poll.is_open())
+            .map(|poll| poll.to_json())
+            .collect(),
+        Err(e) => {
+            eprintln!("Failed to read polls for room {}: {}", room_id, e);
+            vec![]
+        }
+    }
+}
+
+/// How a student did across the course's polls and quizzes.
+pub(crate) fn score(
+    course_id: u32,
+    account: &AccountIdentity,
+) -> Result<PollScore, Box<dyn Error + Send + Sync>> {
+    Ok(score_in(&list(course_id)?, account))
+}
+
+fn score_in(polls: &[Poll], account: &AccountIdentity) -> PollScore {
+    let mut score = PollScore::default();
+    for poll in polls {
+        let response = poll.responses.get(account);
+        score.polls += 1;
+        score.answered += response.is_some() as usize;
+        if poll.correct.is_some() {
+            score.quizzes += 1;
+            score.correct +=
+                response.is_some_and(|response| response.correct == Some(true)) as usize;
+        }
+    }
+    score
+}
+
+/// Every poll with its results and each student's answer, plus a score per student.
+pub(crate) fn export(course_id: u32) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+    let polls = list(course_id)?;
+    let students: HashSet<&AccountIdentity> = polls
+        .iter()
+        .flat_map(|poll| poll.responses.keys())
+        .collect();
+    let scores: BTreeMap<&AccountIdentity, PollScore> = students
+        .into_iter()
+        .map(|student| (student, score_in(&polls, student)))
+        .collect();
+    let polls: Vec<_> = polls
+        .iter()
+        .map(|poll| {
+            serde_json::json!({
+                "id": poll.id,
+                "created_by": poll.created_by,
+                "question": poll.question,
+                "kind": poll.kind,
+                "options": poll.options,
+                "correct": poll.correct,
+                "tolerance": poll.tolerance,
+                "opened_at": poll.opened_at,
+                "closes_at": poll.closes_at,
+                "results": poll.results(),
+                "responses": poll.responses
+            })
+        })
+        .collect();
+    Ok(serde_json::json!({
+        "room_id": course_id,
+        "polls": polls,
+        "scores": scores
+    }))
+}
+
+/// Opens a poll or quiz for the room and closes it when its answer window runs out.
+pub async fn create_poll(user_arc: Arc<Mutex<User>>, payload: CreatePollPayload) {
+    let (poll, course_id) = {
+        let user = user_arc.lock().await;
+        if !user.can(Permission::Poll) {
+            user.reject_action("create_poll", "not_permitted");
+            return;
+        }
+        let (Some(room_id), Some(user_id)) = (user.room_id, user.id.as_ref()) else {
+            return;
+        };
+        let course_id = RoomManager::instance().course_of(room_id).await;
+        match create(course_id, room_id, user_id.account().clone(), payload) {
+            Ok(poll) => (poll, course_id),
+            Err(reason) => {
+                user.reject_action("create_poll", reason);
+                return;
+            }
+        }
+    };
+
+    let opened_message = serde_json::json!({
+        "type": "poll_opened",
+        "poll": poll.to_json()
+    });
+    RoomManager::instance()
+        .broadcast_message(None, poll.room_id, opened_message.to_string())
+        .await;
+
+    let poll_id = poll.id;
+    let window = Duration::from_millis(poll.closes_at.saturating_sub(poll.opened_at));
+    tokio::spawn(async move {
+        tokio::time::sleep(window).await;
+        // Already closed by the teacher if this fails
+        if let Ok(poll) = close(course_id, poll_id) {
+            announce_closed(&poll).await;
+        }
+    });
+}
+
+/// Records a student's answer, and sends the updated results to whoever runs polls.
+pub async fn answer_poll(user_arc: Arc<Mutex<User>>, payload: AnswerPollPayload) {
+    let poll = {
+        let user = user_arc.lock().await;
+        if !user.can(Permission::Chat) {
+            user.reject_action("answer_poll", "not_permitted");
+            return;
+        }
+        let (Some(room_id), Some(user_id)) = (user.room_id, user.id.as_ref()) else {
+            return;
+        };
+        let course_id = RoomManager::instance().course_of(room_id).await;
+        match answer(
+            course_id,
+            room_id,
+            payload.poll_id,
+            user_id.account(),
+            payload.answer,
+        ) {
+            Ok(poll) => {
+                let recorded_message = serde_json::json!({
+                    "type": "poll_answer_recorded",
+                    "poll_id": poll.id
+                });
+                user.send(recorded_message.to_string());
+                poll
+            }
+            Err(reason) => {
+                user.reject_action("answer_poll", reason);
+                return;
+            }
+        }
+    };
+
+    send_live_results(&poll).await;
+}
+
+pub async fn close_poll(user_arc: Arc<Mutex<User>>, payload: ClosePollPayload) {
+    let poll = {
+        let user = user_arc.lock().await;
+        if !user.can(Permission::Poll) {
+            user.reject_action("close_poll", "not_permitted");
+            return;
+        }
+        let Some(room_id) = user.room_id else {
+            return;
+        };
+        let course_id = RoomManager::instance().course_of(room_id).await;
+        match close(course_id, payload.poll_id) {
+            Ok(poll) => poll,
+            Err(reason) => {
+                user.reject_action("close_poll", reason);
+                return;
+            }
+        }
+    };
+    announce_closed(&poll).await;
+}
+
+/// Sends the course's polls with every student's answers back to the teacher.
+pub async fn export_polls(user_arc: Arc<Mutex<User>>) {
+    let user = user_arc.lock().await;
+    if !user.can(Permission::Poll) {
+        user.reject_action("export_polls", "not_permitted");
+        return;
+    }
+    let Some(room_id) = user.room_id else {
+        return;
+    };
+    let course_id = RoomManager::instance().course_of(room_id).await;
+    match export(course_id) {
+        Ok(export) => {
+            let export_message = serde_json::json!({
+                "type": "poll_export",
+                "export": export
+            });
+            user.send(export_message.to_string());
+        }
+        Err(e) => {
+            eprintln!("Failed to export polls for course {}: {}", course_id, e);
+            user.reject_action("export_polls", "poll_unavailable");
+        }
+    }
+}
+
+// Results are live for staff only, students see them once the poll closes
+async fn send_live_results(poll: &Poll) {
+    let room_manager = RoomManager::instance();
+    let results_message = serde_json::json!({
+        "type": "poll_results",
+        "poll_id": poll.id,
+        "results": poll.results()
+    });
+    let staff = room_manager
+        .users_with_permission(poll.room_id, Permission::Poll)
+        .await;
+    room_manager
+        .send_to_users(poll.room_id, &staff, results_message.to_string())
+        .await;
+}
+
+async fn announce_closed(poll: &Poll) {
+    let closed_message = serde_json::json!({
+        "type": "poll_closed",
+        "poll": poll.to_json()
+    });
+    RoomManager::instance()
+        .broadcast_message(None, poll.room_id, closed_message.to_string())
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll(kind: PollKind, options: usize, correct: Option<PollAnswer>) -> Poll {
+        Poll {
+            id: 1,
+            room_id: 1,
+            created_by: AccountIdentity::from([1; 32]),
+            question: "Which?".to_string(),
+            kind,
+            options: (0..options).map(|option| option.to_string()).collect(),
+            correct,
+            tolerance: 0.5,
+            opened_at: 0,
+            closes_at: 0,
+            closed: true,
+            responses: BTreeMap::new(),
+        }
+    }
+
+    fn respond(poll: &mut Poll, student: u8, answer: PollAnswer) {
+        let correct = poll
+            .correct
+            .as_ref()
+            .map(|correct| answer.matches(correct, poll.tolerance));
+        poll.responses.insert(
+            AccountIdentity::from([student; 32]),
+            PollResponse {
+                answer,
+                answered_at: 0,
+                correct,
+            },
+        );
+    }
+
+    #[test]
+    fn answers_are_checked_and_normalized() {
+        assert_eq!(
+            PollAnswer::MultipleChoice(vec![2, 0, 2]).normalize(PollKind::MultipleChoice, 3),
+            Ok(PollAnswer::MultipleChoice(vec![0, 2]))
+        );
+        assert_eq!(
+            PollAnswer::FreeText("  Paris ".to_string()).normalize(PollKind::FreeText, 0),
+            Ok(PollAnswer::FreeText("Paris".to_string()))
+        );
+        assert_eq!(
+            PollAnswer::SingleChoice(3).normalize(PollKind::SingleChoice, 3),
+            Err("invalid_option")
+        );
+        assert_eq!(
+            PollAnswer::SingleChoice(0).normalize(PollKind::MultipleChoice, 3),
+            Err("wrong_answer_kind")
+        );
+        assert_eq!(
+            PollAnswer::FreeText(" ".to_string()).normalize(PollKind::FreeText, 0),
+            Err("empty_answer")
+        );
+        assert_eq!(
+            PollAnswer::Numeric(f64::NAN).normalize(PollKind::Numeric, 0),
+            Err("invalid_number")
+        );
+    }
+
+    #[test]
+    fn answers_match_ignoring_case_and_within_tolerance() {
+        let paris = PollAnswer::FreeText("Paris".to_string());
+        assert!(PollAnswer::FreeText("paris".to_string()).matches(&paris, 0.0));
+        assert!(PollAnswer::Numeric(3.4).matches(&PollAnswer::Numeric(3.0), 0.5));
+        assert!(!PollAnswer::Numeric(3.6).matches(&PollAnswer::Numeric(3.0), 0.5));
+        assert!(!PollAnswer::MultipleChoice(vec![0])
+            .matches(&PollAnswer::MultipleChoice(vec![0, 1]), 0.0));
+    }
+
+    #[test]
+    fn choice_results_count_every_pick() {
+        let mut poll = poll(PollKind::MultipleChoice, 3, None);
+        respond(&mut poll, 2, PollAnswer::MultipleChoice(vec![0, 2]));
+        respond(&mut poll, 3, PollAnswer::MultipleChoice(vec![2]));
+        // A changed answer replaces the earlier one
+        respond(&mut poll, 3, PollAnswer::MultipleChoice(vec![1, 2]));
+        assert_eq!(
+            poll.results(),
+            serde_json::json!({
+                "responses": 2,
+                "correct": null,
+                "breakdown": { "counts": [1, 1, 2] }
+            })
+        );
+    }
+
+    #[test]
+    fn text_and_numeric_results_are_aggregated() {
+        let mut text = poll(PollKind::FreeText, 0, None);
+        respond(&mut text, 2, PollAnswer::FreeText("Paris".to_string()));
+        respond(&mut text, 3, PollAnswer::FreeText("paris".to_string()));
+        respond(&mut text, 4, PollAnswer::FreeText("Lyon".to_string()));
+        assert_eq!(
+            text.results()["breakdown"],
+            serde_json::json!({ "counts": { "lyon": 1, "paris": 2 } })
+        );
+
+        let mut numeric = poll(PollKind::Numeric, 0, Some(PollAnswer::Numeric(3.0)));
+        assert_eq!(
+            numeric.results()["breakdown"],
+            serde_json::json!({ "mean": null, "min": null, "max": null })
+        );
+        respond(&mut numeric, 2, PollAnswer::Numeric(2.0));
+        respond(&mut numeric, 3, PollAnswer::Numeric(3.25));
+        assert_eq!(
+            numeric.results(),
+            serde_json::json!({
+                "responses": 2,
+                "correct": 1,
+                "breakdown": { "mean": 2.625, "min": 2.0, "max": 3.25 }
+            })
+        );
+    }
+
+    #[test]
+    fn score_counts_answers_and_correct_quiz_answers() {
+        let mut survey = poll(PollKind::SingleChoice, 2, None);
+        respond(&mut survey, 2, PollAnswer::SingleChoice(1));
+        let mut right = poll(PollKind::SingleChoice, 2, Some(PollAnswer::SingleChoice(0)));
+        respond(&mut right, 2, PollAnswer::SingleChoice(0));
+        respond(&mut right, 3, PollAnswer::SingleChoice(1));
+        let mut wrong = poll(PollKind::Numeric, 0, Some(PollAnswer::Numeric(10.0)));
+        respond(&mut wrong, 2, PollAnswer::Numeric(12.0));
+        let unanswered = poll(PollKind::SingleChoice, 2, Some(PollAnswer::SingleChoice(0)));
+        let polls = [survey, right, wrong, unanswered];
+
+        let score = score_in(&polls, &AccountIdentity::from([2; 32]));
+        assert_eq!(
+            (score.polls, score.answered, score.quizzes, score.correct),
+            (4, 3, 3, 1)
+        );
+        let score = score_in(&polls, &AccountIdentity::from([3; 32]));
+        assert_eq!(
+            (score.polls, score.answered, score.quizzes, score.correct),
+            (4, 1, 3, 0)
+        );
+    }
+}
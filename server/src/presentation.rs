@@ -0,0 +1,255 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::identity::ParticipantId;
+use crate::roles::Permission;
+use crate::room_manager::RoomManager;
+use crate::user::User;
+use crate::ws_payload::{
+    FollowPresenterPayload, LaserPointerPayload, SetSlidePayload, StartPresentationPayload,
+};
+
+const MAX_PAGES: usize = 500;
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_HASH_LENGTH: usize = 128;
+// Laser pointer updates faster than this are dropped, 10 a second is plenty to follow
+const LASER_INTERVAL: Duration = Duration::from_millis(100);
+// Who can present and turn the slides
+const PRESENTER_PERMISSION: Permission = Permission::ManageRoom;
+
+/// Where the slides come from: page images, or the course content by its metadata hash,
+/// which clients resolve themselves.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Deck {
+    Pages { urls: Vec<String> },
+    ContentHash { hash: String, page_count: u32 },
+}
+
+impl Deck {
+    fn page_count(&self) -> u32 {
+        match self {
+            Deck::Pages { urls } => urls.len() as u32,
+            Deck::ContentHash { page_count, .. } => *page_count,
+        }
+    }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.page_count() == 0 || self.page_count() as usize > MAX_PAGES {
+            return Err("invalid_page_count");
+        }
+        match self {
+            Deck::Pages { urls } => {
+                let valid = |url: &String| {
+                    url.len() <= MAX_URL_LENGTH
+                        && (url.starts_with("https://") || url.starts_with("http://"))
+                };
+                if !urls.iter().all(valid) {
+                    return Err("invalid_url");
+                }
+            }
+            Deck::ContentHash { hash, .. } => {
+                let hash = hash.strip_prefix("0x").unwrap_or(hash);
+                if hash.is_empty()
+                    || hash.len() > MAX_HASH_LENGTH
+                    || !hash.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    return Err("invalid_hash");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The deck on screen in a room and the slide the presenter is on.
+#[derive(Clone, Debug, Serialize)]
+pub struct Presentation {
+    pub(crate) deck: Deck,
+    pub(crate) presenter: ParticipantId,
+    // Zero based
+    pub(crate) slide: u32,
+    #[serde(skip)]
+    pub(crate) last_laser: Option<Instant>,
+}
+
+impl Presentation {
+    pub(crate) fn set_slide(&mut self, slide: u32) -> Result<(), &'static str> {
+        if slide >= self.deck.page_count() {
+            return Err("invalid_slide");
+        }
+        self.slide = slide;
+        Ok(())
+    }
+
+    /// Whether a laser update may go out now, at most one position per `LASER_INTERVAL`.
+    /// Hiding always goes out, a dropped hide would leave the pointer stuck on screen.
+    pub(crate) fn take_laser_slot(&mut self, hiding: bool) -> bool {
+        if hiding {
+            return true;
+        }
+        let now = Instant::now();
+        if self
+            .last_laser
+            .is_some_and(|last| now.duration_since(last) < LASER_INTERVAL)
+        {
+            return false;
+        }
+        self.last_laser = Some(now);
+        true
+    }
+}
+
+// The room and presenter, if the user may present
+async fn presenter_of(user_arc: &Arc<Mutex<User>>, action: &str) -> Option<(u32, ParticipantId)> {
+    let user = user_arc.lock().await;
+    if !user.can(PRESENTER_PERMISSION) {
+        user.reject_action(action, "not_permitted");
+        return None;
+    }
+    let (Some(room_id), Some(user_id)) = (user.room_id, user.id.clone()) else {
+        return None;
+    };
+    Some((room_id, user_id))
+}
+
+/// Puts a deck up, replacing whatever was being presented.
+pub async fn start_presentation(user_arc: Arc<Mutex<User>>, payload: StartPresentationPayload) {
+    let Some((room_id, user_id)) = presenter_of(&user_arc, "start_presentation").await else {
+        return;
+    };
+    if let Err(reason) = payload.deck.validate() {
+        user_arc
+            .lock()
+            .await
+            .reject_action("start_presentation", reason);
+        return;
+    }
+    let mut presentation = Presentation {
+        deck: payload.deck,
+        presenter: user_id,
+        slide: 0,
+        last_laser: None,
+    };
+    if let Err(reason) = presentation.set_slide(payload.slide.unwrap_or(0)) {
+        user_arc
+            .lock()
+            .await
+            .reject_action("start_presentation", reason);
+        return;
+    }
+
+    let started_message = serde_json::json!({
+        "type": "presentation_started",
+        "presentation": presentation
+    });
+    let room_manager = RoomManager::instance();
+    room_manager.start_presentation(room_id, presentation).await;
+    room_manager
+        .broadcast_message(None, room_id, started_message.to_string())
+        .await;
+}
+
+/// Moves everyone following the presenter to another slide.
+pub async fn set_slide(user_arc: Arc<Mutex<User>>, payload: SetSlidePayload) {
+    let Some((room_id, user_id)) = presenter_of(&user_arc, "set_slide").await else {
+        return;
+    };
+    let room_manager = RoomManager::instance();
+    if let Err(reason) = room_manager
+        .set_slide(room_id, &user_id, payload.slide)
+        .await
+    {
+        user_arc.lock().await.reject_action("set_slide", reason);
+        return;
+    }
+
+    // Free browsers get it too, so they know where the presenter is
+    let slide_message = serde_json::json!({
+        "type": "slide_changed",
+        "presenter": user_id,
+        "slide": payload.slide
+    });
+    room_manager
+        .broadcast_message(None, room_id, slide_message.to_string())
+        .await;
+}
+
+pub async fn end_presentation(user_arc: Arc<Mutex<User>>) {
+    let Some((room_id, _)) = presenter_of(&user_arc, "end_presentation").await else {
+        return;
+    };
+    let room_manager = RoomManager::instance();
+    if !room_manager.end_presentation(room_id).await {
+        return;
+    }
+    let ended_message = serde_json::json!({
+        "type": "presentation_ended"
+    });
+    room_manager
+        .broadcast_message(None, room_id, ended_message.to_string())
+        .await;
+}
+
+/// Where the presenter points on the current slide, in 0..1 slide coordinates,
+/// or `None` to hide the pointer. Only followers get it, nobody else has that slide up.
+pub async fn laser_pointer(user_arc: Arc<Mutex<User>>, payload: LaserPointerPayload) {
+    let Some((room_id, user_id)) = presenter_of(&user_arc, "laser_pointer").await else {
+        return;
+    };
+    if let Some(position) = payload.position {
+        if !position.iter().all(|v| (0.0..=1.0).contains(v)) {
+            user_arc
+                .lock()
+                .await
+                .reject_action("laser_pointer", "invalid_position");
+            return;
+        }
+    }
+    let room_manager = RoomManager::instance();
+    // Over the rate or nothing being presented, either way there's nothing to show
+    let hiding = payload.position.is_none();
+    let Some(slide) = room_manager.take_laser_slot(room_id, hiding).await else {
+        return;
+    };
+
+    let laser_message = serde_json::json!({
+        "type": "laser_pointer",
+        "slide": slide,
+        "position": payload.position
+    });
+    let Some(users) = room_manager.room_users(room_id).await else {
+        return;
+    };
+    for user_arc in users {
+        let user = user_arc.lock().await;
+        if user.follow_presenter && user.id.as_ref() != Some(&user_id) {
+            user.send(laser_message.to_string());
+        }
+    }
+}
+
+/// Switches the sender between following the presenter and browsing the deck freely.
+/// Coming back to follow sends the slide the presenter is on.
+pub async fn follow_presenter(user_arc: Arc<Mutex<User>>, payload: FollowPresenterPayload) {
+    let mut user = user_arc.lock().await;
+    let Some(room_id) = user.room_id else {
+        return;
+    };
+    user.follow_presenter = payload.follow;
+    if !payload.follow {
+        return;
+    }
+    let Some(presentation) = RoomManager::instance().presentation(room_id).await else {
+        return;
+    };
+    let slide_message = serde_json::json!({
+        "type": "slide_changed",
+        "presenter": presentation.presenter,
+        "slide": presentation.slide
+    });
+    user.send(slide_message.to_string());
+}
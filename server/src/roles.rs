@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // Created the course on chain
+    Teacher,
+    // Delegated by the teacher for this room
+    CoTeacher,
+    TeachingAssistant,
+    // Enrolled on chain
+    Student,
+    // Delegated read-only access, e.g. accreditation auditors. Doesn't need to be enrolled.
+    Observer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Chat,
+    // Room-wide announcements
+    Announce,
+    // Camera and microphone
+    Produce,
+    ShareScreen,
+    Record,
+    // Run polls and quizzes and see their live results
+    Poll,
+    // Draw on the whiteboard, when the teacher lets everyone
+    Draw,
+    // Heard room-wide from a stage zone
+    Stage,
+    // Heard room-wide from the podium
+    Podium,
+    // Mute, stop video, kick
+    Moderate,
+    // Ban, lock the room, mute everyone
+    ManageRoom,
+    // Hand out co-teacher, TA and observer roles
+    Delegate,
+}
+
+impl Role {
+    pub fn can(&self, permission: Permission) -> bool {
+        use Permission::*;
+        match self {
+            Role::Teacher => true,
+            Role::CoTeacher => permission != Delegate,
+            Role::TeachingAssistant => {
+                matches!(
+                    permission,
+                    Chat | Produce | ShareScreen | Stage | Poll | Draw | Moderate
+                )
+            }
+            Role::Student => matches!(permission, Chat | Produce | Draw),
+            Role::Observer => false,
+        }
+    }
+
+    /// Roles the teacher can hand out through the delegation list.
+    pub fn is_delegable(&self) -> bool {
+        matches!(
+            self,
+            Role::CoTeacher | Role::TeachingAssistant | Role::Observer
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Teacher => "teacher",
+            Role::CoTeacher => "co_teacher",
+            Role::TeachingAssistant => "teaching_assistant",
+            Role::Student => "student",
+            Role::Observer => "observer",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_PERMISSIONS: [Permission; 12] = [
+        Permission::Chat,
+        Permission::Announce,
+        Permission::Produce,
+        Permission::ShareScreen,
+        Permission::Record,
+        Permission::Poll,
+        Permission::Draw,
+        Permission::Stage,
+        Permission::Podium,
+        Permission::Moderate,
+        Permission::ManageRoom,
+        Permission::Delegate,
+    ];
+
+    fn permissions(role: Role) -> Vec<Permission> {
+        ALL_PERMISSIONS
+            .into_iter()
+            .filter(|permission| role.can(*permission))
+            .collect()
+    }
+
+    #[test]
+    fn only_the_teacher_delegates() {
+        assert_eq!(permissions(Role::Teacher), ALL_PERMISSIONS);
+        assert_eq!(
+            permissions(Role::CoTeacher),
+            ALL_PERMISSIONS[..ALL_PERMISSIONS.len() - 1]
+        );
+    }
+
+    #[test]
+    fn assistants_help_run_the_class_but_not_the_room() {
+        use Permission::*;
+        assert_eq!(
+            permissions(Role::TeachingAssistant),
+            [Chat, Produce, ShareScreen, Poll, Draw, Stage, Moderate]
+        );
+    }
+
+    #[test]
+    fn students_take_part_and_observers_only_watch() {
+        use Permission::*;
+        assert_eq!(permissions(Role::Student), [Chat, Produce, Draw]);
+        assert!(permissions(Role::Observer).is_empty());
+    }
+
+    #[test]
+    fn teachers_and_students_come_from_the_chain() {
+        assert!(!Role::Teacher.is_delegable());
+        assert!(!Role::Student.is_delegable());
+        assert!(Role::CoTeacher.is_delegable());
+        assert!(Role::TeachingAssistant.is_delegable());
+        assert!(Role::Observer.is_delegable());
+    }
+}
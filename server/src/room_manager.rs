@@ -1,50 +1,304 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::env;
 use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr};
 use std::num::{NonZeroU32, NonZeroU8};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use futures_util::SinkExt;
+use std::time::{Duration, Instant};
+use arc_swap::ArcSwap;
 use lazy_static::lazy_static;
-use mediasoup::prelude::{RtpCodecParametersParameters, WorkerSettings};
+use mediasoup::prelude::{
+    ListenInfo, ProducerId, Protocol, RtpCapabilities, RtpCapabilitiesFinalized,
+    RtpCodecParametersParameters, WebRtcTransport, WebRtcTransportListenInfos,
+    WebRtcTransportOptions, WorkerSettings,
+};
 use mediasoup::router::{Router, RouterOptions};
 use mediasoup::rtp_parameters::{MimeTypeAudio, MimeTypeVideo, RtpCodecCapability};
 use mediasoup::worker::{Worker, WorkerId};
 use mediasoup::worker_manager::WorkerManager;
 use tokio::sync::{Mutex, RwLock};
 use tokio::time::timeout;
-use tokio_tungstenite::tungstenite::Message;
 
+use crate::attendance::{AttendanceEventKind, AttendanceSample, AttendanceSession};
+use crate::breakout::BreakoutSession;
+use crate::capacity::{Capacity, CourseParams, Occupancy, RoomPhase, RoomSummary};
+use crate::chat::{ChatScope, Delivery};
+use crate::identity::{AccountIdentity, ParticipantId};
+use crate::lobby::LobbyEntry;
+use crate::metrics;
+use crate::presentation::Presentation;
+use crate::classroom_map::{ClassroomMap, Zone, ZoneKind};
+use crate::roles::{Permission, Role};
+use crate::room_tick;
+use crate::session;
 use crate::stream_types::StreamInfo;
 use crate::user::User;
+use crate::whiteboard::{self, DrawMode, Whiteboard, WhiteboardOp};
 
 lazy_static! {
     static ref ROOM_MANAGER: Arc<RoomManager> = {
         let manager = RoomManager::new();
         Arc::new(manager)
     };
+    // Where WebRTC transports listen, and the address clients are given instead
+    // when the server sits behind NAT
+    static ref MEDIA_LISTEN_IP: IpAddr = env::var("EDUVERSE_MEDIA_LISTEN_IP")
+        .ok()
+        .and_then(|ip| ip.parse().ok())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    static ref MEDIA_ANNOUNCED_ADDRESS: Option<String> =
+        env::var("EDUVERSE_MEDIA_ANNOUNCED_ADDRESS").ok();
 }
 
+pub type UserHandle = Arc<Mutex<User>>;
+
+// Breakout rooms get ids from the top half of the u32 range, course ids stay well below it
+const BREAKOUT_ID_BASE: u32 = 1 << 31;
+
 pub struct Room {
-    pub(crate) teacher: String,
+    // Teacher, student cap and schedule from the contract, breakout rooms share their classroom's
+    pub(crate) course: CourseParams,
     pub(crate) name: String,
-    pub(crate) users: RwLock<Vec<Arc<Mutex<User>>>>,
+    // The classroom a breakout room was split from
+    pub(crate) parent: Option<u32>,
+    // Everyone in the room by account. Joins and leaves swap in a new map,
+    // so reading the occupants never waits on a lock.
+    members: ArcSwap<HashMap<ParticipantId, UserHandle>>,
     // each room has its own router
     router: Router,
+    // Walls, desks, spawn points and seats loaded from the course's Tiled map
+    pub(crate) map: ClassroomMap,
+    // Set when someone joins or leaves so the next tick refreshes everyone's view
+    views_dirty: AtomicBool,
+    // Locked per room, changing one room never blocks another
+    state: RwLock<RoomState>,
+}
+
+// Everything about a room that changes during the session
+#[derive(Default)]
+struct RoomState {
     // Track all active streams in the room
     active_streams: HashMap<String, StreamInfo>,
     // Spatial grid for quick proximity checks
-    spatial_grid: HashMap<(i32, i32), Vec<String>>,
-    // Example: {
-    //   (10,10): ["user1", "user2"],
-    //   (11,10): ["user3"]
-    // }
+    spatial_grid: SpatialGrid,
+    // Moderation state for this session, set by the teacher
+    banned: HashSet<AccountIdentity>,
+    // Can't send chat messages, their audio is left alone
+    chat_muted: HashSet<AccountIdentity>,
+    locked: bool,
+    // New arrivals wait in the lobby until a teacher lets them in
+    waiting_room: bool,
+    // In order of arrival
+    lobby: Vec<LobbyEntry>,
+    recording: bool,
+    // Roles the teacher handed out for this room, by account
+    delegations: HashMap<AccountIdentity, Role>,
+    // Accounts seen enrolling on chain
+    enrolled: HashSet<AccountIdentity>,
+    // Role of everyone currently in the room
+    roles: HashMap<ParticipantId, Role>,
+    // Raised hands, in the order they went up
+    hand_queue: VecDeque<ParticipantId>,
+    // Whoever the teacher gave the floor to, heard room-wide until it ends
+    floor: Option<Floor>,
+    // Counts grants, so a timeout only ends the grant it was started for
+    floor_grants: u64,
+    // Starts with the first join into an empty room, ends when the last one leaves
+    attendance: Option<AttendanceSession>,
+    // Outlives sessions, it's only cleared by the teacher
+    whiteboard: Whiteboard,
+    // The deck on screen, if the teacher is presenting
+    presentation: Option<Presentation>,
+    // Breakout rooms the class is split into right now
+    breakouts: Option<BreakoutSession>,
+    // Counts breakout sessions, so a timer only ends the one it was started for
+    breakout_generations: u64,
+}
+
+#[derive(Clone)]
+pub(crate) struct Floor {
+    pub(crate) holder: ParticipantId,
+    pub(crate) grant: u64,
+}
+
+impl Room {
+    /// Snapshot of the room's occupants.
+    pub(crate) fn members(&self) -> Arc<HashMap<ParticipantId, UserHandle>> {
+        self.members.load_full()
+    }
+
+    // False if someone already holds this id
+    fn add_member(&self, user_id: &ParticipantId, user: &UserHandle) -> bool {
+        let mut added = false;
+        self.members.rcu(|members| {
+            added = !members.contains_key(user_id);
+            let mut members = HashMap::clone(members);
+            members
+                .entry(user_id.clone())
+                .or_insert_with(|| user.clone());
+            members
+        });
+        added
+    }
+
+    fn remove_member(&self, user_id: &ParticipantId) {
+        self.members.rcu(|members| {
+            let mut members = HashMap::clone(members);
+            members.remove(user_id);
+            members
+        });
+    }
+}
+
+// Who stands on which tile. Example: {
+//   (10,10): ["user1", "user2"],
+//   (11,10): ["user3"]
+// }
+#[derive(Default)]
+struct SpatialGrid(HashMap<(i32, i32), Vec<ParticipantId>>);
+
+impl SpatialGrid {
+    fn occupied_tiles(&self) -> HashSet<(i32, i32)> {
+        self.0
+            .iter()
+            .filter(|(_, user_ids)| !user_ids.is_empty())
+            .map(|(tile, _)| *tile)
+            .collect()
+    }
+
+    fn add(&mut self, user_id: &ParticipantId, tile: (i32, i32)) {
+        self.0.entry(tile).or_default().push(user_id.clone());
+    }
+
+    fn remove(&mut self, user_id: &ParticipantId, tile: (i32, i32)) {
+        if let Some(user_ids) = self.0.get_mut(&tile) {
+            user_ids.retain(|id| id != user_id);
+            if user_ids.is_empty() {
+                self.0.remove(&tile);
+            }
+        }
+    }
+
+    /// Users standing within `radius` tiles of `center`.
+    fn users_in_view(
+        &self,
+        center: (i32, i32),
+        radius: i32,
+    ) -> Vec<(ParticipantId, (i32, i32))> {
+        let mut found = vec![];
+        for y in (center.1 - radius)..=(center.1 + radius) {
+            for x in (center.0 - radius)..=(center.0 + radius) {
+                let (dx, dy) = (x - center.0, y - center.1);
+                if dx * dx + dy * dy > radius * radius {
+                    continue;
+                }
+                if let Some(user_ids) = self.0.get(&(x, y)) {
+                    found.extend(user_ids.iter().map(|id| (id.clone(), (x, y))));
+                }
+            }
+        }
+        found
+    }
+
+    fn users_in_zone(&self, zone: &Zone) -> HashSet<ParticipantId> {
+        self.0
+            .iter()
+            .filter(|(tile, _)| zone.area.contains(**tile))
+            .flat_map(|(_, user_ids)| user_ids.iter().cloned())
+            .collect()
+    }
+
+    /// Every user with the tile they stand on.
+    fn users(&self) -> impl Iterator<Item = ((i32, i32), &ParticipantId)> {
+        self.0
+            .iter()
+            .flat_map(|(tile, user_ids)| user_ids.iter().map(move |id| (*tile, id)))
+    }
+}
+
+impl RoomState {
+    /// Whether a listener standing on `listener` hears a speaker standing on `speaker`,
+    /// taking the map's audio zones into account before falling back to proximity.
+    fn can_hear(
+        &self,
+        map: &ClassroomMap,
+        speaker_id: &ParticipantId,
+        speaker: (i32, i32),
+        listener: (i32, i32),
+        range: f32,
+    ) -> bool {
+        let speaker_zone = map.zone_at(speaker);
+        let listener_zone = map.zone_at(listener);
+        let speaker_kind = speaker_zone.map(|zone| zone.kind);
+        let listener_kind = listener_zone.map(|zone| zone.kind);
+
+        if speaker_kind == Some(ZoneKind::Silent) || listener_kind == Some(ZoneKind::Silent) {
+            return false;
+        }
+        // Holding the floor works like standing on the stage, wherever the speaker is
+        if self.floor.as_ref().map(|floor| &floor.holder) == Some(speaker_id) {
+            return true;
+        }
+        let speaker_can = |permission| {
+            self.roles
+                .get(speaker_id)
+                .map(|role| role.can(permission))
+                .unwrap_or(false)
+        };
+        match speaker_kind {
+            Some(ZoneKind::Stage) if speaker_can(Permission::Stage) => return true,
+            Some(ZoneKind::Podium) if speaker_can(Permission::Podium) => return true,
+            Some(ZoneKind::PrivateTable) => {
+                return listener_zone.map(|zone| &zone.id) == speaker_zone.map(|zone| &zone.id)
+            }
+            _ => {}
+        }
+        // Sitting at a private table shuts out everything but the stage and podium
+        if listener_kind == Some(ZoneKind::PrivateTable) {
+            return false;
+        }
+
+        let (dx, dy) = ((speaker.0 - listener.0) as f32, (speaker.1 - listener.1) as f32);
+        (dx * dx + dy * dy).sqrt() <= range
+    }
+
+    /// Everyone the listener should currently be receiving audio from.
+    fn audible_speakers(
+        &self,
+        map: &ClassroomMap,
+        listener_id: &ParticipantId,
+        listener: (i32, i32),
+        range: f32,
+    ) -> HashSet<ParticipantId> {
+        self.spatial_grid
+            .users()
+            .filter(|(tile, speaker_id)| {
+                *speaker_id != listener_id
+                    && self.can_hear(map, speaker_id, *tile, listener, range)
+            })
+            .map(|(_, speaker_id)| speaker_id.clone())
+            .collect()
+    }
+
+    // Producer id -> owning user id
+    fn stream_owners(&self) -> HashMap<String, ParticipantId> {
+        self.active_streams
+            .iter()
+            .map(|(producer_id, info)| (producer_id.clone(), info.user_id.clone()))
+            .collect()
+    }
 }
 
 pub struct RoomManager {
-    pub(crate) rooms: RwLock<HashMap<u32, Room>>,
+    // Only written when a room is created, everything inside a room has its own locks
+    rooms: RwLock<HashMap<u32, Arc<Room>>>,
     worker_manager: WorkerManager,
     worker_pool: Mutex<Vec<Worker>>,
     room_to_worker: Mutex<HashMap<u32, WorkerId>>,
+    // Session token -> (room id, user id, issued at), for reconnecting
+    sessions: Mutex<HashMap<String, (u32, ParticipantId, Instant)>>,
+    next_breakout_id: AtomicU32,
 }
 
 impl RoomManager {
@@ -66,12 +320,71 @@ impl RoomManager {
             worker_pool: Mutex::new(vec![]),
             worker_manager: WorkerManager::new(),
             room_to_worker: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            next_breakout_id: AtomicU32::new(BREAKOUT_ID_BASE),
         }
     }
     pub async fn initialize(&self) -> Result<(), Box<dyn Error>> {
         self.initialize_workers().await?;
         Ok(())
     }
+    /// Media workers that are still running, rooms can only be opened on these.
+    pub(crate) async fn live_workers(&self) -> usize {
+        let pool = self.worker_pool.lock().await;
+        pool.iter().filter(|worker| !worker.closed()).count()
+    }
+    pub(crate) async fn room_count(&self) -> usize {
+        self.rooms.read().await.len()
+    }
+    /// Users connected to each room, by room id.
+    pub(crate) async fn users_per_room(&self) -> BTreeMap<u32, usize> {
+        self.rooms
+            .read()
+            .await
+            .iter()
+            .map(|(room_id, room)| (*room_id, room.members().len()))
+            .collect()
+    }
+    /// Producers and consumers on each media worker, counted from the users in its rooms.
+    pub(crate) async fn media_by_worker(&self) -> BTreeMap<String, (usize, usize)> {
+        let mut by_worker: BTreeMap<String, (usize, usize)> = self
+            .worker_pool
+            .lock()
+            .await
+            .iter()
+            .map(|worker| (worker.id().to_string(), (0, 0)))
+            .collect();
+        let room_workers: Vec<(u32, WorkerId)> = self
+            .room_to_worker
+            .lock()
+            .await
+            .iter()
+            .map(|(room_id, worker_id)| (*room_id, *worker_id))
+            .collect();
+
+        for (room_id, worker_id) in room_workers {
+            let Some(users) = self.room_users(room_id).await else {
+                continue;
+            };
+            let counts = by_worker.entry(worker_id.to_string()).or_default();
+            for user in users {
+                match timeout(Duration::from_secs(5), user.lock()).await {
+                    Ok(user_lock) => {
+                        let (producers, consumers) = user_lock.media_counts();
+                        counts.0 += producers;
+                        counts.1 += consumers;
+                    }
+                    Err(_) => {
+                        eprintln!(
+                            "Failed to acquire lock for a user in room {} within timeout",
+                            room_id
+                        );
+                    }
+                }
+            }
+        }
+        by_worker
+    }
     pub fn instance() -> Arc<RoomManager> {
         ROOM_MANAGER.clone()
     }
@@ -93,10 +406,36 @@ impl RoomManager {
 
     pub async fn add_room_from_contract(
         &self,
-        teacher: String,
         course_id: u32,
         course_name: String,
+        course: CourseParams,
     ) -> Result<u32, Box<dyn Error>> {
+        let state = RoomState {
+            whiteboard: whiteboard::load(course_id),
+            ..RoomState::default()
+        };
+        self.open_room(
+            course_id,
+            course,
+            course_name,
+            ClassroomMap::load_for_course(course_id),
+            None,
+            state,
+        )
+        .await?;
+        Ok(course_id)
+    }
+
+    // Gives the room its own router and tick loop, and makes it joinable
+    async fn open_room(
+        &self,
+        room_id: u32,
+        course: CourseParams,
+        name: String,
+        map: ClassroomMap,
+        parent: Option<u32>,
+        state: RoomState,
+    ) -> Result<(), Box<dyn Error>> {
         // Acquire a worker from the pool with the least load.
         let worker = self.get_least_loaded_worker().await?;
         let router = worker
@@ -121,85 +460,1164 @@ impl RoomManager {
 
         // Create a new Room instance.
         let room = Room {
-            teacher: teacher.clone(),
-            name: course_name.clone(),
-            users: RwLock::new(vec![]),
+            course,
+            name,
+            parent,
+            members: ArcSwap::from_pointee(HashMap::new()),
             router,
-            active_streams: Default::default(),
-            spatial_grid: HashMap::new(),
+            map,
+            views_dirty: AtomicBool::new(false),
+            state: RwLock::new(state),
         };
 
         // Lock and modify the rooms map.
         let mut rooms = self.rooms.write().await;
-        rooms.insert(course_id, room);
+        rooms.insert(room_id, Arc::new(room));
+        drop(rooms);
 
         // Track room-worker association.
         let mut room_to_worker = self.room_to_worker.lock().await;
-        room_to_worker.insert(course_id, worker.id());
+        room_to_worker.insert(room_id, worker.id());
 
-        Ok(course_id)
+        // Movement is applied by the room's own simulation tick
+        tokio::spawn(room_tick::run(room_id));
+
+        Ok(())
     }
 
-    pub(crate) async fn add_user_to_room(&self, room_id: u32, user: Arc<Mutex<User>>) {
-        let mut rooms = self.rooms.write().await; // Use write lock for rooms
-        if let Some(room) = rooms.get_mut(&room_id) {
-            let mut users = room.users.write().await; // Use write lock for users
-            users.push(user);
+    /// Opens `count` breakout rooms under a classroom. They share its map, course,
+    /// enrollments, delegations and ban list, but have their own router and chat.
+    pub(crate) async fn create_breakout_rooms(
+        &self,
+        parent_id: u32,
+        count: usize,
+    ) -> Result<Vec<u32>, Box<dyn Error + Send + Sync>> {
+        let parent = self.room(parent_id).await.ok_or("Room not found")?;
+        let (banned, delegations, enrolled) = {
+            let state = parent.state.read().await;
+            (
+                state.banned.clone(),
+                state.delegations.clone(),
+                state.enrolled.clone(),
+            )
+        };
+
+        let mut room_ids = vec![];
+        for index in 0..count {
+            let room_id = self.next_breakout_id.fetch_add(1, Ordering::Relaxed);
+            let state = RoomState {
+                banned: banned.clone(),
+                delegations: delegations.clone(),
+                enrolled: enrolled.clone(),
+                ..RoomState::default()
+            };
+            let opened = self
+                .open_room(
+                    room_id,
+                    parent.course.clone(),
+                    format!("{} - Breakout {}", parent.name, index + 1),
+                    parent.map.clone(),
+                    Some(parent_id),
+                    state,
+                )
+                .await
+                // Not `Send`, so it can't be held across the awaits below
+                .map_err(|e| e.to_string());
+            if let Err(e) = opened {
+                for room_id in room_ids {
+                    self.close_room(room_id).await;
+                }
+                return Err(e.into());
+            }
+            room_ids.push(room_id);
         }
+        Ok(room_ids)
     }
 
-    pub(crate) async fn remove_user_from_room(&self, room_id: u32, user_id: String) {
-        let mut rooms = self.rooms.write().await;
-        if let Some(room) = rooms.get_mut(&room_id) {
-            let mut users = room.users.write().await;
-            users.retain(|user| {
-                let user_lock = futures::executor::block_on(user.lock());
-                user_lock.id.as_ref() != Some(&user_id)
-            });
+    /// Takes a room away, its tick loop stops and the router closes once nothing holds it.
+    pub(crate) async fn close_room(&self, room_id: u32) {
+        self.rooms.write().await.remove(&room_id);
+        self.room_to_worker.lock().await.remove(&room_id);
+    }
+
+    /// The classroom a room belongs to: its parent for breakout rooms, itself otherwise.
+    pub(crate) async fn course_of(&self, room_id: u32) -> u32 {
+        self.room(room_id)
+            .await
+            .and_then(|room| room.parent)
+            .unwrap_or(room_id)
+    }
+
+    pub(crate) async fn room(&self, room_id: u32) -> Option<Arc<Room>> {
+        self.rooms.read().await.get(&room_id).cloned()
+    }
+
+    pub(crate) async fn room_exists(&self, room_id: u32) -> bool {
+        self.rooms.read().await.contains_key(&room_id)
+    }
+
+    /// Returns false if the room is gone or the id is already taken.
+    pub(crate) async fn add_user_to_room(
+        &self,
+        room_id: u32,
+        user_id: &ParticipantId,
+        user: UserHandle,
+    ) -> bool {
+        match self.room(room_id).await {
+            Some(room) => room.add_member(user_id, &user),
+            None => false,
+        }
+    }
+
+    pub(crate) async fn remove_user_from_room(
+        &self,
+        room_id: u32,
+        user_id: ParticipantId,
+        coordinates: (i32, i32),
+    ) {
+        let Some(room) = self.room(room_id).await else {
+            return;
+        };
+        room.remove_member(&user_id);
+        let mut state = room.state.write().await;
+        state.spatial_grid.remove(&user_id, coordinates);
+        state.roles.remove(&user_id);
+        state.active_streams.retain(|_, info| info.user_id != user_id);
+        room.views_dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Picks a free spawn tile in the room and reserves it in the spatial grid.
+    pub(crate) async fn spawn_user(
+        &self,
+        room_id: u32,
+        user_id: &ParticipantId,
+    ) -> Option<(i32, i32)> {
+        let room = self.room(room_id).await?;
+        let mut state = room.state.write().await;
+        let tile = room.map.find_spawn(&state.spatial_grid.occupied_tiles())?;
+        state.spatial_grid.add(user_id, tile);
+        room.views_dirty.store(true, Ordering::Relaxed);
+        Some(tile)
+    }
+
+    pub(crate) async fn users_in_view(
+        &self,
+        room_id: u32,
+        center: (i32, i32),
+        radius: i32,
+    ) -> HashMap<ParticipantId, (i32, i32)> {
+        match self.room(room_id).await {
+            Some(room) => {
+                let state = room.state.read().await;
+                state.spatial_grid.users_in_view(center, radius).into_iter().collect()
+            }
+            None => HashMap::new(),
+        }
+    }
+
+    /// Returns whether membership changed since the last call, and resets the flag.
+    pub(crate) async fn take_views_dirty(&self, room_id: u32) -> bool {
+        self.room(room_id)
+            .await
+            .map(|room| room.views_dirty.swap(false, Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    pub(crate) async fn mark_views_dirty(&self, room_id: u32) {
+        if let Some(room) = self.room(room_id).await {
+            room.views_dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) async fn issue_session(&self, room_id: u32, user_id: &ParticipantId) -> String {
+        let token = session::new_token();
+        let mut sessions = self.sessions.lock().await;
+        // Tokens of users that never came back would pile up otherwise
+        sessions.retain(|_, (_, _, issued_at)| issued_at.elapsed() < *session::SESSION_TOKEN_TTL);
+        sessions.insert(token.clone(), (room_id, user_id.clone(), Instant::now()));
+        token
+    }
+
+    /// The room and user a token resumes, unless it's unknown or too old.
+    pub(crate) async fn session(&self, token: &str) -> Option<(u32, ParticipantId)> {
+        let mut sessions = self.sessions.lock().await;
+        let (room_id, user_id, issued_at) = sessions.get(token).cloned()?;
+        if issued_at.elapsed() >= *session::SESSION_TOKEN_TTL {
+            sessions.remove(token);
+            return None;
+        }
+        Some((room_id, user_id))
+    }
+
+    pub(crate) async fn revoke_session(&self, token: &str) {
+        self.sessions.lock().await.remove(token);
+    }
+
+    /// Points a session at the room its user was moved to.
+    pub(crate) async fn rebind_session(&self, token: &str, room_id: u32) {
+        if let Some(session) = self.sessions.lock().await.get_mut(token) {
+            session.0 = room_id;
+        }
+    }
+
+    /// Ban list and room lock are enforced here before anyone is let in.
+    pub(crate) async fn check_admission(
+        &self,
+        room_id: u32,
+        user_id: &AccountIdentity,
+    ) -> Result<(), &'static str> {
+        let room = self.room(room_id).await.ok_or("room_not_found")?;
+        // Breakouts are only reached through the classroom
+        if room.parent.is_some() {
+            return Err("breakout_room");
+        }
+        let state = room.state.read().await;
+        if state.banned.contains(user_id) {
+            return Err("banned");
+        }
+        if state.locked {
+            return Err("room_locked");
+        }
+        Ok(())
+    }
+
+    /// Works out what an authenticated account is in this room: the on-chain teacher,
+    /// a delegated role, or an enrolled student. `None` means it has no business here.
+    pub(crate) async fn resolve_role(
+        &self,
+        room_id: u32,
+        user_id: &AccountIdentity,
+    ) -> Option<Role> {
+        let room = self.room(room_id).await?;
+        if &room.course.teacher == user_id {
+            return Some(Role::Teacher);
+        }
+        let state = room.state.read().await;
+        if let Some(role) = state.delegations.get(user_id) {
+            return Some(*role);
+        }
+        if state.enrolled.contains(user_id) {
+            return Some(Role::Student);
         }
+        None
     }
 
+    pub(crate) async fn set_user_role(&self, room_id: u32, user_id: &ParticipantId, role: Role) {
+        if let Some(room) = self.room(room_id).await {
+            room.state.write().await.roles.insert(user_id.clone(), role);
+        }
+    }
+
+    /// Takes a seat for a user entering the classroom and records their role, unless
+    /// every seat for that role is taken. Another device of an account that's already
+    /// in always fits.
+    pub(crate) async fn claim_seat(
+        &self,
+        room_id: u32,
+        user_id: &ParticipantId,
+        role: Role,
+    ) -> Result<(), &'static str> {
+        let room = self.room(room_id).await.ok_or("room_not_found")?;
+        // Counted and taken under the state lock, so two joins can't both get the last seat
+        let mut state = room.state.write().await;
+        let seated = self.seated(&state).await;
+        if !seated.contains_key(user_id.account()) {
+            Capacity::for_course(&room.course).admits(&Occupancy::count(&seated), role)?;
+        }
+        state.roles.insert(user_id.clone(), role);
+        Ok(())
+    }
 
+    // Role of every account in the classroom, students away in breakouts included
+    async fn seated(&self, state: &RoomState) -> HashMap<AccountIdentity, Role> {
+        let mut seated: HashMap<AccountIdentity, Role> = state
+            .roles
+            .iter()
+            .map(|(user_id, role)| (user_id.account().clone(), *role))
+            .collect();
+        let breakout_ids = state
+            .breakouts
+            .iter()
+            .flat_map(|session| session.rooms.iter());
+        for breakout_id in breakout_ids {
+            let Some(breakout) = self.room(*breakout_id).await else {
+                continue;
+            };
+            let breakout_state = breakout.state.read().await;
+            for (user_id, role) in breakout_state.roles.iter() {
+                seated.insert(user_id.account().clone(), *role);
+            }
+        }
+        seated
+    }
+
+    /// Every classroom with its live occupancy, breakout rooms are counted in theirs.
+    pub(crate) async fn room_listing(&self) -> Vec<RoomSummary> {
+        let rooms: Vec<(u32, Arc<Room>)> = self
+            .rooms
+            .read()
+            .await
+            .iter()
+            .filter(|(_, room)| room.parent.is_none())
+            .map(|(room_id, room)| (*room_id, room.clone()))
+            .collect();
+
+        let mut listing = vec![];
+        for (room_id, room) in rooms {
+            listing.push(self.summarize(room_id, &room).await);
+        }
+        listing.sort_by_key(|summary| summary.course_id);
+        listing
+    }
+
+    /// One classroom's entry in the listing. `None` for breakout rooms.
+    pub(crate) async fn room_summary(&self, room_id: u32) -> Option<RoomSummary> {
+        let room = self.room(room_id).await?;
+        if room.parent.is_some() {
+            return None;
+        }
+        Some(self.summarize(room_id, &room).await)
+    }
+
+    async fn summarize(&self, room_id: u32, room: &Room) -> RoomSummary {
+        let state = room.state.read().await;
+        let occupancy = Occupancy::count(&self.seated(&state).await);
+        RoomSummary {
+            course_id: room_id,
+            name: room.name.clone(),
+            teacher: room.course.teacher.clone(),
+            start_time: room.course.start_time,
+            end_time: room.course.end_time,
+            metadata_hash: format!("0x{}", hex::encode(&room.course.metadata_hash)),
+            state: RoomPhase::of(&room.course, &occupancy),
+            occupancy,
+            capacity: Capacity::for_course(&room.course),
+            locked: state.locked,
+            waiting_room: state.waiting_room,
+            breakouts: state.breakouts.is_some(),
+        }
+    }
+
+    pub(crate) async fn user_role(&self, room_id: u32, user_id: &ParticipantId) -> Option<Role> {
+        let room = self.room(room_id).await?;
+        let role = room.state.read().await.roles.get(user_id).copied();
+        role
+    }
+
+    /// Adds or, with `None`, removes an entry in the room's delegation list.
+    pub(crate) async fn set_delegation(
+        &self,
+        room_id: u32,
+        user_id: &AccountIdentity,
+        role: Option<Role>,
+    ) {
+        if let Some(room) = self.room(room_id).await {
+            let mut state = room.state.write().await;
+            match role {
+                Some(role) => state.delegations.insert(user_id.clone(), role),
+                None => state.delegations.remove(user_id),
+            };
+        }
+    }
+
+    pub(crate) async fn record_enrollment(&self, course_id: u32, student: AccountIdentity) {
+        match self.room(course_id).await {
+            Some(room) => {
+                room.state.write().await.enrolled.insert(student);
+            }
+            None => println!("Enrollment for unknown room {}", course_id),
+        }
+    }
+
+    pub(crate) async fn set_recording(&self, room_id: u32, recording: bool) {
+        if let Some(room) = self.room(room_id).await {
+            room.state.write().await.recording = recording;
+        }
+    }
+
+    /// Bans the account from the whole classroom, breakout rooms included.
+    pub(crate) async fn ban_user(&self, room_id: u32, user_id: &AccountIdentity) {
+        let course_id = self.course_of(room_id).await;
+        let mut room_ids = vec![course_id];
+        if let Some(session) = self.breakouts(course_id).await {
+            room_ids.extend(session.rooms);
+        }
+        for room_id in room_ids {
+            if let Some(room) = self.room(room_id).await {
+                room.state.write().await.banned.insert(user_id.clone());
+            }
+        }
+    }
+
+    pub(crate) async fn set_chat_muted(&self, room_id: u32, user_id: &AccountIdentity, muted: bool) {
+        if let Some(room) = self.room(room_id).await {
+            let mut state = room.state.write().await;
+            if muted {
+                state.chat_muted.insert(user_id.clone());
+            } else {
+                state.chat_muted.remove(user_id);
+            }
+        }
+    }
+
+    pub(crate) async fn is_chat_muted(&self, room_id: u32, user_id: &AccountIdentity) -> bool {
+        match self.room(room_id).await {
+            Some(room) => room.state.read().await.chat_muted.contains(user_id),
+            None => false,
+        }
+    }
+
+    /// Puts the user's hand up and returns their place in the queue, starting at 1.
+    pub(crate) async fn raise_hand(
+        &self,
+        room_id: u32,
+        user_id: &ParticipantId,
+    ) -> Result<usize, &'static str> {
+        let room = self.room(room_id).await.ok_or("room_not_found")?;
+        let mut state = room.state.write().await;
+        if state.floor.as_ref().map(|floor| &floor.holder) == Some(user_id) {
+            return Err("has_floor");
+        }
+        if state.hand_queue.contains(user_id) {
+            return Err("already_raised");
+        }
+        state.hand_queue.push_back(user_id.clone());
+        Ok(state.hand_queue.len())
+    }
+
+    /// Returns whether the user's hand was up.
+    pub(crate) async fn lower_hand(&self, room_id: u32, user_id: &ParticipantId) -> bool {
+        let Some(room) = self.room(room_id).await else {
+            return false;
+        };
+        let mut state = room.state.write().await;
+        let before = state.hand_queue.len();
+        state.hand_queue.retain(|id| id != user_id);
+        state.hand_queue.len() != before
+    }
+
+    pub(crate) async fn hand_queue(&self, room_id: u32) -> Vec<ParticipantId> {
+        match self.room(room_id).await {
+            Some(room) => room.state.read().await.hand_queue.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    /// Gives the floor to `target`, or to the first raised hand without one, taking them
+    /// out of the queue. Returns the new grant and whoever held the floor before.
+    pub(crate) async fn grant_floor(
+        &self,
+        room_id: u32,
+        target: Option<ParticipantId>,
+    ) -> Result<(Floor, Option<ParticipantId>), &'static str> {
+        let room = self.room(room_id).await.ok_or("room_not_found")?;
+        let mut state = room.state.write().await;
+        let holder = match target {
+            Some(target) => {
+                if !room.members().contains_key(&target) {
+                    return Err("target_not_found");
+                }
+                state.hand_queue.retain(|id| id != &target);
+                target
+            }
+            None => state.hand_queue.pop_front().ok_or("queue_empty")?,
+        };
+        state.floor_grants += 1;
+        let floor = Floor {
+            holder,
+            grant: state.floor_grants,
+        };
+        let previous = state.floor.replace(floor.clone()).map(|floor| floor.holder);
+        Ok((floor, previous))
+    }
+
+    /// Ends the current grant, or with `grant` set only that one. Returns who held it.
+    pub(crate) async fn end_floor(
+        &self,
+        room_id: u32,
+        grant: Option<u64>,
+    ) -> Option<ParticipantId> {
+        let room = self.room(room_id).await?;
+        let mut state = room.state.write().await;
+        let current = state.floor.as_ref()?.grant;
+        if grant.is_some_and(|grant| grant != current) {
+            return None;
+        }
+        state.floor.take().map(|floor| floor.holder)
+    }
+
+    pub(crate) async fn floor_holder(&self, room_id: u32) -> Option<ParticipantId> {
+        let room = self.room(room_id).await?;
+        let state = room.state.read().await;
+        state.floor.as_ref().map(|floor| floor.holder.clone())
+    }
+
+    pub(crate) async fn record_attendance(
+        &self,
+        room_id: u32,
+        user_id: &ParticipantId,
+        role: Option<Role>,
+        kind: AttendanceEventKind,
+    ) {
+        if let Some(room) = self.room(room_id).await {
+            let mut state = room.state.write().await;
+            state
+                .attendance
+                .get_or_insert_with(|| AttendanceSession::new(room_id))
+                .record_event(user_id, role, kind);
+        }
+    }
+
+    pub(crate) async fn add_attendance_sample(
+        &self,
+        room_id: u32,
+        sample: &AttendanceSample,
+        seconds: u64,
+    ) {
+        if let Some(room) = self.room(room_id).await {
+            if let Some(session) = room.state.write().await.attendance.as_mut() {
+                session.add_sample(sample, seconds);
+            }
+        }
+    }
+
+    pub(crate) async fn attendance_session(&self, room_id: u32) -> Option<AttendanceSession> {
+        let room = self.room(room_id).await?;
+        let session = room.state.read().await.attendance.clone();
+        session
+    }
+
+    /// Closes the attendance session and hands it back, if nobody is left in the room.
+    pub(crate) async fn end_attendance_if_empty(
+        &self,
+        room_id: u32,
+    ) -> Option<AttendanceSession> {
+        let room = self.room(room_id).await?;
+        let mut state = room.state.write().await;
+        // Checked under the state lock, so a join can't slip in between.
+        // Students away in breakouts are still in class.
+        if !room.members().is_empty() || state.breakouts.is_some() {
+            return None;
+        }
+        let mut session = state.attendance.take()?;
+        session.end();
+        Some(session)
+    }
+
+    /// Applies a whiteboard op from `author`, returning its sequence number.
+    pub(crate) async fn apply_whiteboard_op(
+        &self,
+        room_id: u32,
+        author: &ParticipantId,
+        role: Role,
+        op: &WhiteboardOp,
+    ) -> Result<u64, &'static str> {
+        let room = self.room(room_id).await.ok_or("room_not_found")?;
+        let mut state = room.state.write().await;
+        state.whiteboard.apply(author, role, op)
+    }
+
+    pub(crate) async fn set_whiteboard_mode(&self, room_id: u32, mode: DrawMode) {
+        if let Some(room) = self.room(room_id).await {
+            room.state.write().await.whiteboard.set_mode(mode);
+        }
+    }
+
+    pub(crate) async fn whiteboard_json(&self, room_id: u32) -> Option<serde_json::Value> {
+        let room = self.room(room_id).await?;
+        let board = room.state.read().await.whiteboard.to_json();
+        Some(board)
+    }
+
+    pub(crate) async fn take_whiteboard_snapshot(&self, room_id: u32) -> Option<Whiteboard> {
+        let room = self.room(room_id).await?;
+        let snapshot = room.state.write().await.whiteboard.take_snapshot();
+        snapshot
+    }
+
+    pub(crate) async fn start_presentation(&self, room_id: u32, presentation: Presentation) {
+        if let Some(room) = self.room(room_id).await {
+            room.state.write().await.presentation = Some(presentation);
+        }
+    }
+
+    /// Turns to `slide`. Someone else turning the slides takes over as presenter.
+    pub(crate) async fn set_slide(
+        &self,
+        room_id: u32,
+        presenter: &ParticipantId,
+        slide: u32,
+    ) -> Result<(), &'static str> {
+        let room = self.room(room_id).await.ok_or("room_not_found")?;
+        let mut state = room.state.write().await;
+        let presentation = state.presentation.as_mut().ok_or("no_presentation")?;
+        presentation.set_slide(slide)?;
+        presentation.presenter = presenter.clone();
+        Ok(())
+    }
+
+    pub(crate) async fn end_presentation(&self, room_id: u32) -> bool {
+        let Some(room) = self.room(room_id).await else {
+            return false;
+        };
+        let ended = room.state.write().await.presentation.take().is_some();
+        ended
+    }
+
+    pub(crate) async fn presentation(&self, room_id: u32) -> Option<Presentation> {
+        let room = self.room(room_id).await?;
+        let presentation = room.state.read().await.presentation.clone();
+        presentation
+    }
+
+    /// The current slide, if a laser pointer update may go out now.
+    pub(crate) async fn take_laser_slot(&self, room_id: u32, hiding: bool) -> Option<u32> {
+        let room = self.room(room_id).await?;
+        let mut state = room.state.write().await;
+        let presentation = state.presentation.as_mut()?;
+        presentation
+            .take_laser_slot(hiding)
+            .then_some(presentation.slide)
+    }
+
+    pub(crate) async fn breakouts(&self, room_id: u32) -> Option<BreakoutSession> {
+        let room = self.room(room_id).await?;
+        let breakouts = room.state.read().await.breakouts.clone();
+        breakouts
+    }
+
+    /// Records the split, unless the room is already split. Returns the session
+    /// with its generation filled in.
+    pub(crate) async fn start_breakouts(
+        &self,
+        room_id: u32,
+        mut session: BreakoutSession,
+    ) -> Option<BreakoutSession> {
+        let room = self.room(room_id).await?;
+        let mut state = room.state.write().await;
+        if state.breakouts.is_some() {
+            return None;
+        }
+        state.breakout_generations += 1;
+        session.generation = state.breakout_generations;
+        state.breakouts = Some(session.clone());
+        Some(session)
+    }
+
+    /// Ends the current breakouts, or with `generation` set only those.
+    pub(crate) async fn end_breakouts(
+        &self,
+        room_id: u32,
+        generation: Option<u64>,
+    ) -> Option<BreakoutSession> {
+        let room = self.room(room_id).await?;
+        let mut state = room.state.write().await;
+        let current = state.breakouts.as_ref()?.generation;
+        if generation.is_some_and(|generation| generation != current) {
+            return None;
+        }
+        state.breakouts.take()
+    }
+
+    pub(crate) async fn assign_breakout(
+        &self,
+        room_id: u32,
+        user_id: ParticipantId,
+        breakout_id: u32,
+    ) {
+        if let Some(room) = self.room(room_id).await {
+            if let Some(session) = room.state.write().await.breakouts.as_mut() {
+                session.assignments.insert(user_id, breakout_id);
+            }
+        }
+    }
+
+    /// Producer ids of screen shares, which don't count as time on camera.
+    pub(crate) async fn screen_producers(&self, room_id: u32) -> HashSet<String> {
+        let Some(room) = self.room(room_id).await else {
+            return HashSet::new();
+        };
+        let state = room.state.read().await;
+        state
+            .active_streams
+            .iter()
+            .filter(|(_, info)| info.is_screen())
+            .map(|(producer_id, _)| producer_id.clone())
+            .collect()
+    }
+
+    /// Users in the room whose role has the given permission.
+    pub(crate) async fn users_with_permission(
+        &self,
+        room_id: u32,
+        permission: Permission,
+    ) -> HashSet<ParticipantId> {
+        let Some(room) = self.room(room_id).await else {
+            return HashSet::new();
+        };
+        let state = room.state.read().await;
+        state
+            .roles
+            .iter()
+            .filter(|(_, role)| role.can(permission))
+            .map(|(user_id, _)| user_id.clone())
+            .collect()
+    }
+
+    pub(crate) async fn waiting_room_enabled(&self, room_id: u32) -> bool {
+        match self.room(room_id).await {
+            Some(room) => room.state.read().await.waiting_room,
+            None => false,
+        }
+    }
+
+    pub(crate) async fn set_waiting_room(&self, room_id: u32, enabled: bool) {
+        if let Some(room) = self.room(room_id).await {
+            room.state.write().await.waiting_room = enabled;
+        }
+    }
+
+    /// Returns false if the room is gone or the user is already waiting.
+    pub(crate) async fn add_to_lobby(&self, room_id: u32, entry: LobbyEntry) -> bool {
+        let Some(room) = self.room(room_id).await else {
+            return false;
+        };
+        let mut state = room.state.write().await;
+        if state
+            .lobby
+            .iter()
+            .any(|waiting| waiting.user_id == entry.user_id)
+        {
+            return false;
+        }
+        state.lobby.push(entry);
+        true
+    }
+
+    /// Takes `target` out of the lobby, or everyone when it's `None`.
+    pub(crate) async fn take_from_lobby(
+        &self,
+        room_id: u32,
+        target: Option<&ParticipantId>,
+    ) -> Vec<LobbyEntry> {
+        let Some(room) = self.room(room_id).await else {
+            return vec![];
+        };
+        let mut state = room.state.write().await;
+        match target {
+            Some(target) => {
+                let (taken, waiting) = state
+                    .lobby
+                    .drain(..)
+                    .partition(|entry| &entry.user_id == target);
+                state.lobby = waiting;
+                taken
+            }
+            None => std::mem::take(&mut state.lobby),
+        }
+    }
+
+    pub(crate) async fn lobby(&self, room_id: u32) -> Vec<LobbyEntry> {
+        match self.room(room_id).await {
+            Some(room) => room.state.read().await.lobby.clone(),
+            None => vec![],
+        }
+    }
+
+    pub(crate) async fn set_locked(&self, room_id: u32, locked: bool) {
+        if let Some(room) = self.room(room_id).await {
+            room.state.write().await.locked = locked;
+        }
+    }
+
+    pub(crate) async fn find_user(
+        &self,
+        room_id: u32,
+        user_id: &ParticipantId,
+    ) -> Option<UserHandle> {
+        self.room(room_id).await?.members().get(user_id).cloned()
+    }
+
+    /// Like `find_user`, but also looks in the classroom's breakout rooms.
+    pub(crate) async fn find_in_course(
+        &self,
+        course_id: u32,
+        user_id: &ParticipantId,
+    ) -> Option<UserHandle> {
+        if let Some(user) = self.find_user(course_id, user_id).await {
+            return Some(user);
+        }
+        for room_id in self.breakouts(course_id).await?.rooms {
+            if let Some(user) = self.find_user(room_id, user_id).await {
+                return Some(user);
+            }
+        }
+        None
+    }
+
+    /// Every device the account is signed in from.
+    pub(crate) async fn find_account_users(
+        &self,
+        room_id: u32,
+        account: &AccountIdentity,
+    ) -> Vec<UserHandle> {
+        let Some(room) = self.room(room_id).await else {
+            return vec![];
+        };
+        let members = room.members();
+        members
+            .iter()
+            .filter(|(user_id, _)| user_id.account() == account)
+            .map(|(_, user)| user.clone())
+            .collect()
+    }
+
+    /// Like `find_account_users`, but across the classroom and its breakout rooms.
+    pub(crate) async fn find_account_in_course(
+        &self,
+        room_id: u32,
+        account: &AccountIdentity,
+    ) -> Vec<UserHandle> {
+        let course_id = self.course_of(room_id).await;
+        let mut users = self.find_account_users(course_id, account).await;
+        if let Some(session) = self.breakouts(course_id).await {
+            for room_id in session.rooms {
+                users.extend(self.find_account_users(room_id, account).await);
+            }
+        }
+        users
+    }
+
+    pub(crate) async fn room_users(&self, room_id: u32) -> Option<Vec<UserHandle>> {
+        let room = self.room(room_id).await?;
+        let users = room.members().values().cloned().collect();
+        Some(users)
+    }
+
+    pub(crate) async fn zone_at(&self, room_id: u32, tile: (i32, i32)) -> Option<Zone> {
+        self.room(room_id).await?.map.zone_at(tile).cloned()
+    }
+
+    /// Recomputes who hears whom after someone moved and pauses or resumes
+    /// audio consumers to match.
+    pub(crate) async fn refresh_audio_routing(&self, room_id: u32) {
+        let Some(room) = self.room(room_id).await else {
+            return;
+        };
+
+        for (user_id, user) in room.members().iter() {
+            match timeout(Duration::from_secs(5), user.lock()).await {
+                Ok(mut user_lock) => {
+                    let (audible, stream_owners) = {
+                        let state = room.state.read().await;
+                        (
+                            state.audible_speakers(
+                                &room.map,
+                                user_id,
+                                user_lock.coordinates,
+                                user_lock.audio_range,
+                            ),
+                            state.stream_owners(),
+                        )
+                    };
+                    user_lock
+                        .update_audio_routing(audible, &stream_owners)
+                        .await;
+                }
+                Err(_) => {
+                    eprintln!(
+                        "Failed to acquire lock for a user in room {} within timeout",
+                        room_id
+                    );
+                }
+            }
+        }
+    }
+
+    /// Opens a WebRTC transport on the room's router, a user sends and receives over the same one.
+    pub(crate) async fn create_transport(
+        &self,
+        room_id: u32,
+    ) -> Result<WebRtcTransport, &'static str> {
+        let room = self.room(room_id).await.ok_or("room_not_found")?;
+        let udp = ListenInfo {
+            protocol: Protocol::Udp,
+            ip: *MEDIA_LISTEN_IP,
+            announced_address: MEDIA_ANNOUNCED_ADDRESS.clone(),
+            port: None,
+            port_range: None,
+            flags: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        };
+        // TCP as a fallback for clients behind firewalls that drop UDP
+        let tcp = ListenInfo {
+            protocol: Protocol::Tcp,
+            ..udp.clone()
+        };
+        let options = WebRtcTransportOptions::new(WebRtcTransportListenInfos::new(udp).insert(tcp));
+        room.router
+            .create_webrtc_transport(options)
+            .await
+            .map_err(|e| {
+                eprintln!("Failed to create a transport in room {}: {}", room_id, e);
+                "media_unavailable"
+            })
+    }
+
+    /// Codecs the room's router handles, clients load them before producing or consuming.
+    pub(crate) async fn rtp_capabilities(&self, room_id: u32) -> Option<RtpCapabilitiesFinalized> {
+        Some(self.room(room_id).await?.router.rtp_capabilities().clone())
+    }
+
+    pub(crate) async fn can_consume(
+        &self,
+        room_id: u32,
+        producer_id: &ProducerId,
+        rtp_capabilities: &RtpCapabilities,
+    ) -> bool {
+        match self.room(room_id).await {
+            Some(room) => room.router.can_consume(producer_id, rtp_capabilities),
+            None => false,
+        }
+    }
+
+    /// Makes a new producer known to the room, so others can consume it.
+    pub(crate) async fn add_stream(&self, room_id: u32, producer_id: String, info: StreamInfo) {
+        if let Some(room) = self.room(room_id).await {
+            room.state
+                .write()
+                .await
+                .active_streams
+                .insert(producer_id, info);
+        }
+    }
+
+    /// Every producer in the room, by id.
+    pub(crate) async fn streams(&self, room_id: u32) -> HashMap<String, StreamInfo> {
+        match self.room(room_id).await {
+            Some(room) => room.state.read().await.active_streams.clone(),
+            None => HashMap::new(),
+        }
+    }
+
+    pub(crate) async fn stream_owner(&self, room_id: u32, producer_id: &str) -> Option<ParticipantId> {
+        let room = self.room(room_id).await?;
+        let state = room.state.read().await;
+        state
+            .active_streams
+            .get(producer_id)
+            .map(|info| info.user_id.clone())
+    }
+
+    /// Forgets the given producers and closes every consumer in the room they fed.
+    pub(crate) async fn close_consumers_of(&self, room_id: u32, producer_ids: &HashSet<String>) {
+        if producer_ids.is_empty() {
+            return;
+        }
+        let Some(room) = self.room(room_id).await else {
+            return;
+        };
+        room.state
+            .write()
+            .await
+            .active_streams
+            .retain(|producer_id, _| !producer_ids.contains(producer_id));
+        let users: Vec<UserHandle> = room.members().values().cloned().collect();
+
+        for user in users {
+            match timeout(Duration::from_secs(5), user.lock()).await {
+                Ok(mut user_lock) => user_lock.close_consumers_of(producer_ids),
+                Err(_) => {
+                    eprintln!(
+                        "Failed to acquire lock for a user in room {} within timeout",
+                        room_id
+                    );
+                }
+            }
+        }
+    }
+
+    /// Delivery rules for chat: works out who a message from `sender_id`, standing on
+    /// `sender_tile` with the given view radius, reaches in the given scope.
+    /// Proximity chat goes to the same area of interest position updates do.
+    pub(crate) async fn chat_delivery(
+        &self,
+        room_id: u32,
+        sender_id: &ParticipantId,
+        sender_tile: (i32, i32),
+        view_radius: i32,
+        scope: ChatScope,
+        target: Option<ParticipantId>,
+    ) -> Result<Delivery, &'static str> {
+        let room = self.room(room_id).await.ok_or("room_not_found")?;
+        let mut delivery = Delivery {
+            target: None,
+            zone_id: None,
+            recipients: None,
+        };
+
+        match scope {
+            ChatScope::Direct => {
+                let target = target.ok_or("missing_target")?;
+                if &target == sender_id || !room.members().contains_key(&target) {
+                    return Err("target_not_found");
+                }
+                delivery.recipients = Some(HashSet::from([target.clone()]));
+                delivery.target = Some(target);
+            }
+            ChatScope::Proximity => {
+                let state = room.state.read().await;
+                let mut recipients: HashSet<ParticipantId> = state
+                    .spatial_grid
+                    .users_in_view(sender_tile, view_radius)
+                    .into_iter()
+                    .map(|(user_id, _)| user_id)
+                    .collect();
+                recipients.remove(sender_id);
+                delivery.recipients = Some(recipients);
+            }
+            ChatScope::Zone => {
+                let zone = room.map.zone_at(sender_tile).ok_or("not_in_zone")?;
+                let state = room.state.read().await;
+                let mut recipients = state.spatial_grid.users_in_zone(zone);
+                recipients.remove(sender_id);
+                delivery.recipients = Some(recipients);
+                delivery.zone_id = Some(zone.id.clone());
+            }
+            // Everyone, `recipients` stays `None`
+            ChatScope::Announcement => {}
+        }
+        Ok(delivery)
+    }
+
+    /// Validates a one-tile step against the room map and updates the spatial grid.
+    pub(crate) async fn move_user(
+        &self,
+        room_id: u32,
+        user_id: &ParticipantId,
+        from: (i32, i32),
+        to: (i32, i32),
+    ) -> bool {
+        let Some(room) = self.room(room_id).await else {
+            return false;
+        };
+        if !room.map.is_valid_step(from, to) {
+            return false;
+        }
+        let mut state = room.state.write().await;
+        state.spatial_grid.remove(user_id, from);
+        state.spatial_grid.add(user_id, to);
+        true
+    }
 }
 
 impl RoomManager {
     pub(crate) async fn broadcast_message(
         &self,
-        sender_id: Option<String>,
+        sender_id: Option<ParticipantId>,
         room_id: u32,
         message: String,
     ) {
-        let users = {
-            let rooms = self.rooms.read().await;
-            if let Some(room) = rooms.get(&room_id) {
-                room.users.read().await.clone()
-            } else {
-                println!("Room {} not found", room_id);
-                return;
-            }
+        let started = Instant::now();
+        let Some(room) = self.room(room_id).await else {
+            println!("Room {} not found", room_id);
+            return;
         };
 
-        for user in users {
+        for (user_id, user) in room.members().iter() {
+            if sender_id.as_ref() == Some(user_id) {
+                continue;
+            }
             match timeout(Duration::from_secs(5), user.lock()).await {
-                Ok(mut user_lock) => {
-                    let should_send = user_lock.id.as_ref()
-                        .map(|user_id| sender_id.as_ref() != Some(user_id))
-                        .unwrap_or(false);
-
-                    if should_send {
-                        if let Err(e) = user_lock.websocket.send(Message::Text(message.clone())).await {
-                            if let Some(user_id) = &user_lock.id {
-                                eprintln!("Failed to broadcast message to user {}: {}", user_id, e);
-                            } else {
-                                eprintln!("Failed to broadcast message to a user: {}", e);
-                            }
-                        }
-                    }
+                Ok(user_lock) => {
+                    user_lock.send(message.clone());
                 },
                 Err(_) => {
                     eprintln!("Failed to acquire lock for a user in room {} within timeout", room_id);
                 }
             }
         }
+        metrics::BROADCAST_SECONDS.observe(started.elapsed().as_secs_f64());
     }
-}
\ No newline at end of file
+
+    /// Like `broadcast_message`, but only to the given users.
+    pub(crate) async fn send_to_users(
+        &self,
+        room_id: u32,
+        recipients: &HashSet<ParticipantId>,
+        message: String,
+    ) {
+        let Some(room) = self.room(room_id).await else {
+            println!("Room {} not found", room_id);
+            return;
+        };
+        let members = room.members();
+
+        for user in recipients.iter().filter_map(|user_id| members.get(user_id)) {
+            match timeout(Duration::from_secs(5), user.lock()).await {
+                Ok(user_lock) => {
+                    user_lock.send(message.clone());
+                }
+                Err(_) => {
+                    eprintln!(
+                        "Failed to acquire lock for a user in room {} within timeout",
+                        room_id
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participant(byte: u8) -> ParticipantId {
+        AccountIdentity::from([byte; 32]).into()
+    }
+
+    fn in_view(grid: &SpatialGrid, center: (i32, i32), radius: i32) -> HashSet<ParticipantId> {
+        grid.users_in_view(center, radius)
+            .into_iter()
+            .map(|(user_id, _)| user_id)
+            .collect()
+    }
+
+    #[test]
+    fn view_is_a_circle_around_the_center() {
+        let mut grid = SpatialGrid::default();
+        grid.add(&participant(1), (10, 10));
+        grid.add(&participant(2), (13, 10));
+        grid.add(&participant(3), (12, 12));
+        grid.add(&participant(4), (13, 13));
+        grid.add(&participant(5), (10, 14));
+
+        assert_eq!(
+            in_view(&grid, (10, 10), 3),
+            HashSet::from([participant(1), participant(2), participant(3)])
+        );
+        assert_eq!(in_view(&grid, (10, 10), 0), HashSet::from([participant(1)]));
+    }
+
+    #[test]
+    fn users_sharing_a_tile_are_all_found() {
+        let mut grid = SpatialGrid::default();
+        grid.add(&participant(1), (0, 0));
+        grid.add(&participant(2), (0, 0));
+        let found = grid.users_in_view((1, 0), 1);
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|(_, tile)| *tile == (0, 0)));
+    }
+
+    #[test]
+    fn moving_away_leaves_the_view() {
+        let mut grid = SpatialGrid::default();
+        grid.add(&participant(1), (5, 5));
+        grid.remove(&participant(1), (5, 5));
+        grid.add(&participant(1), (20, 5));
+        assert!(in_view(&grid, (5, 5), 5).is_empty());
+        assert_eq!(grid.occupied_tiles(), HashSet::from([(20, 5)]));
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::attendance;
+use crate::identity::ParticipantId;
+use crate::room_manager::RoomManager;
+use crate::user::User;
+use crate::whiteboard;
+
+// 10 ticks a second
+pub const TICK_INTERVAL: Duration = Duration::from_millis(100);
+// Speed limit: tiles a user can move per tick
+pub const MAX_STEPS_PER_TICK: usize = 1;
+// Queued steps beyond this are treated as flooding and rejected
+pub const MAX_QUEUED_MOVES: usize = 4;
+// Default area of interest, in tiles
+pub const VIEW_RADIUS: i32 = 12;
+// Attendance is sampled every this many ticks
+const ATTENDANCE_EVERY_TICKS: u64 =
+    attendance::SAMPLE_SECS * 1000 / TICK_INTERVAL.as_millis() as u64;
+// The whiteboard is written to disk at most this often, every 5 seconds
+const WHITEBOARD_SNAPSHOT_EVERY_TICKS: u64 = 50;
+
+// (user id, position at the start of the tick, position at the end)
+type Move = (ParticipantId, (i32, i32), (i32, i32));
+
+/// Runs the fixed-rate simulation for a room until the room goes away.
+pub async fn run(room_id: u32) {
+    let mut ticker = interval(TICK_INTERVAL);
+    // If a tick runs long, don't burst to catch up, that would undo the speed limit
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut tick: u64 = 0;
+
+    loop {
+        ticker.tick().await;
+        tick += 1;
+        if !step(room_id, tick).await {
+            println!("Room {} is gone, stopping its tick loop", room_id);
+            break;
+        }
+        if tick.is_multiple_of(ATTENDANCE_EVERY_TICKS) {
+            attendance::sample(room_id).await;
+        }
+        if tick.is_multiple_of(WHITEBOARD_SNAPSHOT_EVERY_TICKS) {
+            whiteboard::snapshot(room_id).await;
+        }
+    }
+}
+
+// Applies queued movement for one tick and sends each user one `positions` delta
+// covering what it can see.
+// Returns false once the room no longer exists.
+async fn step(room_id: u32, tick: u64) -> bool {
+    let room_manager = RoomManager::instance();
+    let Some(users) = room_manager.room_users(room_id).await else {
+        return false;
+    };
+
+    let mut moved: Vec<Move> = vec![];
+    for user_arc in users {
+        let mut user = user_arc.lock().await;
+        let Some(user_id) = user.id.clone() else {
+            continue;
+        };
+        if user.move_queue.is_empty() {
+            continue;
+        }
+
+        let start = user.coordinates;
+        for _ in 0..MAX_STEPS_PER_TICK {
+            let Some(next) = user.move_queue.pop_front() else {
+                break;
+            };
+            // Checks the step size, room bounds and collision layer of the map
+            if room_manager
+                .move_user(room_id, &user_id, user.coordinates, next)
+                .await
+            {
+                user.coordinates = next;
+            } else {
+                user.reject_movement();
+                break;
+            }
+        }
+
+        if user.coordinates != start {
+            moved.push((user_id, start, user.coordinates));
+        }
+    }
+
+    // Joins and leaves change views too, even on a tick where nobody moved
+    let membership_changed = room_manager.take_views_dirty(room_id).await;
+    if moved.is_empty() && !membership_changed {
+        return true;
+    }
+
+    update_views(room_id, tick, &moved).await;
+
+    for (user_id, start, end) in moved.iter() {
+        User::broadcast_zone_change(room_id, user_id, Some(*start), *end).await;
+    }
+    if !moved.is_empty() {
+        room_manager.refresh_audio_routing(room_id).await;
+    }
+
+    true
+}
+
+// Area-of-interest filtering: every user gets view_enter/view_leave for users crossing
+// its view radius, and positions only for movers it can see.
+async fn update_views(room_id: u32, tick: u64, moved: &[Move]) {
+    let room_manager = RoomManager::instance();
+    let Some(users) = room_manager.room_users(room_id).await else {
+        return;
+    };
+    let moved: HashMap<&ParticipantId, (i32, i32)> = moved
+        .iter()
+        .map(|(user_id, _, end)| (user_id, *end))
+        .collect();
+
+    for user_arc in users {
+        let mut user = user_arc.lock().await;
+        let Some(user_id) = user.id.clone() else {
+            continue;
+        };
+
+        let mut in_view = room_manager
+            .users_in_view(room_id, user.coordinates, user.view_radius)
+            .await;
+        in_view.remove(&user_id);
+
+        let entered: Vec<_> = in_view
+            .iter()
+            .filter(|(id, _)| !user.visible_users.contains(*id))
+            .map(|(id, coordinates)| serde_json::json!({ "user_id": id, "coordinates": coordinates }))
+            .collect();
+        let left: Vec<&ParticipantId> = user
+            .visible_users
+            .iter()
+            .filter(|id| !in_view.contains_key(*id))
+            .collect();
+        // Users that just entered the view already come with their position
+        let positions: Vec<_> = moved
+            .iter()
+            .filter(|(id, _)| {
+                **id == &user_id
+                    || (in_view.contains_key(**id) && user.visible_users.contains(**id))
+            })
+            .map(|(id, coordinates)| serde_json::json!({ "user_id": id, "coordinates": coordinates }))
+            .collect();
+
+        if !left.is_empty() {
+            let leave_message = serde_json::json!({
+                "type": "view_leave",
+                "user_ids": left
+            });
+            user.send(leave_message.to_string());
+        }
+        if !entered.is_empty() {
+            let enter_message = serde_json::json!({
+                "type": "view_enter",
+                "users": entered
+            });
+            user.send(enter_message.to_string());
+        }
+        if !positions.is_empty() {
+            let positions_message = serde_json::json!({
+                "type": "positions",
+                "tick": tick,
+                "users": positions
+            });
+            user.send(positions_message.to_string());
+        }
+
+        user.visible_users = in_view.into_keys().collect::<HashSet<_>>();
+    }
+}
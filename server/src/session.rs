@@ -0,0 +1,510 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use rand::RngCore;
+use tokio::sync::Mutex;
+
+use crate::attendance::{self, AttendanceEventKind};
+use crate::identity::{AccountIdentity, ParticipantId};
+use crate::lobby;
+use crate::room_manager::RoomManager;
+use crate::user::User;
+use crate::ws_payload::ReconnectPayload;
+
+lazy_static! {
+    // How long a dropped user keeps their place in the room while waiting to reconnect
+    pub(crate) static ref RECONNECT_GRACE_PERIOD: Duration =
+        duration_from_env("EDUVERSE_RECONNECT_GRACE_SECS", 30);
+    // How often the server pings every socket
+    pub(crate) static ref HEARTBEAT_INTERVAL: Duration =
+        duration_from_env("EDUVERSE_HEARTBEAT_INTERVAL_SECS", 15);
+    // A socket that sent nothing, not even a pong, for this long is considered dead
+    pub(crate) static ref HEARTBEAT_TIMEOUT: Duration =
+        duration_from_env("EDUVERSE_HEARTBEAT_TIMEOUT_SECS", 45);
+    // A session token older than this can't resume, a new join with a signature is needed
+    pub(crate) static ref SESSION_TOKEN_TTL: Duration =
+        duration_from_env("EDUVERSE_SESSION_TOKEN_TTL_SECS", 12 * 60 * 60);
+    // Connected but no actions for this long shows the user as away
+    pub(crate) static ref AWAY_AFTER: Duration = duration_from_env("EDUVERSE_AWAY_AFTER_SECS", 300);
+    pub(crate) static ref DUPLICATE_LOGIN_POLICY: DuplicateLoginPolicy =
+        match env::var("EDUVERSE_DUPLICATE_LOGIN") {
+            Ok(value) => DuplicateLoginPolicy::parse(&value).unwrap_or_else(|| {
+                eprintln!(
+                    "Unknown EDUVERSE_DUPLICATE_LOGIN {:?}, expected replace, reject or multi_device. Using replace",
+                    value
+                );
+                DuplicateLoginPolicy::Replace
+            }),
+            Err(_) => DuplicateLoginPolicy::Replace,
+        };
+}
+
+/// What happens when an account that's already in a room signs in again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DuplicateLoginPolicy {
+    // The new login takes over, the old connection is told it signed in elsewhere
+    Replace,
+    // The new login is turned away while the old one is still connected
+    Reject,
+    // Each device is its own participant, told apart by device id.
+    // Signing in again from the same device replaces that device's session.
+    MultiDevice,
+}
+
+impl DuplicateLoginPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "replace" => Some(DuplicateLoginPolicy::Replace),
+            "reject" => Some(DuplicateLoginPolicy::Reject),
+            "multi_device" => Some(DuplicateLoginPolicy::MultiDevice),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn duration_from_env(name: &str, default_secs: u64) -> Duration {
+    let secs = env::var(name)
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(default_secs);
+    Duration::from_secs(secs)
+}
+
+/// The participant id a login gets under the duplicate-login policy. Device ids only
+/// count with multi-device logins, a device that didn't pick one gets a random id.
+pub(crate) fn participant_for(
+    account: AccountIdentity,
+    device_id: Option<String>,
+) -> Result<ParticipantId, &'static str> {
+    participant_under(*DUPLICATE_LOGIN_POLICY, account, device_id)
+}
+
+fn participant_under(
+    policy: DuplicateLoginPolicy,
+    account: AccountIdentity,
+    device_id: Option<String>,
+) -> Result<ParticipantId, &'static str> {
+    if policy != DuplicateLoginPolicy::MultiDevice {
+        return Ok(account.into());
+    }
+    let device_id = device_id.unwrap_or_else(|| new_token()[..8].to_string());
+    if !ParticipantId::is_valid_device(&device_id) {
+        return Err("invalid_device_id");
+    }
+    Ok(ParticipantId::new(account, Some(device_id)))
+}
+
+/// Makes room for a new login of `user_id`, or refuses it, following the duplicate-login
+/// policy. A session that's only waiting for its socket to come back is always replaced.
+pub(crate) async fn resolve_duplicate_login(
+    room_id: u32,
+    user_id: &ParticipantId,
+) -> Result<(), &'static str> {
+    let existing = RoomManager::instance()
+        .find_in_course(room_id, user_id)
+        .await;
+    resolve_existing_login(*DUPLICATE_LOGIN_POLICY, existing, user_id).await
+}
+
+async fn resolve_existing_login(
+    policy: DuplicateLoginPolicy,
+    existing: Option<Arc<Mutex<User>>>,
+    user_id: &ParticipantId,
+) -> Result<(), &'static str> {
+    let Some(existing) = existing else {
+        return Ok(());
+    };
+    let connected = !existing.lock().await.reconnecting;
+    if connected && policy == DuplicateLoginPolicy::Reject {
+        return Err("already_signed_in");
+    }
+
+    println!("{} signed in again, replacing the old session", user_id);
+    // Leave first so closing the old socket doesn't look like a dropped connection
+    User::handle_leave_room(existing.clone()).await;
+    existing.lock().await.sign_out_elsewhere();
+    Ok(())
+}
+
+/// Makes sure `handle_disconnect` runs however the connection task ends, panics included.
+pub(crate) struct ConnectionGuard {
+    // Swapped for the restored user when the connection reconnects into an old slot
+    pub(crate) user_arc: Arc<Mutex<User>>,
+    connection_id: u64,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn new(user_arc: Arc<Mutex<User>>, connection_id: u64) -> Self {
+        ConnectionGuard {
+            user_arc,
+            connection_id,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        // Nothing left to clean up for if the runtime itself is shutting down
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(handle_disconnect(self.user_arc.clone(), self.connection_id));
+        }
+    }
+}
+
+/// What a wallet signs to join a course: the course and the challenge the server sent
+/// on this connection, so a signature is only good for one join attempt.
+pub(crate) fn join_message(course_id: u32, challenge: &str) -> String {
+    format!("eduverse-join:{}:{}", course_id, challenge)
+}
+
+/// A fresh random session token, hex encoded.
+pub(crate) fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Called when a socket goes away. The user stays in the room, marked as reconnecting,
+/// and only leaves for good if nobody reconnects with their token within the grace period.
+pub(crate) async fn handle_disconnect(user_arc: Arc<Mutex<User>>, connection_id: u64) {
+    hold_for_reconnect(user_arc, connection_id, *RECONNECT_GRACE_PERIOD).await;
+}
+
+async fn hold_for_reconnect(
+    user_arc: Arc<Mutex<User>>,
+    connection_id: u64,
+    grace_period: Duration,
+) {
+    // Nothing to hold for someone who was never let in
+    if lobby::leave(&user_arc).await {
+        return;
+    }
+    let (room_id, user_id) = {
+        let mut user = user_arc.lock().await;
+        // The user already came back over a newer socket
+        if user.connection_id != connection_id {
+            return;
+        }
+        let (Some(room_id), Some(user_id)) = (user.room_id, user.id.clone()) else {
+            return;
+        };
+        user.reconnecting = true;
+        user.move_queue.clear();
+        (room_id, user_id)
+    };
+    println!(
+        "{} disconnected from room {}, holding their place",
+        user_id, room_id
+    );
+    attendance::record(room_id, &user_id, None, AttendanceEventKind::Disconnect).await;
+
+    let reconnecting_message = serde_json::json!({
+        "type": "user_reconnecting",
+        "user_id": user_id
+    });
+    RoomManager::instance()
+        .broadcast_message(Some(user_id), room_id, reconnecting_message.to_string())
+        .await;
+
+    tokio::spawn(async move {
+        tokio::time::sleep(grace_period).await;
+        let expired = {
+            let user = user_arc.lock().await;
+            user.reconnecting && user.connection_id == connection_id
+        };
+        if expired {
+            User::handle_leave_room(user_arc).await;
+        }
+    });
+}
+
+/// Shows the user as away once they've gone `away_after` without an action.
+pub(crate) async fn check_idle(
+    user_arc: Arc<Mutex<User>>,
+    idle_for: Duration,
+    away_after: Duration,
+) {
+    if idle_for > away_after {
+        set_away(user_arc, true).await;
+    }
+}
+
+/// Shows the user as away after a stretch of inactivity, and as active again on their next action.
+pub(crate) async fn set_away(user_arc: Arc<Mutex<User>>, away: bool) {
+    let (room_id, user_id) = {
+        let mut user = user_arc.lock().await;
+        if user.away == away {
+            return;
+        }
+        let (Some(room_id), Some(user_id)) = (user.room_id, user.id.clone()) else {
+            return;
+        };
+        user.away = away;
+        (room_id, user_id)
+    };
+
+    let status_message = serde_json::json!({
+        "type": "user_status",
+        "user_id": user_id,
+        "status": if away { "away" } else { "active" }
+    });
+    RoomManager::instance()
+        .broadcast_message(None, room_id, status_message.to_string())
+        .await;
+}
+
+/// Moves a new connection into the `User` slot the token belongs to. Returns the restored
+/// user, which the connection should use from now on, or `None` if the token is no good.
+pub(crate) async fn reconnect(
+    user_arc: Arc<Mutex<User>>,
+    payload: ReconnectPayload,
+) -> Option<Arc<Mutex<User>>> {
+    let connection = {
+        let user = user_arc.lock().await;
+        if user.room_id.is_some() {
+            user.reject_reconnect("already_joined");
+            return None;
+        }
+        user.connection()
+    };
+
+    let room_manager = RoomManager::instance();
+    let Some((room_id, user_id)) = room_manager.session(&payload.session_token).await else {
+        user_arc.lock().await.reject_reconnect("invalid_session");
+        return None;
+    };
+    let Some(restored) = room_manager.find_user(room_id, &user_id).await else {
+        user_arc.lock().await.reject_reconnect("session_expired");
+        return None;
+    };
+
+    let closed_producers = {
+        let mut restored_user = restored.lock().await;
+        // The old socket is still up, so this is a second login rather than a reconnect
+        if !restored_user.reconnecting {
+            if *DUPLICATE_LOGIN_POLICY == DuplicateLoginPolicy::Reject {
+                drop(restored_user);
+                user_arc.lock().await.reject_reconnect("already_signed_in");
+                return None;
+            }
+            restored_user.sign_out_elsewhere();
+        }
+
+        // Tokens are single use
+        room_manager.revoke_session(&payload.session_token).await;
+        let session_token = room_manager.issue_session(room_id, &user_id).await;
+        restored_user
+            .resume_session(connection, session_token)
+            .await
+    };
+    println!("{} reconnected to room {}", user_id, room_id);
+    attendance::record(room_id, &user_id, None, AttendanceEventKind::Reconnect).await;
+
+    // Resend the whole area of interest, the client may have lost it
+    room_manager.mark_views_dirty(room_id).await;
+    room_manager
+        .close_consumers_of(room_id, &closed_producers)
+        .await;
+
+    let reconnected_message = serde_json::json!({
+        "type": "user_reconnected",
+        "user_id": user_id
+    });
+    room_manager
+        .broadcast_message(Some(user_id), room_id, reconnected_message.to_string())
+        .await;
+    room_manager.refresh_audio_routing(room_id).await;
+
+    Some(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    const GRACE: Duration = Duration::from_millis(50);
+
+    // A user on a real loopback socket, seated in a room that has no router behind it.
+    // The client end is returned so the socket stays open.
+    async fn seated_user(
+        room_id: u32,
+    ) -> (Arc<Mutex<User>>, WebSocketStream<MaybeTlsStream<TcpStream>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let client = tokio::spawn(async move { tokio_tungstenite::connect_async(url).await });
+        let (stream, _) = listener.accept().await.unwrap();
+        let (sink, _) = tokio_tungstenite::accept_async(stream)
+            .await
+            .unwrap()
+            .split();
+        let (client, _) = client.await.unwrap().unwrap();
+
+        let mut user = User::new(sink);
+        user.id = Some(account().into());
+        user.room_id = Some(room_id);
+        (Arc::new(Mutex::new(user)), client)
+    }
+
+    const POLICIES: [DuplicateLoginPolicy; 3] = [
+        DuplicateLoginPolicy::Replace,
+        DuplicateLoginPolicy::Reject,
+        DuplicateLoginPolicy::MultiDevice,
+    ];
+
+    fn account() -> AccountIdentity {
+        AccountIdentity::from([7; 32])
+    }
+
+    #[test]
+    fn policies_parse_from_their_env_names() {
+        assert_eq!(
+            DuplicateLoginPolicy::parse("replace"),
+            Some(DuplicateLoginPolicy::Replace)
+        );
+        assert_eq!(
+            DuplicateLoginPolicy::parse("reject"),
+            Some(DuplicateLoginPolicy::Reject)
+        );
+        assert_eq!(
+            DuplicateLoginPolicy::parse("multi_device"),
+            Some(DuplicateLoginPolicy::MultiDevice)
+        );
+        assert_eq!(DuplicateLoginPolicy::parse("multi-device"), None);
+        assert_eq!(DuplicateLoginPolicy::parse("Reject"), None);
+    }
+
+    #[test]
+    fn device_ids_only_count_with_multi_device_logins() {
+        for policy in [DuplicateLoginPolicy::Replace, DuplicateLoginPolicy::Reject] {
+            let user_id = participant_under(policy, account(), Some("laptop".into())).unwrap();
+            assert_eq!(user_id, account().into());
+            // Not even looked at
+            assert!(participant_under(policy, account(), Some("no spaces".into())).is_ok());
+        }
+
+        let policy = DuplicateLoginPolicy::MultiDevice;
+        let laptop = participant_under(policy, account(), Some("laptop".into())).unwrap();
+        assert_eq!(laptop.account(), &account());
+        assert_eq!(laptop.device(), Some("laptop"));
+        assert_eq!(
+            participant_under(policy, account(), Some("no spaces".into())),
+            Err("invalid_device_id")
+        );
+        // A device that didn't pick an id gets a random one
+        let first = participant_under(policy, account(), None).unwrap();
+        let second = participant_under(policy, account(), None).unwrap();
+        assert!(first.device().is_some_and(ParticipantId::is_valid_device));
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn a_first_login_goes_through_under_every_policy() {
+        for policy in POLICIES {
+            assert_eq!(
+                resolve_existing_login(policy, None, &account().into()).await,
+                Ok(())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_connected_login_is_replaced_unless_the_policy_rejects() {
+        for (room_id, policy) in (9_010..).zip(POLICIES) {
+            let (existing, _client) = seated_user(room_id).await;
+            let result =
+                resolve_existing_login(policy, Some(existing.clone()), &account().into()).await;
+
+            let existing = existing.lock().await;
+            if policy == DuplicateLoginPolicy::Reject {
+                assert_eq!(result, Err("already_signed_in"));
+                assert_eq!(existing.room_id, Some(room_id));
+            } else {
+                assert_eq!(result, Ok(()));
+                assert_eq!(existing.room_id, None);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_login_waiting_to_reconnect_is_always_replaced() {
+        for (room_id, policy) in (9_020..).zip(POLICIES) {
+            let (existing, _client) = seated_user(room_id).await;
+            existing.lock().await.reconnecting = true;
+            let result =
+                resolve_existing_login(policy, Some(existing.clone()), &account().into()).await;
+
+            assert_eq!(result, Ok(()));
+            assert_eq!(existing.lock().await.room_id, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnected_users_leave_once_the_grace_period_runs_out() {
+        let (user_arc, _client) = seated_user(9_001).await;
+        let connection_id = user_arc.lock().await.connection_id;
+
+        hold_for_reconnect(user_arc.clone(), connection_id, GRACE).await;
+        {
+            let user = user_arc.lock().await;
+            assert!(user.reconnecting);
+            assert_eq!(user.room_id, Some(9_001));
+        }
+
+        tokio::time::sleep(GRACE * 4).await;
+        let user = user_arc.lock().await;
+        assert!(!user.reconnecting);
+        assert_eq!(user.room_id, None);
+    }
+
+    #[tokio::test]
+    async fn reconnecting_within_the_grace_period_keeps_the_place() {
+        let (user_arc, _client) = seated_user(9_002).await;
+        let connection_id = user_arc.lock().await.connection_id;
+
+        hold_for_reconnect(user_arc.clone(), connection_id, GRACE).await;
+        // What `resume_session` does for the new socket
+        {
+            let mut user = user_arc.lock().await;
+            user.connection_id = connection_id + 1_000;
+            user.reconnecting = false;
+        }
+
+        tokio::time::sleep(GRACE * 4).await;
+        assert_eq!(user_arc.lock().await.room_id, Some(9_002));
+    }
+
+    #[tokio::test]
+    async fn a_stale_socket_closing_leaves_the_user_alone() {
+        let (user_arc, _client) = seated_user(9_003).await;
+        let connection_id = user_arc.lock().await.connection_id;
+
+        hold_for_reconnect(user_arc.clone(), connection_id + 1_000, GRACE).await;
+        tokio::time::sleep(GRACE * 4).await;
+        let user = user_arc.lock().await;
+        assert!(!user.reconnecting);
+        assert_eq!(user.room_id, Some(9_003));
+    }
+
+    #[tokio::test]
+    async fn away_follows_activity_only_while_in_a_room() {
+        let (user_arc, _client) = seated_user(9_004).await;
+        let away_after = Duration::from_secs(60);
+
+        check_idle(user_arc.clone(), Duration::from_secs(59), away_after).await;
+        assert!(!user_arc.lock().await.away);
+        check_idle(user_arc.clone(), Duration::from_secs(61), away_after).await;
+        assert!(user_arc.lock().await.away);
+        // The next action brings them back
+        set_away(user_arc.clone(), false).await;
+        assert!(!user_arc.lock().await.away);
+
+        user_arc.lock().await.room_id = None;
+        check_idle(user_arc.clone(), Duration::from_secs(61), away_after).await;
+        assert!(!user_arc.lock().await.away);
+    }
+}
@@ -1,34 +1,81 @@
 use mediasoup::rtp_parameters::MediaKind;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug)]
+use crate::identity::ParticipantId;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum StreamType {
     Camera,
     Audio,
+    Screen,
+}
+
+impl StreamType {
+    // What a producer is when the client doesn't say
+    pub(crate) fn default_for(kind: MediaKind) -> Self {
+        match kind {
+            MediaKind::Audio => StreamType::Audio,
+            MediaKind::Video => StreamType::Camera,
+        }
+    }
 }
+
 #[derive(Clone, Debug)]
 pub struct StreamInfo {
     // Who owns this stream (pubKey)
-    user_id: String,
-    stream_type: StreamType,  // e.g., Camera/Screen/Audio
-    settings: StreamSettings, // Current stream settings
-    position: (i32, i32),     // Position of the stream source (for spatial audio/video)
-}
-pub struct MediaState {
-    stream_id: String,
+    pub(crate) user_id: ParticipantId,
     kind: MediaKind,
-    quality: QualityLevel,
-    active: bool,
+    stream_type: StreamType, // e.g., Camera/Screen/Audio
 }
-#[derive(Clone, Debug)]
-struct StreamSettings {
-    quality: QualityLevel, // High/Medium/Low
-    max_bitrate: u32,      // e.g., 1500000 for HD
-    range: f32,            // e.g., 50.0 units - Range for spatial audio/video
+impl StreamInfo {
+    pub(crate) fn new(user_id: ParticipantId, kind: MediaKind, stream_type: StreamType) -> Self {
+        StreamInfo {
+            user_id,
+            kind,
+            stream_type,
+        }
+    }
+
+    pub(crate) fn is_screen(&self) -> bool {
+        matches!(self.stream_type, StreamType::Screen)
+    }
+
+    /// How the stream is announced to clients that may want to consume it.
+    pub(crate) fn to_json(&self, producer_id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "producer_id": producer_id,
+            "user_id": self.user_id,
+            "kind": self.kind,
+            "stream_type": self.stream_type
+        })
+    }
 }
 
-#[derive(Clone, Debug)]
-pub enum QualityLevel {
-    High,   // 1080p
-    Medium, // 720p
-    Low,    // 480p
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::AccountIdentity;
+
+    #[test]
+    fn producers_default_to_camera_and_microphone() {
+        let owner: ParticipantId = AccountIdentity::from([1; 32]).into();
+        let audio = StreamInfo::new(
+            owner.clone(),
+            MediaKind::Audio,
+            StreamType::default_for(MediaKind::Audio),
+        );
+        let camera = StreamInfo::new(
+            owner.clone(),
+            MediaKind::Video,
+            StreamType::default_for(MediaKind::Video),
+        );
+        let screen = StreamInfo::new(owner, MediaKind::Video, StreamType::Screen);
+        assert!(!audio.is_screen() && !camera.is_screen() && screen.is_screen());
+
+        let announced = camera.to_json("p1");
+        assert_eq!(announced["producer_id"], "p1");
+        assert_eq!(announced["kind"], "video");
+        assert_eq!(announced["stream_type"], "camera");
+    }
 }
@@ -1,36 +1,88 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
-use mediasoup::consumer::Consumer;
-use mediasoup::data_structures::{DtlsParameters, IceCandidate, IceParameters};
-use mediasoup::prelude::{MediaKind, RtpParameters};
-use mediasoup::producer::Producer;
-use mediasoup::rtp_parameters::RtcpParameters;
+use mediasoup::consumer::{Consumer, ConsumerOptions};
+use mediasoup::prelude::{MediaKind, Transport, WebRtcTransportRemoteParameters};
+use mediasoup::producer::{Producer, ProducerId, ProducerOptions};
 use mediasoup::webrtc_transport::WebRtcTransport;
-use rand::Rng;
-use serde::{Deserialize, Serialize};
-use sp_core::{sr25519, ByteArray, Pair};
+use serde::Deserialize;
+use sp_core::{sr25519, Pair};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{interval, Instant, MissedTickBehavior};
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
 
+use crate::attendance::{self, AttendanceEventKind};
+use crate::attestation;
+use crate::breakout;
+use crate::capacity;
+use crate::chat::{self, ChatScope, RateLimiter, JOIN_SNAPSHOT_SIZE, MAX_PAGE_SIZE};
+use crate::classroom_map::ClassroomMap;
+use crate::hand_raise;
+use crate::roles::{Permission, Role};
 use crate::room_manager::RoomManager;
+use crate::room_tick::{MAX_QUEUED_MOVES, VIEW_RADIUS};
+use crate::identity::{AccountIdentity, ParticipantId};
+use crate::lobby;
+use crate::metrics;
+use crate::moderation;
+use crate::polls;
+use crate::presentation;
+use crate::session::{self, ConnectionGuard};
+use crate::stream_types::{StreamInfo, StreamType};
+use crate::whiteboard::{self, WhiteboardOp};
 use crate::ws_payload::{
-    ConsumePayload, JoinPayload, MovementPayload, ProducePayload, ResumePayload, TransportOptions,
+    AnswerPollPayload, AssignBreakoutPayload, AttestationPayload, BreakoutBroadcastPayload,
+    ChatHistoryPayload, ClosePollPayload, ConsumePayload, CreatePollPayload, DeleteMessagePayload,
+    FollowPresenterPayload, GrantFloorPayload, JoinPayload, LaserPointerPayload,
+    LobbyDecisionPayload, LowerHandPayload, ModerationPayload, MovementPayload, ProducePayload,
+    ReconnectPayload, ResumePayload, SendMessagePayload, SetSlidePayload, StartBreakoutsPayload,
+    StartPresentationPayload, TransportOptions, VisitBreakoutPayload, WaitingRoomPayload,
+    WhiteboardModePayload,
 };
+
+// Every websocket gets its own id, so a stale socket closing can't tear down a reconnected user
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
 pub struct User {
-    pub(crate) id: Option<String>,
-    room_id: Option<u32>,
-    coordinates: (i32, i32),
-    pub websocket: WebSocketStream<TcpStream>,
+    pub(crate) id: Option<ParticipantId>,
+    // Set once the user is authenticated and let into a room
+    pub(crate) role: Option<Role>,
+    pub(crate) room_id: Option<u32>,
+    pub(crate) coordinates: (i32, i32),
+    // Outgoing messages, drained into the websocket by a dedicated writer task
+    outbound: mpsc::UnboundedSender<Message>,
+    pub(crate) connection_id: u64,
+    // Set while the socket is gone but the user still holds their place in the room
+    pub(crate) reconnecting: bool,
+    // Lets a new socket take over this user after a disconnect
+    pub(crate) session_token: Option<String>,
+    // No actions for a while, see `session::AWAY_AFTER`
+    pub(crate) away: bool,
+    // Steps waiting to be applied by the room tick
+    pub(crate) move_queue: VecDeque<(i32, i32)>,
     transport: Option<WebRtcTransport>,
     // Track what this user is broadcasting
     producers: HashMap<String, Producer>,
     // Track what this user is receiving
     consumers: HashMap<String, Consumer>,
-    audio_range: f32,
+    pub(crate) audio_range: f32,
+    // Users whose audio this user currently receives, given zones and proximity
+    audible_peers: HashSet<ParticipantId>,
+    // Area of interest: how far this user sees, and who it currently has in view
+    pub(crate) view_radius: i32,
+    pub(crate) visible_users: HashSet<ParticipantId>,
+    chat_limiter: RateLimiter,
+    // Turns slides along with the presenter, off while browsing the deck on their own
+    pub(crate) follow_presenter: bool,
+    // The room whose lobby the user is waiting in, they're not in any room until let in
+    pub(crate) waiting_in: Option<u32>,
+    // Signed as part of the next join, replaced on every attempt so signatures can't be replayed
+    join_challenge: String,
 }
 
 #[derive(Deserialize)]
@@ -39,12 +91,74 @@ enum UserAction {
     // canvas movement/join/leave/send-message actions
     #[serde(rename = "join")]
     JoinRoom(JoinPayload),
+    #[serde(rename = "reconnect")]
+    Reconnect(ReconnectPayload),
     #[serde(rename = "leave")]
     LeaveRoom,
+    #[serde(rename = "list_rooms")]
+    ListRooms,
     #[serde(rename = "move")]
     MoveTo(MovementPayload),
     #[serde(rename = "send_message")]
-    SendMessage(String),
+    SendMessage(SendMessagePayload),
+    #[serde(rename = "chat_history")]
+    ChatHistory(ChatHistoryPayload),
+    #[serde(rename = "delete_message")]
+    DeleteMessage(DeleteMessagePayload), // teacher/co-teacher only
+    #[serde(rename = "moderate")]
+    Moderate(ModerationPayload), // teacher/staff only, signed
+    #[serde(rename = "set_recording")]
+    SetRecording(bool),
+    #[serde(rename = "raise_hand")]
+    RaiseHand,
+    #[serde(rename = "lower_hand")]
+    LowerHand(LowerHandPayload),
+    #[serde(rename = "grant_floor")]
+    GrantFloor(GrantFloorPayload), // teacher/staff only
+    #[serde(rename = "revoke_floor")]
+    RevokeFloor, // teacher/staff only
+    #[serde(rename = "create_poll")]
+    CreatePoll(CreatePollPayload), // teacher/staff only
+    #[serde(rename = "answer_poll")]
+    AnswerPoll(AnswerPollPayload),
+    #[serde(rename = "close_poll")]
+    ClosePoll(ClosePollPayload), // teacher/staff only
+    #[serde(rename = "export_polls")]
+    ExportPolls, // teacher/staff only
+    #[serde(rename = "attendance_report")]
+    AttendanceReport, // teacher/co-teacher only
+    #[serde(rename = "attendance_attestation")]
+    AttendanceAttestation(AttestationPayload),
+    #[serde(rename = "whiteboard_op")]
+    WhiteboardOp(WhiteboardOp),
+    #[serde(rename = "whiteboard_mode")]
+    WhiteboardMode(WhiteboardModePayload), // teacher/co-teacher only
+    #[serde(rename = "start_presentation")]
+    StartPresentation(StartPresentationPayload), // teacher/co-teacher only
+    #[serde(rename = "set_slide")]
+    SetSlide(SetSlidePayload), // teacher/co-teacher only
+    #[serde(rename = "end_presentation")]
+    EndPresentation, // teacher/co-teacher only
+    #[serde(rename = "laser_pointer")]
+    LaserPointer(LaserPointerPayload), // teacher/co-teacher only
+    #[serde(rename = "follow_presenter")]
+    FollowPresenter(FollowPresenterPayload),
+    #[serde(rename = "start_breakouts")]
+    StartBreakouts(StartBreakoutsPayload), // teacher/co-teacher only
+    #[serde(rename = "close_breakouts")]
+    CloseBreakouts, // teacher/co-teacher only
+    #[serde(rename = "visit_breakout")]
+    VisitBreakout(VisitBreakoutPayload), // teacher/co-teacher only
+    #[serde(rename = "assign_breakout")]
+    AssignBreakout(AssignBreakoutPayload), // teacher/co-teacher only
+    #[serde(rename = "breakout_broadcast")]
+    BreakoutBroadcast(BreakoutBroadcastPayload), // teacher/co-teacher only
+    #[serde(rename = "set_waiting_room")]
+    SetWaitingRoom(WaitingRoomPayload), // teacher/co-teacher only
+    #[serde(rename = "lobby_admit")]
+    LobbyAdmit(LobbyDecisionPayload), // teacher/co-teacher only
+    #[serde(rename = "lobby_deny")]
+    LobbyDeny(LobbyDecisionPayload), // teacher/co-teacher only
 
     // webrtc actions
     #[serde(rename = "webrtc_init")]
@@ -60,41 +174,242 @@ enum UserAction {
     Resume(ResumePayload), // if the user paused a video to focus on audio-only, Resume would let them start receiving the video stream again.
 }
 impl User {
-    pub fn new(mut websocket: WebSocketStream<TcpStream>) -> Self {
-        User {
+    pub fn new(mut websocket: SplitSink<WebSocketStream<TcpStream>, Message>) -> Self {
+        let (outbound, mut outbound_rx) = mpsc::unbounded_channel::<Message>();
+        tokio::spawn(async move {
+            while let Some(message) = outbound_rx.recv().await {
+                metrics::SEND_QUEUE_DEPTH.observe(outbound_rx.len() as f64);
+                let closing = message.is_close();
+                if let Err(e) = websocket.send(message).await {
+                    eprintln!("Failed to write to websocket: {}", e);
+                    break;
+                }
+                if closing {
+                    break;
+                }
+            }
+            let _ = websocket.close().await;
+        });
+
+        let user = User {
             id: None,
+            role: None,
             room_id: None,
             coordinates: (0, 0),
-            websocket,
+            outbound,
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            reconnecting: false,
+            session_token: None,
+            away: false,
+            move_queue: VecDeque::new(),
             transport: None,
             producers: HashMap::new(),
             consumers: HashMap::new(),
             audio_range: 50.0,
+            audible_peers: HashSet::new(),
+            view_radius: VIEW_RADIUS,
+            visible_users: HashSet::new(),
+            chat_limiter: RateLimiter::new(),
+            follow_presenter: true,
+            waiting_in: None,
+            join_challenge: session::new_token(),
+        };
+        user.send_join_challenge();
+        user
+    }
+
+    fn send_join_challenge(&self) {
+        let challenge_message = serde_json::json!({
+            "type": "join_challenge",
+            "challenge": self.join_challenge
+        });
+        self.send(challenge_message.to_string());
+    }
+
+    /// The challenge the next join has to sign. Using it up sends the client a new one.
+    fn take_join_challenge(&mut self) -> String {
+        let challenge = std::mem::replace(&mut self.join_challenge, session::new_token());
+        self.send_join_challenge();
+        challenge
+    }
+    /// Queues a text message for this user's websocket.
+    pub(crate) fn send(&self, message: String) {
+        // Nobody to deliver to until the user reconnects
+        if self.reconnecting {
+            return;
+        }
+        if self.outbound.send(Message::Text(message)).is_err() {
+            eprintln!("Websocket writer for {:?} is gone, dropping message", self.id);
         }
     }
-    pub async fn handle_ws_actions(user_arc: Arc<Mutex<Self>>) {
+
+    pub(crate) fn ping(&self) {
+        if !self.reconnecting {
+            let _ = self.outbound.send(Message::Ping(vec![]));
+        }
+    }
+
+    pub(crate) fn close_socket(&self) {
+        let _ = self.outbound.send(Message::Close(None));
+    }
+
+    pub async fn handle_ws_actions(
+        mut user_arc: Arc<Mutex<Self>>,
+        mut incoming: SplitStream<WebSocketStream<TcpStream>>,
+    ) {
+        let connection_id = user_arc.lock().await.connection_id;
+        // Dropped when this task ends, however it ends, and cleans up after the user
+        let mut guard = ConnectionGuard::new(user_arc.clone(), connection_id);
+        let mut heartbeat = interval(*session::HEARTBEAT_INTERVAL);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Any frame counts as a sign of life, only actions count as activity
+        let mut last_seen = Instant::now();
+        let mut last_activity = Instant::now();
         loop {
-            let message = {
-                let mut user = user_arc.lock().await;
-                user.websocket.next().await
+            // The user isn't locked while waiting, so broadcasts can reach it meanwhile
+            let message = tokio::select! {
+                message = incoming.next() => message,
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() > *session::HEARTBEAT_TIMEOUT {
+                        println!("Heartbeat timed out for connection {}", connection_id);
+                        user_arc.lock().await.close_socket();
+                        break;
+                    }
+                    user_arc.lock().await.ping();
+                    session::check_idle(
+                        user_arc.clone(),
+                        last_activity.elapsed(),
+                        *session::AWAY_AFTER,
+                    )
+                    .await;
+                    continue;
+                }
             };
+            last_seen = Instant::now();
 
             match message {
                 Some(Ok(msg)) => {
+                    // tungstenite answers pings by itself, pongs only matter for last_seen
+                    if msg.is_ping() || msg.is_pong() {
+                        continue;
+                    }
+                    last_activity = Instant::now();
+                    session::set_away(user_arc.clone(), false).await;
                     if let Ok(message_str) = msg.to_text() {
                         match serde_json::from_str::<UserAction>(message_str) {
                             Ok(action) => match action {
                                 UserAction::JoinRoom(payload) => {
                                     Self::join_room(user_arc.clone(), payload).await;
                                 }
+                                UserAction::Reconnect(payload) => {
+                                    // From here on this socket drives the restored user
+                                    if let Some(restored) =
+                                        session::reconnect(user_arc.clone(), payload).await
+                                    {
+                                        user_arc = restored;
+                                        guard.user_arc = user_arc.clone();
+                                    }
+                                }
                                 UserAction::LeaveRoom => {
                                     Self::handle_leave_room(user_arc.clone()).await;
                                 }
+                                UserAction::ListRooms => {
+                                    capacity::list_rooms(user_arc.clone()).await;
+                                }
                                 UserAction::MoveTo(coordinates) => {
                                     Self::handle_move_to(user_arc.clone(), coordinates).await;
                                 }
-                                UserAction::SendMessage(message) => {
-                                    Self::handle_send_message(user_arc.clone(), message).await;
+                                UserAction::SendMessage(payload) => {
+                                    Self::handle_send_message(user_arc.clone(), payload).await;
+                                }
+                                UserAction::ChatHistory(payload) => {
+                                    Self::handle_chat_history(user_arc.clone(), payload).await;
+                                }
+                                UserAction::DeleteMessage(payload) => {
+                                    Self::handle_delete_message(user_arc.clone(), payload).await;
+                                }
+                                UserAction::Moderate(payload) => {
+                                    moderation::moderate(user_arc.clone(), payload).await;
+                                }
+                                UserAction::SetRecording(recording) => {
+                                    Self::handle_set_recording(user_arc.clone(), recording).await;
+                                }
+                                UserAction::RaiseHand => {
+                                    hand_raise::raise_hand(user_arc.clone()).await;
+                                }
+                                UserAction::LowerHand(payload) => {
+                                    hand_raise::lower_hand(user_arc.clone(), payload).await;
+                                }
+                                UserAction::GrantFloor(payload) => {
+                                    hand_raise::grant_floor(user_arc.clone(), payload).await;
+                                }
+                                UserAction::RevokeFloor => {
+                                    hand_raise::revoke_floor(user_arc.clone()).await;
+                                }
+                                UserAction::CreatePoll(payload) => {
+                                    polls::create_poll(user_arc.clone(), payload).await;
+                                }
+                                UserAction::AnswerPoll(payload) => {
+                                    polls::answer_poll(user_arc.clone(), payload).await;
+                                }
+                                UserAction::ClosePoll(payload) => {
+                                    polls::close_poll(user_arc.clone(), payload).await;
+                                }
+                                UserAction::ExportPolls => {
+                                    polls::export_polls(user_arc.clone()).await;
+                                }
+                                UserAction::AttendanceReport => {
+                                    attendance::attendance_report(user_arc.clone()).await;
+                                }
+                                UserAction::AttendanceAttestation(payload) => {
+                                    attestation::attendance_attestation(user_arc.clone(), payload)
+                                        .await;
+                                }
+                                UserAction::WhiteboardOp(op) => {
+                                    whiteboard::whiteboard_op(user_arc.clone(), op).await;
+                                }
+                                UserAction::WhiteboardMode(payload) => {
+                                    whiteboard::set_mode(user_arc.clone(), payload).await;
+                                }
+                                UserAction::StartPresentation(payload) => {
+                                    presentation::start_presentation(user_arc.clone(), payload)
+                                        .await;
+                                }
+                                UserAction::SetSlide(payload) => {
+                                    presentation::set_slide(user_arc.clone(), payload).await;
+                                }
+                                UserAction::EndPresentation => {
+                                    presentation::end_presentation(user_arc.clone()).await;
+                                }
+                                UserAction::LaserPointer(payload) => {
+                                    presentation::laser_pointer(user_arc.clone(), payload).await;
+                                }
+                                UserAction::FollowPresenter(payload) => {
+                                    presentation::follow_presenter(user_arc.clone(), payload).await;
+                                }
+                                UserAction::StartBreakouts(payload) => {
+                                    breakout::start_breakouts(user_arc.clone(), payload).await;
+                                }
+                                UserAction::CloseBreakouts => {
+                                    breakout::close_breakouts(user_arc.clone()).await;
+                                }
+                                UserAction::VisitBreakout(payload) => {
+                                    breakout::visit_breakout(user_arc.clone(), payload).await;
+                                }
+                                UserAction::AssignBreakout(payload) => {
+                                    breakout::assign_breakout(user_arc.clone(), payload).await;
+                                }
+                                UserAction::BreakoutBroadcast(payload) => {
+                                    breakout::breakout_broadcast(user_arc.clone(), payload).await;
+                                }
+                                UserAction::SetWaitingRoom(payload) => {
+                                    lobby::set_waiting_room(user_arc.clone(), payload).await;
+                                }
+                                UserAction::LobbyAdmit(payload) => {
+                                    lobby::admit(user_arc.clone(), payload).await;
+                                }
+                                UserAction::LobbyDeny(payload) => {
+                                    lobby::deny(user_arc.clone(), payload).await;
                                 }
 
                                 UserAction::ConnectTransport(transport_options) => {
@@ -107,11 +422,11 @@ impl User {
                                 UserAction::Produce(produce_payload) => {
                                     Self::handle_produce(user_arc.clone(), produce_payload).await;
                                 }
-                                UserAction::Consume(consumePayload) => {
-                                    Self::handle_consume(user_arc.clone(), consumePayload).await;
+                                UserAction::Consume(consume_payload) => {
+                                    Self::handle_consume(user_arc.clone(), consume_payload).await;
                                 }
-                                UserAction::Resume(resumePayload) => {
-                                    Self::handle_resume(user_arc.clone(), resumePayload).await;
+                                UserAction::Resume(resume_payload) => {
+                                    Self::handle_resume(user_arc.clone(), resume_payload).await;
                                 }
                                 UserAction::InitializeWebRTC => {
                                     Self::handle_webrtc_init(user_arc.clone()).await;
@@ -121,7 +436,10 @@ impl User {
                         }
                     }
                 }
-                Some(Err(e)) => println!("Error receiving message: {}", e),
+                Some(Err(e)) => {
+                    println!("Error receiving message: {}", e);
+                    break;
+                }
                 None => break,
             }
         }
@@ -129,64 +447,367 @@ impl User {
     async fn join_room(user_arc: Arc<Mutex<Self>>, payload: JoinPayload) {
         print!("Joining room");
         if !RoomManager::instance()
-            .rooms
-            .read()
-            .await.contains_key(&payload.course_id)
+            .room_exists(payload.course_id)
+            .await
+        {
+            return;
+        }
+        let (already_joined, challenge) = {
+            let mut user = user_arc.lock().await;
+            // Spent by this attempt whatever comes of it
+            let challenge = user.take_join_challenge();
+            (
+                user.room_id.is_some() || user.waiting_in.is_some(),
+                challenge,
+            )
+        };
+        if already_joined {
+            user_arc
+                .lock()
+                .await
+                .reject_join(payload.course_id, "already_joined");
+            return;
+        }
+
+        let (course_id, signature, message_signed) =
+            (payload.course_id, payload.signature, payload.message_signed);
+        let pub_address = match AccountIdentity::parse(&payload.pub_address) {
+            Ok(pub_address) => pub_address,
+            Err(e) => {
+                eprintln!("Invalid address {}: {}", payload.pub_address, e);
+                user_arc.lock().await.reject_join(course_id, "invalid_address");
+                return;
+            }
+        };
+
+        if message_signed != session::join_message(course_id, &challenge) {
+            user_arc.lock().await.reject_join(course_id, "invalid_challenge");
+            return;
+        }
+
+        // Verify the signature
+        if let Err(e) = verify_signature(&pub_address, signature, message_signed) {
+            eprintln!("Signature verification failed: {}", e);
+            user_arc.lock().await.reject_join(course_id, "invalid_signature");
+            return;
+        }
+
+        if let Err(reason) = RoomManager::instance()
+            .check_admission(course_id, &pub_address)
+            .await
         {
+            eprintln!("{} can't join room {}: {}", pub_address, course_id, reason);
+            user_arc.lock().await.reject_join(course_id, reason);
             return;
         }
 
-        let (course_id, pub_address, signature, message_signed) = (
-            payload.course_id,
-            payload.pub_address,
-            payload.signature,
-            payload.message_signed,
-        );
-        //
-        // // Verify the signature
-        // if let Err(e) = verify_signature(pub_address.clone(), signature, message_signed) {
-        //     eprintln!("Signature verification failed: {}", e);
-        //     return;
-        // }
+        // Teacher and enrollments come from the chain, everything else from the delegation list
+        let Some(role) = RoomManager::instance()
+            .resolve_role(course_id, &pub_address)
+            .await
+        else {
+            eprintln!("{} is not enrolled in {}", pub_address, course_id);
+            user_arc.lock().await.reject_join(course_id, "not_enrolled");
+            return;
+        };
+
+        let user_id = match session::participant_for(pub_address, payload.device_id) {
+            Ok(user_id) => user_id,
+            Err(reason) => {
+                user_arc.lock().await.reject_join(course_id, reason);
+                return;
+            }
+        };
+        if let Err(reason) = session::resolve_duplicate_login(course_id, &user_id).await {
+            eprintln!("{} can't join room {}: {}", user_id, course_id, reason);
+            user_arc.lock().await.reject_join(course_id, reason);
+            return;
+        }
 
-        // TODO check if user is enrolled by calling is_enrolled view function in smart contract
-        // const isEnrolled = contract.query.isEnrolled(course_id, pub_address);
-        // if(!isEnrolled) eprintln!("You havent enrolled in: {}", e); return;
+        // With the waiting room on, everyone but the teachers waits to be let in
+        if !role.can(lobby::ADMIT_PERMISSION)
+            && RoomManager::instance()
+                .waiting_room_enabled(course_id)
+                .await
+        {
+            lobby::wait(user_arc, course_id, user_id, role).await;
+            return;
+        }
+        Self::enter_room(user_arc, course_id, user_id, role).await;
+    }
 
+    /// Puts an authenticated user into the room and sends them the room snapshot.
+    pub(crate) async fn enter_room(
+        user_arc: Arc<Mutex<Self>>,
+        course_id: u32,
+        user_id: ParticipantId,
+        role: Role,
+    ) {
         let mut user = user_arc.lock().await;
-        if user.id.is_none() {
-            user.id = Some(pub_address.clone());
+        if user.id.as_ref().is_some_and(|id| id != &user_id) {
+            user.reject_join(course_id, "identity_mismatch");
+            return;
         }
-        user.room_id = Some(course_id);
-        user.coordinates = get_rand_coordinates();
+        user.id = Some(user_id.clone());
 
-        RoomManager::instance()
-            .add_user_to_room(payload.course_id, user_arc.clone())
+        // Claiming the id can still fail if another tab got in since the check above
+        if !RoomManager::instance()
+            .add_user_to_room(course_id, &user_id, user_arc.clone())
+            .await
+        {
+            user.reject_join(course_id, "already_signed_in");
+            return;
+        }
+        if let Err(reason) = RoomManager::instance()
+            .claim_seat(course_id, &user_id, role)
+            .await
+        {
+            eprintln!("{} can't join room {}: {}", user_id, course_id, reason);
+            RoomManager::instance()
+                .remove_user_from_room(course_id, user_id, user.coordinates)
+                .await;
+            user.reject_join(course_id, reason);
+            return;
+        }
+        let Some(coordinates) = RoomManager::instance()
+            .spawn_user(course_id, &user_id)
+            .await
+        else {
+            eprintln!("No free spawn tile in room {}", course_id);
+            RoomManager::instance()
+                .remove_user_from_room(course_id, user_id, user.coordinates)
+                .await;
+            user.reject_join(course_id, "no_free_spawn");
+            return;
+        };
+        user.room_id = Some(course_id);
+        user.coordinates = coordinates;
+        user.role = Some(role);
+        let session_token = RoomManager::instance()
+            .issue_session(course_id, &user_id)
             .await;
+        user.session_token = Some(session_token.clone());
+        attendance::record(course_id, &user_id, Some(role), AttendanceEventKind::Join).await;
         println!("User added to room");
         println!("{:?} {:?} {:?}", user.id, user.room_id, user.coordinates);
 
+        // Tell the joining user where they spawned and what the room looks like
+        let map = RoomManager::instance()
+            .room(course_id)
+            .await
+            .map(|room| serde_json::to_value(&room.map).unwrap_or_default());
+        let board = RoomManager::instance().whiteboard_json(course_id).await;
+        let presentation = RoomManager::instance().presentation(course_id).await;
+        let joined_message = serde_json::json!({
+            "type": "room_joined",
+            "user_id": user_id,
+            "device_id": user_id.device(),
+            "role": role,
+            "coordinates": coordinates,
+            "map": map,
+            "session_token": session_token,
+            "chat_history": recent_chat(course_id, &user_id),
+            "open_polls": polls::open_polls(course_id).await,
+            "whiteboard": board,
+            "presentation": presentation
+        });
+        user.send(joined_message.to_string());
+        drop(user);
+
+        // Positions are only sent to users that have the newcomer in view, through view_enter
         let join_message = serde_json::json!({
             "type": "user_joined",
-            "user_id": user.id,
-            "coordinates": user.coordinates
+            "user_id": user_id,
+            "role": role
         });
 
         RoomManager::instance()
-            .broadcast_message(user.id.clone(), course_id, join_message.to_string())
+            .broadcast_message(Some(user_id.clone()), course_id, join_message.to_string())
             .await;
         print!("broadcasted to everyone");
+
+        Self::broadcast_zone_change(course_id, &user_id, None, coordinates).await;
+        RoomManager::instance().refresh_audio_routing(course_id).await;
+        // Staff joining mid-session get the hands that are already up
+        if role.can(hand_raise::STAFF_PERMISSION) {
+            hand_raise::send_queue(course_id).await;
+        }
+        // And whoever is waiting to be let in
+        if role.can(lobby::ADMIT_PERMISSION) {
+            lobby::send_pending(course_id).await;
+        }
     }
-    async fn handle_leave_room(user_arc: Arc<Mutex<Self>>) {
-        let (room_id, user_id) = {
-            let user = user_arc.lock().await;
-            (user.room_id, user.id.clone())
+    pub(crate) fn can(&self, permission: Permission) -> bool {
+        self.role.map(|role| role.can(permission)).unwrap_or(false)
+    }
+
+    pub(crate) fn reject_action(&self, action: &str, reason: &str) {
+        let reject_message = serde_json::json!({
+            "type": "action_rejected",
+            "action": action,
+            "reason": reason
+        });
+        self.send(reject_message.to_string());
+    }
+
+    /// Switches the role of a user who is already in the room.
+    pub(crate) async fn assign_role(&mut self, room_id: u32, role: Role) {
+        self.role = Some(role);
+        if let Some(user_id) = self.id.as_ref() {
+            RoomManager::instance()
+                .set_user_role(room_id, user_id, role)
+                .await;
+        }
+        let role_message = serde_json::json!({
+            "type": "role_changed",
+            "role": role
+        });
+        self.send(role_message.to_string());
+    }
+
+    pub(crate) fn reject_reconnect(&self, reason: &str) {
+        let reject_message = serde_json::json!({
+            "type": "reconnect_rejected",
+            "reason": reason
+        });
+        self.send(reject_message.to_string());
+    }
+
+    /// Tells this connection a newer login took its place, then closes its socket.
+    pub(crate) fn sign_out_elsewhere(&self) {
+        let notice = serde_json::json!({
+            "type": "signed_in_elsewhere"
+        });
+        self.send(notice.to_string());
+        self.close_socket();
+    }
+
+    /// The outgoing side of this user's socket, for handing over on reconnect.
+    pub(crate) fn connection(&self) -> (mpsc::UnboundedSender<Message>, u64) {
+        (self.outbound.clone(), self.connection_id)
+    }
+
+    /// Attaches a new socket to this user and brings the client back up to date: position,
+    /// role, and the producers and consumers it still has. The transport gets an ICE restart
+    /// so the client can renegotiate instead of producing and consuming everything again.
+    /// If that isn't possible the media is released and the client starts over with
+    /// `webrtc_init`; the ids of the producers that went away are returned.
+    pub(crate) async fn resume_session(
+        &mut self,
+        (outbound, connection_id): (mpsc::UnboundedSender<Message>, u64),
+        session_token: String,
+    ) -> HashSet<String> {
+        self.outbound = outbound;
+        self.connection_id = connection_id;
+        self.reconnecting = false;
+        self.away = false;
+        self.session_token = Some(session_token.clone());
+        // Make the next tick and audio refresh send everything again
+        self.visible_users.clear();
+        self.audible_peers.clear();
+
+        let ice_parameters = match self.transport.as_ref() {
+            Some(transport) if !transport.closed() => match transport.restart_ice().await {
+                Ok(ice_parameters) => Some(ice_parameters),
+                Err(e) => {
+                    eprintln!("Failed to restart ICE for {:?}: {}", self.id, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        // Producers and consumers are useless to a client that can't reach the transport
+        let closed_producers = match ice_parameters {
+            Some(_) => HashSet::new(),
+            None => self.release_media(),
+        };
+        let producers: Vec<_> = self
+            .producers
+            .values()
+            .map(|producer| serde_json::json!({ "id": producer.id(), "kind": producer.kind() }))
+            .collect();
+        let consumers: Vec<_> = self
+            .consumers
+            .values()
+            .map(|consumer| {
+                serde_json::json!({
+                    "id": consumer.id(),
+                    "producer_id": consumer.producer_id(),
+                    "kind": consumer.kind()
+                })
+            })
+            .collect();
+
+        let map = match self.room_id {
+            Some(room_id) => RoomManager::instance()
+                .room(room_id)
+                .await
+                .map(|room| serde_json::to_value(&room.map).unwrap_or_default()),
+            None => None,
+        };
+        let chat_history = match (self.room_id, self.id.as_ref()) {
+            (Some(room_id), Some(user_id)) => recent_chat(room_id, user_id),
+            _ => vec![],
+        };
+        let open_polls = match self.room_id {
+            Some(room_id) => polls::open_polls(room_id).await,
+            None => vec![],
+        };
+        let (board, presentation) = match self.room_id {
+            Some(room_id) => (
+                RoomManager::instance().whiteboard_json(room_id).await,
+                RoomManager::instance().presentation(room_id).await,
+            ),
+            None => (None, None),
+        };
+        let reconnected_message = serde_json::json!({
+            "type": "reconnected",
+            "user_id": self.id,
+            "role": self.role,
+            "coordinates": self.coordinates,
+            "map": map,
+            "session_token": session_token,
+            "ice_parameters": ice_parameters,
+            "producers": producers,
+            "consumers": consumers,
+            "chat_history": chat_history,
+            "open_polls": open_polls,
+            "whiteboard": board,
+            "presentation": presentation
+        });
+        self.send(reconnected_message.to_string());
+        closed_producers
+    }
+
+    pub(crate) fn reject_join(&self, course_id: u32, reason: &str) {
+        metrics::JOIN_FAILURES.inc(reason);
+        let reject_message = serde_json::json!({
+            "type": "join_rejected",
+            "course_id": course_id,
+            "reason": reason
+        });
+        self.send(reject_message.to_string());
+    }
+
+    /// Every way out of a room ends up here: leaving, being kicked, or not reconnecting in time.
+    pub(crate) async fn handle_leave_room(user_arc: Arc<Mutex<Self>>) {
+        if lobby::leave(&user_arc).await {
+            return;
+        }
+        let (room_id, user_id, coordinates) = {
+            let mut user = user_arc.lock().await;
+            if let Some(session_token) = user.session_token.take() {
+                RoomManager::instance().revoke_session(&session_token).await;
+            }
+            (user.room_id, user.id.clone(), user.coordinates)
         };
 
         if let (Some(room_id), Some(user_id)) = (room_id, user_id) {
+            hand_raise::user_left(room_id, &user_id).await;
             RoomManager::instance()
-                .remove_user_from_room(room_id, user_id.clone())
+                .remove_user_from_room(room_id, user_id.clone(), coordinates)
                 .await;
+            attendance::record(room_id, &user_id, None, AttendanceEventKind::Leave).await;
 
             let leave_message = serde_json::json!({
                 "type": "user_left",
@@ -197,102 +818,718 @@ impl User {
                 .broadcast_message(Some(user_id), room_id, leave_message.to_string())
                 .await;
 
-            user_arc.lock().await.room_id = None;
+            let mut user = user_arc.lock().await;
+            user.room_id = None;
+            user.role = None;
+            user.reconnecting = false;
+            user.away = false;
+            user.move_queue.clear();
+            user.visible_users.clear();
+            user.audible_peers.clear();
+            let closed_producers = user.release_media();
+            drop(user);
+
+            RoomManager::instance()
+                .close_consumers_of(room_id, &closed_producers)
+                .await;
+            RoomManager::instance().refresh_audio_routing(room_id).await;
+            attendance::end_session_if_empty(room_id).await;
         }
     }
 
-    async fn handle_move_to(user_arc: Arc<Mutex<Self>>, coordinates: MovementPayload) {
-        let (room_id, user_id, valid_move) = {
+    /// Moves a user between a classroom and its breakout rooms. Their media is torn down,
+    /// the client sets it up again against the new room's router.
+    pub(crate) async fn move_to_room(
+        user_arc: Arc<Mutex<Self>>,
+        to: u32,
+    ) -> Result<(), &'static str> {
+        let (from, user_id, role, coordinates, session_token) = {
             let mut user = user_arc.lock().await;
-            let x_displacement = (user.coordinates.0 - coordinates.x).abs();
-            let y_displacement = (user.coordinates.1 - coordinates.y).abs();
-            let valid_move = (x_displacement == 1 && y_displacement == 0)
-                || (x_displacement == 0 && y_displacement == 1);
+            let (Some(from), Some(user_id), Some(role)) =
+                (user.room_id, user.id.clone(), user.role)
+            else {
+                return Err("not_in_room");
+            };
+            user.move_queue.clear();
+            (
+                from,
+                user_id,
+                role,
+                user.coordinates,
+                user.session_token.clone(),
+            )
+        };
+        if from == to {
+            return Ok(());
+        }
 
-            if valid_move {
-                user.coordinates = (coordinates.x, coordinates.y);
-            }
+        let room_manager = RoomManager::instance();
+        if !room_manager
+            .add_user_to_room(to, &user_id, user_arc.clone())
+            .await
+        {
+            return Err("already_in_room");
+        }
+        let Some(spawn) = room_manager.spawn_user(to, &user_id).await else {
+            room_manager
+                .remove_user_from_room(to, user_id, coordinates)
+                .await;
+            return Err("no_free_spawn");
+        };
+        room_manager.set_user_role(to, &user_id, role).await;
+        hand_raise::user_left(from, &user_id).await;
+        room_manager
+            .remove_user_from_room(from, user_id.clone(), coordinates)
+            .await;
+        if let Some(session_token) = session_token.as_ref() {
+            room_manager.rebind_session(session_token, to).await;
+        }
 
-            (user.room_id, user.id.clone(), valid_move)
+        let room = room_manager.room(to).await;
+        let changed_message = serde_json::json!({
+            "type": "room_changed",
+            "room_id": to,
+            "parent": room.as_ref().and_then(|room| room.parent),
+            "name": room.as_ref().map(|room| room.name.clone()),
+            "coordinates": spawn,
+            "map": room.map(|room| serde_json::to_value(&room.map).unwrap_or_default()),
+            "chat_history": recent_chat(to, &user_id),
+            "open_polls": polls::open_polls(to).await,
+            "whiteboard": room_manager.whiteboard_json(to).await,
+            "presentation": room_manager.presentation(to).await
+        });
+        let closed_producers = {
+            let mut user = user_arc.lock().await;
+            user.room_id = Some(to);
+            user.coordinates = spawn;
+            user.move_queue.clear();
+            user.visible_users.clear();
+            user.audible_peers.clear();
+            user.send(changed_message.to_string());
+            user.release_media()
         };
 
-        if let (Some(room_id), Some(user_id)) = (room_id, user_id) {
-            if valid_move {
-                let move_message = serde_json::json!({
-                    "type": "user_moved",
-                    "user_id": user_id,
-                    "coordinates": coordinates
-                });
+        let left_message = serde_json::json!({
+            "type": "user_left",
+            "user_id": user_id
+        });
+        room_manager
+            .broadcast_message(None, from, left_message.to_string())
+            .await;
+        let joined_message = serde_json::json!({
+            "type": "user_joined",
+            "user_id": user_id,
+            "role": role
+        });
+        room_manager
+            .broadcast_message(Some(user_id.clone()), to, joined_message.to_string())
+            .await;
 
-                RoomManager::instance()
-                    .broadcast_message(Some(user_id), room_id, move_message.to_string())
-                    .await;
+        room_manager
+            .close_consumers_of(from, &closed_producers)
+            .await;
+        room_manager.refresh_audio_routing(from).await;
+        Self::broadcast_zone_change(to, &user_id, None, spawn).await;
+        room_manager.refresh_audio_routing(to).await;
+        if role.can(hand_raise::STAFF_PERMISSION) {
+            hand_raise::send_queue(to).await;
+        }
+        Ok(())
+    }
+
+    // Moves are only queued here, the room tick validates and applies them
+    async fn handle_move_to(user_arc: Arc<Mutex<Self>>, coordinates: MovementPayload) {
+        let mut user = user_arc.lock().await;
+        if user.room_id.is_none() {
+            return;
+        }
+
+        let target = (coordinates.x, coordinates.y);
+        let last = user.move_queue.back().copied().unwrap_or(user.coordinates);
+        // Anything but a single step from where the user will be is a teleport attempt,
+        // and a full queue means the client is sending faster than the speed limit
+        if !ClassroomMap::is_adjacent(last, target) || user.move_queue.len() >= MAX_QUEUED_MOVES {
+            user.reject_movement();
+            return;
+        }
+        user.move_queue.push_back(target);
+    }
+
+    /// Drops any queued steps and sends the client back to the position the server holds.
+    /// Every movement correction goes through here.
+    pub(crate) fn reject_movement(&mut self) {
+        metrics::REJECTED_MOVES.inc();
+        self.move_queue.clear();
+        let reject_message = serde_json::json!({
+            "type": "movement_rejected",
+            "user_id": self.id,
+            "coordinates": self.coordinates
+        });
+        self.send(reject_message.to_string());
+    }
+
+    // Sends zone_left/zone_entered to the whole room when a step crosses a zone boundary
+    pub(crate) async fn broadcast_zone_change(
+        room_id: u32,
+        user_id: &ParticipantId,
+        from: Option<(i32, i32)>,
+        to: (i32, i32),
+    ) {
+        let room_manager = RoomManager::instance();
+        let old_zone = match from {
+            Some(from) => room_manager.zone_at(room_id, from).await,
+            None => None,
+        };
+        let new_zone = room_manager.zone_at(room_id, to).await;
+        if old_zone.as_ref().map(|zone| &zone.id) == new_zone.as_ref().map(|zone| &zone.id) {
+            return;
+        }
+
+        if let Some(zone) = old_zone {
+            let left_message = serde_json::json!({
+                "type": "zone_left",
+                "user_id": user_id,
+                "zone_id": zone.id,
+                "zone_kind": zone.kind
+            });
+            room_manager
+                .broadcast_message(None, room_id, left_message.to_string())
+                .await;
+        }
+        if let Some(zone) = new_zone {
+            let entered_message = serde_json::json!({
+                "type": "zone_entered",
+                "user_id": user_id,
+                "zone_id": zone.id,
+                "zone_kind": zone.kind
+            });
+            room_manager
+                .broadcast_message(None, room_id, entered_message.to_string())
+                .await;
+        }
+    }
+
+    /// Pauses audio consumers of users that are no longer audible, resumes the
+    /// ones that are, and tells the client who it should be consuming.
+    pub(crate) async fn update_audio_routing(
+        &mut self,
+        audible: HashSet<ParticipantId>,
+        stream_owners: &HashMap<String, ParticipantId>,
+    ) {
+        for consumer in self.consumers.values() {
+            if consumer.kind() != MediaKind::Audio {
+                continue;
+            }
+            let Some(owner) = stream_owners.get(&consumer.producer_id().to_string()) else {
+                continue;
+            };
+            let should_hear = audible.contains(owner);
+            if should_hear != consumer.paused() {
+                continue;
+            }
+            let result = if should_hear {
+                consumer.resume().await
             } else {
-                let reject_message = serde_json::json!({
-                    "type": "movement_rejected",
-                    "user_id": user_id,
-                    "coordinates": coordinates
-                });
+                consumer.pause().await
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to update audio consumer {}: {}", consumer.id(), e);
+            }
+        }
 
-                RoomManager::instance()
-                    .broadcast_message(Some(user_id), room_id, reject_message.to_string())
-                    .await;
+        if audible != self.audible_peers {
+            let peers_message = serde_json::json!({
+                "type": "audio_peers",
+                "user_ids": audible
+            });
+            self.send(peers_message.to_string());
+            self.audible_peers = audible;
+        }
+    }
+
+    /// Closes this user's transport along with its producers and consumers,
+    /// and returns the ids of the producers that went away.
+    pub(crate) fn release_media(&mut self) -> HashSet<String> {
+        let closed_producers = self.producers.keys().cloned().collect();
+        // mediasoup closes producers, consumers and transports when they're dropped
+        self.consumers.clear();
+        self.producers.clear();
+        self.transport = None;
+        closed_producers
+    }
+
+    /// How many producers and consumers this user has on the room's router.
+    pub(crate) fn media_counts(&self) -> (usize, usize) {
+        (self.producers.len(), self.consumers.len())
+    }
+
+    /// Drops consumers of producers that were closed and tells the client which ones.
+    pub(crate) fn close_consumers_of(&mut self, producer_ids: &HashSet<String>) {
+        let closed: Vec<String> = self
+            .consumers
+            .iter()
+            .filter(|(_, consumer)| producer_ids.contains(&consumer.producer_id().to_string()))
+            .map(|(consumer_id, _)| consumer_id.clone())
+            .collect();
+        for consumer_id in closed {
+            let Some(consumer) = self.consumers.remove(&consumer_id) else {
+                continue;
+            };
+            let closed_message = serde_json::json!({
+                "type": "consumer_closed",
+                "consumer_id": consumer_id,
+                "producer_id": consumer.producer_id()
+            });
+            self.send(closed_message.to_string());
+        }
+    }
+
+    /// Ids of this user's producers of the given kind that aren't paused.
+    pub(crate) fn live_producers(&self, kind: MediaKind) -> Vec<String> {
+        self.producers
+            .iter()
+            .filter(|(_, producer)| producer.kind() == kind && !producer.paused())
+            .map(|(producer_id, _)| producer_id.clone())
+            .collect()
+    }
+
+    /// Pauses this user's producers of the given kind on behalf of the teacher.
+    pub(crate) async fn force_pause(&mut self, kind: MediaKind) {
+        for producer in self.producers.values() {
+            if producer.kind() != kind {
+                continue;
+            }
+            if let Err(e) = producer.pause().await {
+                eprintln!("Failed to pause producer {}: {}", producer.id(), e);
             }
         }
+
+        let notice = serde_json::json!({
+            "type": "media_disabled",
+            "kind": kind
+        });
+        self.send(notice.to_string());
+    }
+
+    // One transport per user carries everything it sends and receives
+    async fn handle_webrtc_init(user_arc: Arc<Mutex<Self>>) {
+        let mut user = user_arc.lock().await;
+        let (Some(room_id), Some(user_id)) = (user.room_id, user.id.clone()) else {
+            user.reject_action("webrtc_init", "not_in_room");
+            return;
+        };
+        // A transport that died with its worker is replaced along with its media, a live one is kept
+        let stale_producers = match user.transport.as_ref() {
+            Some(transport) if !transport.closed() => {
+                user.reject_action("webrtc_init", "transport_exists");
+                return;
+            }
+            Some(_) => user.release_media(),
+            None => HashSet::new(),
+        };
+
+        let room_manager = RoomManager::instance();
+        let transport = match room_manager.create_transport(room_id).await {
+            Ok(transport) => transport,
+            Err(reason) => {
+                user.reject_action("webrtc_init", reason);
+                return;
+            }
+        };
+        // What the room is already sending, consumed like any producer announced later
+        let producers: Vec<_> = room_manager
+            .streams(room_id)
+            .await
+            .iter()
+            .filter(|(_, info)| info.user_id != user_id)
+            .map(|(producer_id, info)| info.to_json(producer_id))
+            .collect();
+        let init_message = serde_json::json!({
+            "type": "webrtc_initialized",
+            "transport_id": transport.id(),
+            "ice_parameters": transport.ice_parameters(),
+            "ice_candidates": transport.ice_candidates(),
+            "dtls_parameters": transport.dtls_parameters(),
+            "rtp_capabilities": room_manager.rtp_capabilities(room_id).await,
+            "producers": producers
+        });
+        user.transport = Some(transport);
+        user.send(init_message.to_string());
+        drop(user);
+
+        room_manager
+            .close_consumers_of(room_id, &stale_producers)
+            .await;
     }
+
     async fn handle_connect_transport(
         user_arc: Arc<Mutex<Self>>,
         transport_options: TransportOptions,
     ) {
+        let user = user_arc.lock().await;
+        let Some(transport) = user
+            .transport
+            .as_ref()
+            .filter(|transport| transport.id().to_string() == transport_options.id)
+        else {
+            user.reject_action("connect_transport", "transport_not_found");
+            return;
+        };
+        let remote_parameters = WebRtcTransportRemoteParameters {
+            dtls_parameters: transport_options.dtls_parameters,
+        };
+        if let Err(e) = transport.connect(remote_parameters).await {
+            eprintln!("Failed to connect transport {}: {}", transport.id(), e);
+            user.reject_action("connect_transport", "connect_failed");
+            return;
+        }
+        let connected_message = serde_json::json!({
+            "type": "transport_connected",
+            "transport_id": transport.id()
+        });
+        user.send(connected_message.to_string());
+    }
+
+    async fn handle_produce(user_arc: Arc<Mutex<Self>>, produce_payload: ProducePayload) {
+        let mut user = user_arc.lock().await;
+        let stream_type = produce_payload
+            .stream_type
+            .unwrap_or(StreamType::default_for(produce_payload.kind));
+        let permission = match stream_type {
+            StreamType::Screen => Permission::ShareScreen,
+            _ => Permission::Produce,
+        };
+        if !user.can(permission) {
+            user.reject_action("produce", "not_permitted");
+            return;
+        }
+        let (Some(room_id), Some(user_id)) = (user.room_id, user.id.clone()) else {
+            user.reject_action("produce", "not_in_room");
+            return;
+        };
+        let Some(transport) = user.transport.clone() else {
+            user.reject_action("produce", "no_transport");
+            return;
+        };
+
+        let options = ProducerOptions::new(produce_payload.kind, produce_payload.rtp_parameters);
+        let producer = match transport.produce(options).await {
+            Ok(producer) => producer,
+            Err(e) => {
+                eprintln!("Failed to produce for {}: {}", user_id, e);
+                user.reject_action("produce", "produce_failed");
+                return;
+            }
+        };
+        let producer_id = producer.id().to_string();
+        let info = StreamInfo::new(user_id.clone(), producer.kind(), stream_type);
+        let new_producer_message = serde_json::json!({
+            "type": "new_producer",
+            "producer": info.to_json(&producer_id)
+        });
+        user.producers.insert(producer_id.clone(), producer);
+        let produced_message = serde_json::json!({
+            "type": "produced",
+            "producer_id": producer_id
+        });
+        user.send(produced_message.to_string());
+        drop(user);
+
+        let room_manager = RoomManager::instance();
+        room_manager.add_stream(room_id, producer_id, info).await;
+        room_manager
+            .broadcast_message(Some(user_id), room_id, new_producer_message.to_string())
+            .await;
+    }
+
+    async fn handle_consume(user_arc: Arc<Mutex<Self>>, consume_payload: ConsumePayload) {
         let mut user = user_arc.lock().await;
+        let Some(room_id) = user.room_id else {
+            user.reject_action("consume", "not_in_room");
+            return;
+        };
+        let Some(transport) = user.transport.clone() else {
+            user.reject_action("consume", "no_transport");
+            return;
+        };
+
+        let room_manager = RoomManager::instance();
+        // Only producers in this room, they're the only ones its router can forward
+        let producer_id = match consume_payload.producer_id.parse::<ProducerId>() {
+            Ok(producer_id)
+                if room_manager
+                    .stream_owner(room_id, &consume_payload.producer_id)
+                    .await
+                    .is_some() =>
+            {
+                producer_id
+            }
+            _ => {
+                user.reject_action("consume", "producer_not_found");
+                return;
+            }
+        };
+        if !room_manager
+            .can_consume(room_id, &producer_id, &consume_payload.rtp_capabilities)
+            .await
+        {
+            user.reject_action("consume", "unsupported_codec");
+            return;
+        }
 
-        unimplemented!("Connect transport not implemented yet");
+        let mut options = ConsumerOptions::new(producer_id, consume_payload.rtp_capabilities);
+        // Nothing flows until the client is ready to play it and sends `resume`
+        options.paused = true;
+        let consumer = match transport.consume(options).await {
+            Ok(consumer) => consumer,
+            Err(e) => {
+                eprintln!("Failed to consume {}: {}", consume_payload.producer_id, e);
+                user.reject_action("consume", "consume_failed");
+                return;
+            }
+        };
+        let consumed_message = serde_json::json!({
+            "type": "consumed",
+            "consumer_id": consumer.id(),
+            "producer_id": consumer.producer_id(),
+            "kind": consumer.kind(),
+            "rtp_parameters": consumer.rtp_parameters()
+        });
+        user.consumers.insert(consumer.id().to_string(), consumer);
+        user.send(consumed_message.to_string());
     }
-    async fn handle_webrtc_init(user_arc: Arc<Mutex<Self>>) {
-        unimplemented!("WebRTC not implemented yet");
+
+    async fn handle_resume(user_arc: Arc<Mutex<Self>>, resume_payload: ResumePayload) {
+        let user = user_arc.lock().await;
+        let Some(consumer) = user.consumers.get(&resume_payload.consumer_id) else {
+            user.reject_action("resume", "consumer_not_found");
+            return;
+        };
+        // Audio from someone out of earshot stays paused, audio routing resumes it once they're in range
+        if consumer.kind() == MediaKind::Audio {
+            let owner = match user.room_id {
+                Some(room_id) => {
+                    RoomManager::instance()
+                        .stream_owner(room_id, &consumer.producer_id().to_string())
+                        .await
+                }
+                None => None,
+            };
+            if !owner.is_some_and(|owner| user.audible_peers.contains(&owner)) {
+                return;
+            }
+        }
+        if let Err(e) = consumer.resume().await {
+            eprintln!("Failed to resume consumer {}: {}", consumer.id(), e);
+            user.reject_action("resume", "resume_failed");
+        }
     }
-    async fn handle_produce(user_arc: Arc<Mutex<Self>>, produce_payload: ProducePayload) {
-        unimplemented!("Produce not implemented yet");
+
+    // Who a message reaches depends on its scope, see `RoomManager::chat_delivery`
+    async fn handle_send_message(user_arc: Arc<Mutex<Self>>, payload: SendMessagePayload) {
+        let (room_id, user_id, coordinates, view_radius) = {
+            let mut user = user_arc.lock().await;
+            let permission = match payload.scope {
+                ChatScope::Announcement => Permission::Announce,
+                _ => Permission::Chat,
+            };
+            if !user.can(permission) {
+                user.reject_action("send_message", "not_permitted");
+                return;
+            }
+            if !user.chat_limiter.try_acquire() {
+                user.reject_action("send_message", "rate_limited");
+                return;
+            }
+            (user.room_id, user.id.clone(), user.coordinates, user.view_radius)
+        };
+
+        if let (Some(room_id), Some(user_id)) = (room_id, user_id) {
+            let room_manager = RoomManager::instance();
+            if room_manager.is_chat_muted(room_id, user_id.account()).await {
+                user_arc
+                    .lock()
+                    .await
+                    .reject_action("send_message", "chat_muted");
+                return;
+            }
+            let content = match chat::check_content(&payload.content) {
+                Ok(content) => content,
+                Err(reason) => {
+                    user_arc.lock().await.reject_action("send_message", reason);
+                    return;
+                }
+            };
+
+            let delivery = match room_manager
+                .chat_delivery(
+                    room_id,
+                    &user_id,
+                    coordinates,
+                    view_radius,
+                    payload.scope,
+                    payload.target,
+                )
+                .await
+            {
+                Ok(delivery) => delivery,
+                Err(reason) => {
+                    user_arc.lock().await.reject_action("send_message", reason);
+                    return;
+                }
+            };
+
+            let recorded = match chat::record(
+                room_id,
+                user_id,
+                payload.scope,
+                content,
+                &delivery,
+            ) {
+                Ok(recorded) => recorded,
+                Err(e) => {
+                    eprintln!("Failed to record chat message in room {}: {}", room_id, e);
+                    user_arc
+                        .lock()
+                        .await
+                        .reject_action("send_message", "chat_unavailable");
+                    return;
+                }
+            };
+            let message_payload = recorded.to_json().to_string();
+
+            match delivery.recipients {
+                Some(mut recipients) => {
+                    // The sender gets it back too, with the id and timestamp the server assigned
+                    recipients.insert(recorded.sender);
+                    room_manager
+                        .send_to_users(room_id, &recipients, message_payload)
+                        .await;
+                }
+                None => {
+                    room_manager
+                        .broadcast_message(None, room_id, message_payload)
+                        .await;
+                }
+            }
+        }
     }
-    async fn handle_consume(user_arc: Arc<Mutex<Self>>, consume_payload: ConsumePayload) {
-        unimplemented!("Consume not implemented yet");
+
+    // Removes a message from everyone's screen and from the room's history
+    async fn handle_delete_message(user_arc: Arc<Mutex<Self>>, payload: DeleteMessagePayload) {
+        let room_id = {
+            let user = user_arc.lock().await;
+            if !user.can(Permission::ManageRoom) {
+                user.reject_action("delete_message", "not_permitted");
+                return;
+            }
+            let Some(room_id) = user.room_id else {
+                return;
+            };
+            match chat::delete(room_id, payload.message_id) {
+                Ok(true) => {}
+                Ok(false) => {
+                    user.reject_action("delete_message", "message_not_found");
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Failed to delete chat message in room {}: {}", room_id, e);
+                    user.reject_action("delete_message", "chat_unavailable");
+                    return;
+                }
+            }
+            println!(
+                "{:?} deleted message {} in room {}",
+                user.id, payload.message_id, room_id
+            );
+            room_id
+        };
+
+        let deleted_message = serde_json::json!({
+            "type": "message_deleted",
+            "message_id": payload.message_id
+        });
+        RoomManager::instance()
+            .broadcast_message(None, room_id, deleted_message.to_string())
+            .await;
     }
-    async fn handle_resume(user_arc: Arc<Mutex<Self>>, consumer_id: ResumePayload) {
-        unimplemented!("Resume not implemented yet");
+
+    // Pages back through the room's chat log, only returning what this user could have seen
+    async fn handle_chat_history(user_arc: Arc<Mutex<Self>>, payload: ChatHistoryPayload) {
+        let user = user_arc.lock().await;
+        let (Some(room_id), Some(user_id)) = (user.room_id, user.id.as_ref()) else {
+            return;
+        };
+        let limit = payload.limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        match chat::history(room_id, user_id, payload.before, limit) {
+            Ok((messages, has_more)) => {
+                let history_message = serde_json::json!({
+                    "type": "chat_history",
+                    "messages": messages
+                        .iter()
+                        .map(|message| message.to_json())
+                        .collect::<Vec<_>>(),
+                    "has_more": has_more
+                });
+                user.send(history_message.to_string());
+            }
+            Err(e) => {
+                eprintln!("Failed to read chat history for room {}: {}", room_id, e);
+                user.reject_action("chat_history", "chat_unavailable");
+            }
+        }
     }
 
-    async fn handle_send_message(user_arc: Arc<Mutex<Self>>, message: String) {
+    // Only toggles the room's recording state and lets everyone know, capture happens elsewhere
+    async fn handle_set_recording(user_arc: Arc<Mutex<Self>>, recording: bool) {
         let (room_id, user_id) = {
             let user = user_arc.lock().await;
+            if !user.can(Permission::Record) {
+                user.reject_action("set_recording", "not_permitted");
+                return;
+            }
             (user.room_id, user.id.clone())
         };
 
         if let (Some(room_id), Some(user_id)) = (room_id, user_id) {
-            let message_payload = serde_json::json!({
-                "type": "message",
-                "sender": user_id.clone(),
-                "content": message
-            });
+            RoomManager::instance()
+                .set_recording(room_id, recording)
+                .await;
 
+            let recording_message = serde_json::json!({
+                "type": "recording",
+                "active": recording,
+                "by": user_id
+            });
             RoomManager::instance()
-                .broadcast_message(Some(user_id), room_id, message_payload.to_string())
+                .broadcast_message(None, room_id, recording_message.to_string())
                 .await;
         }
     }
 }
 
-fn verify_signature(
-    pub_address: String,
+// Latest messages for join and reconnect snapshots
+fn recent_chat(room_id: u32, user_id: &ParticipantId) -> Vec<serde_json::Value> {
+    match chat::history(room_id, user_id, None, JOIN_SNAPSHOT_SIZE) {
+        Ok((messages, _)) => messages.iter().map(|message| message.to_json()).collect(),
+        Err(e) => {
+            eprintln!("Failed to read chat history for room {}: {}", room_id, e);
+            vec![]
+        }
+    }
+}
+
+pub(crate) fn verify_signature(
+    pub_address: &AccountIdentity,
     signature: String,
     message_signed: String,
 ) -> Result<(), &'static str> {
     // Signature in bytes
-    let signature_bytes = hex::decode(signature).expect("Invalid hex for signature");
-    let signature = sr25519::Signature::from_slice(&signature_bytes).expect("Invalid signature");
+    let signature_bytes = hex::decode(signature).map_err(|_| "Invalid hex for signature")?;
+    let signature = sr25519::Signature::try_from(signature_bytes.as_slice())
+        .map_err(|_| "Invalid signature")?;
 
-    // Public key in bytes
-    let public_key_bytes = hex::decode(pub_address).expect("Invalid hex for public key");
-    let public_key = sr25519::Public::from_slice(&public_key_bytes).expect("Invalid public key");
+    let public_key = pub_address.public_key();
 
     // Verify the signature
     let is_valid = sr25519::Pair::verify(&signature, message_signed, &public_key);
@@ -304,17 +1541,3 @@ fn verify_signature(
     }
 }
 
-fn get_rand_coordinates() -> (i32, i32) {
-    let mut rng = rand::thread_rng();
-    (rng.gen_range(0..100), rng.gen_range(0..100))
-}
-// impl Drop for User {
-//     fn drop(&mut self) {
-//         self.producers.clear();
-//         self.consumers.clear();
-//
-//         if let Some(mut transport) = self.transport.take() {
-//             let _ = transport.close();
-//         }
-//     }
-// }
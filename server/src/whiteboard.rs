@@ -0,0 +1,572 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::sync::{Arc, OnceLock};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::identity::ParticipantId;
+use crate::roles::{Permission, Role};
+use crate::room_manager::RoomManager;
+use crate::user::User;
+use crate::ws_payload::WhiteboardModePayload;
+
+// Embedded database with the latest snapshot of every room's board, keyed by room id
+const WHITEBOARD_DB_PATH: &str = "whiteboard_db";
+const MAX_ELEMENTS: usize = 5000;
+const MAX_POINTS: usize = 5000;
+const MAX_TEXT_LENGTH: usize = 1000;
+const MAX_ID_LENGTH: usize = 64;
+const MAX_COLOR_LENGTH: usize = 32;
+// Who can draw in teacher-only mode, and who can clear the board or change the mode
+const BOARD_ADMIN_PERMISSION: Permission = Permission::ManageRoom;
+
+static WHITEBOARD_DB: OnceLock<sled::Db> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrawMode {
+    #[default]
+    Everyone,
+    TeacherOnly,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShapeKind {
+    Line,
+    Arrow,
+    Rectangle,
+    Ellipse,
+}
+
+/// Something drawn on the board. Positions are board coordinates, not map tiles.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ElementKind {
+    Stroke {
+        points: Vec<[f32; 2]>,
+        color: String,
+        width: f32,
+    },
+    Shape {
+        shape: ShapeKind,
+        from: [f32; 2],
+        to: [f32; 2],
+        color: String,
+        width: f32,
+        #[serde(default)]
+        fill: Option<String>,
+    },
+    Text {
+        position: [f32; 2],
+        text: String,
+        color: String,
+        size: f32,
+    },
+}
+
+impl ElementKind {
+    fn validate(&self) -> Result<(), &'static str> {
+        let finite = |point: &[f32; 2]| point.iter().all(|v| v.is_finite());
+        match self {
+            ElementKind::Stroke {
+                points,
+                color,
+                width,
+            } => {
+                if points.is_empty() || points.len() > MAX_POINTS {
+                    return Err("invalid_points");
+                }
+                if !points.iter().all(finite) {
+                    return Err("invalid_points");
+                }
+                validate_pen(color, *width)
+            }
+            ElementKind::Shape {
+                from,
+                to,
+                color,
+                width,
+                fill,
+                ..
+            } => {
+                if !finite(from) || !finite(to) {
+                    return Err("invalid_points");
+                }
+                if fill
+                    .as_ref()
+                    .is_some_and(|fill| fill.len() > MAX_COLOR_LENGTH)
+                {
+                    return Err("invalid_color");
+                }
+                validate_pen(color, *width)
+            }
+            ElementKind::Text {
+                position,
+                text,
+                color,
+                size,
+            } => {
+                if !finite(position) {
+                    return Err("invalid_points");
+                }
+                if text.trim().is_empty() {
+                    return Err("empty_text");
+                }
+                if text.chars().count() > MAX_TEXT_LENGTH {
+                    return Err("text_too_long");
+                }
+                validate_pen(color, *size)
+            }
+        }
+    }
+}
+
+fn validate_pen(color: &str, width: f32) -> Result<(), &'static str> {
+    if color.is_empty() || color.len() > MAX_COLOR_LENGTH {
+        return Err("invalid_color");
+    }
+    if !width.is_finite() || width <= 0.0 {
+        return Err("invalid_width");
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BoardElement {
+    pub(crate) id: String,
+    pub(crate) author: ParticipantId,
+    // Sequence number of the op that last wrote it
+    pub(crate) seq: u64,
+    #[serde(flatten)]
+    pub(crate) kind: ElementKind,
+}
+
+/// An edit from a client. Element ids are picked by the client, so it can draw
+/// without waiting for the server, and have to be unique on the board.
+/// `epoch` is the board epoch the sender last saw, from the snapshot or its clears since.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum WhiteboardOp {
+    // Adds an element, or replaces one the sender drew
+    Draw {
+        element_id: String,
+        element: ElementKind,
+        #[serde(default)]
+        epoch: u64,
+    },
+    // More points for a stroke the sender is still drawing
+    Extend {
+        element_id: String,
+        points: Vec<[f32; 2]>,
+        #[serde(default)]
+        epoch: u64,
+    },
+    Erase {
+        element_ids: Vec<String>,
+        #[serde(default)]
+        epoch: u64,
+    },
+    // Teacher only, starts the next epoch
+    Clear,
+}
+
+/// A room's board. The server puts every op it accepts in one sequence and clients
+/// apply them in `seq` order, so everyone ends up with the same board whatever order
+/// concurrent edits arrive in. Erasing wins: an erased id can't be drawn again.
+/// Clearing starts a new epoch and turns away ops sent before the sender saw it, so
+/// erased ids only have to be remembered until the next clear.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Whiteboard {
+    pub(crate) seq: u64,
+    #[serde(default)]
+    pub(crate) epoch: u64,
+    pub(crate) mode: DrawMode,
+    elements: BTreeMap<String, BoardElement>,
+    // Erased in this epoch
+    erased: HashSet<String>,
+    // Changed since the last snapshot was written
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Whiteboard {
+    pub(crate) fn can_draw(&self, role: Role) -> bool {
+        match self.mode {
+            DrawMode::Everyone => role.can(Permission::Draw),
+            DrawMode::TeacherOnly => role.can(BOARD_ADMIN_PERMISSION),
+        }
+    }
+
+    /// Checks the op against the board and the sender's role and applies it.
+    /// Returns the op's sequence number, which is what clients order by.
+    pub(crate) fn apply(
+        &mut self,
+        author: &ParticipantId,
+        role: Role,
+        op: &WhiteboardOp,
+    ) -> Result<u64, &'static str> {
+        if !self.can_draw(role) {
+            return Err("not_permitted");
+        }
+        let is_admin = role.can(BOARD_ADMIN_PERMISSION);
+        let seq = self.seq + 1;
+        match op {
+            WhiteboardOp::Draw { epoch, .. }
+            | WhiteboardOp::Extend { epoch, .. }
+            | WhiteboardOp::Erase { epoch, .. }
+                if *epoch != self.epoch =>
+            {
+                return Err("board_cleared");
+            }
+            WhiteboardOp::Draw {
+                element_id,
+                element,
+                ..
+            } => {
+                if element_id.is_empty() || element_id.len() > MAX_ID_LENGTH {
+                    return Err("invalid_element_id");
+                }
+                if self.erased.contains(element_id) {
+                    return Err("element_erased");
+                }
+                element.validate()?;
+                let author = match self.elements.get(element_id) {
+                    // Only the author or the teacher can change an element
+                    Some(existing) if &existing.author != author && !is_admin => {
+                        return Err("not_permitted");
+                    }
+                    // Stays the author's when the teacher edits it
+                    Some(existing) => existing.author.clone(),
+                    None if self.elements.len() >= MAX_ELEMENTS => return Err("board_full"),
+                    None => author.clone(),
+                };
+                self.elements.insert(
+                    element_id.clone(),
+                    BoardElement {
+                        id: element_id.clone(),
+                        author,
+                        seq,
+                        kind: element.clone(),
+                    },
+                );
+            }
+            WhiteboardOp::Extend {
+                element_id, points, ..
+            } => {
+                let element = self
+                    .elements
+                    .get_mut(element_id)
+                    .ok_or("element_not_found")?;
+                if &element.author != author {
+                    return Err("not_permitted");
+                }
+                let ElementKind::Stroke { points: stroke, .. } = &mut element.kind else {
+                    return Err("not_a_stroke");
+                };
+                if stroke.len() + points.len() > MAX_POINTS {
+                    return Err("invalid_points");
+                }
+                if !points
+                    .iter()
+                    .all(|point| point.iter().all(|v| v.is_finite()))
+                {
+                    return Err("invalid_points");
+                }
+                stroke.extend_from_slice(points);
+                element.seq = seq;
+            }
+            WhiteboardOp::Erase { element_ids, .. } => {
+                // Students can only erase what they drew themselves
+                let foreign = element_ids.iter().any(|element_id| {
+                    self.elements
+                        .get(element_id)
+                        .is_some_and(|existing| &existing.author != author)
+                });
+                if foreign && !is_admin {
+                    return Err("not_permitted");
+                }
+                for element_id in element_ids {
+                    // Already gone is fine, two people can erase the same thing at once
+                    if self.elements.remove(element_id).is_some() {
+                        self.erased.insert(element_id.clone());
+                    }
+                }
+            }
+            WhiteboardOp::Clear => {
+                if !is_admin {
+                    return Err("not_permitted");
+                }
+                self.elements.clear();
+                self.erased.clear();
+                self.epoch += 1;
+            }
+        }
+        self.seq = seq;
+        self.dirty = true;
+        Ok(seq)
+    }
+
+    pub(crate) fn set_mode(&mut self, mode: DrawMode) {
+        self.mode = mode;
+        self.dirty = true;
+    }
+
+    /// A copy to write to disk, if anything changed since the last one.
+    pub(crate) fn take_snapshot(&mut self) -> Option<Whiteboard> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        Some(self.clone())
+    }
+
+    /// The whole board for join and reconnect snapshots. Ops up to `seq` are already in it.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "seq": self.seq,
+            "epoch": self.epoch,
+            "mode": self.mode,
+            "elements": self.elements.values().collect::<Vec<_>>()
+        })
+    }
+}
+
+/// Opens the whiteboard store, call once at startup.
+pub fn init() -> Result<(), Box<dyn Error>> {
+    let db = sled::open(WHITEBOARD_DB_PATH)?;
+    WHITEBOARD_DB
+        .set(db)
+        .map_err(|_| "Whiteboard store already initialized")?;
+    Ok(())
+}
+
+fn store() -> Result<&'static sled::Db, Box<dyn Error + Send + Sync>> {
+    Ok(WHITEBOARD_DB
+        .get()
+        .ok_or("Whiteboard store not initialized")?)
+}
+
+/// The room's board as it was last snapshotted, or an empty one.
+pub(crate) fn load(room_id: u32) -> Whiteboard {
+    let loaded = store().and_then(|db| match db.get(room_id.to_be_bytes())? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    });
+    match loaded {
+        Ok(board) => board.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("Failed to load the whiteboard of room {}: {}", room_id, e);
+            Whiteboard::default()
+        }
+    }
+}
+
+/// Writes the room's board to disk if it changed, called from the room tick.
+pub(crate) async fn snapshot(room_id: u32) {
+    let Some(board) = RoomManager::instance()
+        .take_whiteboard_snapshot(room_id)
+        .await
+    else {
+        return;
+    };
+    let saved = store().and_then(|db| {
+        db.insert(room_id.to_be_bytes(), serde_json::to_vec(&board)?)?;
+        Ok(())
+    });
+    if let Err(e) = saved {
+        eprintln!("Failed to save the whiteboard of room {}: {}", room_id, e);
+    }
+}
+
+pub async fn whiteboard_op(user_arc: Arc<Mutex<User>>, op: WhiteboardOp) {
+    let (room_id, user_id, seq) = {
+        let user = user_arc.lock().await;
+        let (Some(room_id), Some(user_id), Some(role)) = (user.room_id, user.id.clone(), user.role)
+        else {
+            return;
+        };
+        let applied = RoomManager::instance()
+            .apply_whiteboard_op(room_id, &user_id, role, &op)
+            .await;
+        match applied {
+            Ok(seq) => (room_id, user_id, seq),
+            Err(reason) => {
+                user.reject_action("whiteboard_op", reason);
+                return;
+            }
+        }
+    };
+
+    // The sender gets it back too, that's how it learns the op's place in the sequence
+    let op_message = serde_json::json!({
+        "type": "whiteboard_op",
+        "seq": seq,
+        "author": user_id,
+        "op": op
+    });
+    RoomManager::instance()
+        .broadcast_message(None, room_id, op_message.to_string())
+        .await;
+}
+
+/// Switches between everyone drawing and only the teacher drawing.
+pub async fn set_mode(user_arc: Arc<Mutex<User>>, payload: WhiteboardModePayload) {
+    let room_id = {
+        let user = user_arc.lock().await;
+        if !user.can(BOARD_ADMIN_PERMISSION) {
+            user.reject_action("whiteboard_mode", "not_permitted");
+            return;
+        }
+        let Some(room_id) = user.room_id else {
+            return;
+        };
+        room_id
+    };
+
+    RoomManager::instance()
+        .set_whiteboard_mode(room_id, payload.mode)
+        .await;
+    let mode_message = serde_json::json!({
+        "type": "whiteboard_mode",
+        "mode": payload.mode
+    });
+    RoomManager::instance()
+        .broadcast_message(None, room_id, mode_message.to_string())
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::AccountIdentity;
+
+    fn participant(byte: u8) -> ParticipantId {
+        AccountIdentity::from([byte; 32]).into()
+    }
+
+    fn stroke() -> ElementKind {
+        ElementKind::Stroke {
+            points: vec![[0.0, 0.0], [1.0, 1.0]],
+            color: "#000000".to_string(),
+            width: 2.0,
+        }
+    }
+
+    fn draw(element_id: &str, epoch: u64) -> WhiteboardOp {
+        WhiteboardOp::Draw {
+            element_id: element_id.to_string(),
+            element: stroke(),
+            epoch,
+        }
+    }
+
+    fn erase(element_id: &str, epoch: u64) -> WhiteboardOp {
+        WhiteboardOp::Erase {
+            element_ids: vec![element_id.to_string()],
+            epoch,
+        }
+    }
+
+    #[test]
+    fn accepted_ops_get_consecutive_seqs() {
+        let mut board = Whiteboard::default();
+        let student = participant(2);
+        assert_eq!(board.apply(&student, Role::Student, &draw("a", 0)), Ok(1));
+        let extend = WhiteboardOp::Extend {
+            element_id: "a".to_string(),
+            points: vec![[2.0, 2.0]],
+            epoch: 0,
+        };
+        assert_eq!(board.apply(&student, Role::Student, &extend), Ok(2));
+        // Refused ops don't use up a number
+        assert_eq!(
+            board.apply(&student, Role::Student, &draw("", 0)),
+            Err("invalid_element_id")
+        );
+        assert_eq!(board.apply(&student, Role::Student, &erase("a", 0)), Ok(3));
+        assert_eq!(board.seq, 3);
+    }
+
+    #[test]
+    fn erased_ids_cannot_be_drawn_again() {
+        let mut board = Whiteboard::default();
+        let student = participant(2);
+        board.apply(&student, Role::Student, &draw("a", 0)).unwrap();
+        board
+            .apply(&student, Role::Student, &erase("a", 0))
+            .unwrap();
+        assert_eq!(
+            board.apply(&student, Role::Student, &draw("a", 0)),
+            Err("element_erased")
+        );
+        // A concurrent erase of the same element still goes through
+        assert_eq!(
+            board.apply(&participant(1), Role::Teacher, &erase("a", 0)),
+            Ok(3)
+        );
+        assert!(board.to_json()["elements"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_the_author_or_teacher_changes_an_element() {
+        let mut board = Whiteboard::default();
+        let (teacher, author, other) = (participant(1), participant(2), participant(3));
+        board.apply(&author, Role::Student, &draw("a", 0)).unwrap();
+        assert_eq!(
+            board.apply(&other, Role::Student, &draw("a", 0)),
+            Err("not_permitted")
+        );
+        assert_eq!(
+            board.apply(&other, Role::Student, &erase("a", 0)),
+            Err("not_permitted")
+        );
+        board.apply(&teacher, Role::Teacher, &draw("a", 0)).unwrap();
+        assert_eq!(board.elements["a"].author, author);
+        assert_eq!(board.elements["a"].seq, 2);
+    }
+
+    #[test]
+    fn clearing_starts_a_new_epoch_and_turns_away_stale_ops() {
+        let mut board = Whiteboard::default();
+        let (teacher, student) = (participant(1), participant(2));
+        board.apply(&student, Role::Student, &draw("a", 0)).unwrap();
+        board
+            .apply(&student, Role::Student, &erase("a", 0))
+            .unwrap();
+        assert_eq!(
+            board.apply(&student, Role::Student, &WhiteboardOp::Clear),
+            Err("not_permitted")
+        );
+        assert_eq!(
+            board.apply(&teacher, Role::Teacher, &WhiteboardOp::Clear),
+            Ok(3)
+        );
+        assert_eq!(board.epoch, 1);
+        assert!(board.erased.is_empty());
+
+        assert_eq!(
+            board.apply(&student, Role::Student, &draw("b", 0)),
+            Err("board_cleared")
+        );
+        // Ids erased before the clear are free again
+        assert_eq!(board.apply(&student, Role::Student, &draw("a", 1)), Ok(4));
+    }
+
+    #[test]
+    fn teacher_only_mode_locks_out_students() {
+        let mut board = Whiteboard::default();
+        board.set_mode(DrawMode::TeacherOnly);
+        assert_eq!(
+            board.apply(&participant(2), Role::Student, &draw("a", 0)),
+            Err("not_permitted")
+        );
+        assert_eq!(
+            board.apply(&participant(1), Role::CoTeacher, &draw("a", 0)),
+            Ok(1)
+        );
+        assert!(board.take_snapshot().is_some());
+        assert!(board.take_snapshot().is_none());
+    }
+}
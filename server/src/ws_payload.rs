@@ -1,7 +1,17 @@
-use mediasoup::data_structures::{DtlsParameters, IceCandidate, IceParameters};
-use mediasoup::prelude::{MediaKind, RtcpParameters, RtpParameters};
+use mediasoup::data_structures::DtlsParameters;
+use mediasoup::prelude::{MediaKind, RtpCapabilities, RtpParameters};
 use serde::{Deserialize, Serialize};
 
+use crate::breakout::BreakoutAssignment;
+use crate::chat::ChatScope;
+use crate::identity::{AccountIdentity, ParticipantId};
+use crate::moderation::ModerationAction;
+use crate::polls::{PollAnswer, PollKind};
+use crate::presentation::Deck;
+use crate::roles::Role;
+use crate::stream_types::StreamType;
+use crate::whiteboard::DrawMode;
+
 #[derive(Serialize, Deserialize)]
 pub struct MovementPayload {
     pub(crate) x: i32,
@@ -10,33 +20,185 @@ pub struct MovementPayload {
 #[derive(Deserialize)]
 pub struct JoinPayload {
     pub(crate) course_id: u32,
+    // SS58 or hex, parsed into an `AccountIdentity` when joining
     pub(crate) pub_address: String,
     pub(crate) signature: String,
+    // Has to be `session::join_message` for the connection's current challenge
     pub(crate) message_signed: String,
+    // Only used when multi-device logins are allowed
+    #[serde(default)]
+    pub(crate) device_id: Option<String>,
 }
 #[derive(Deserialize)]
-pub struct WebRTCConnectPayload {
-    dtls_parameters: RtcpParameters,
+pub struct ReconnectPayload {
+    // Issued in `room_joined` (or a previous `reconnected`), valid once
+    pub(crate) session_token: String,
+}
+#[derive(Deserialize)]
+pub struct SendMessagePayload {
+    #[serde(default)]
+    pub(crate) scope: ChatScope,
+    // Recipient, only for direct messages
+    #[serde(default)]
+    pub(crate) target: Option<ParticipantId>,
+    pub(crate) content: String,
+}
+#[derive(Deserialize)]
+pub struct DeleteMessagePayload {
+    pub(crate) message_id: u64,
+}
+#[derive(Deserialize)]
+pub struct ChatHistoryPayload {
+    // Message id to page back from, the latest messages when missing
+    #[serde(default)]
+    pub(crate) before: Option<u64>,
+    #[serde(default)]
+    pub(crate) limit: Option<usize>,
+}
+#[derive(Deserialize)]
+pub struct LowerHandPayload {
+    // Staff can lower someone else's hand, everyone else only their own
+    #[serde(default)]
+    pub(crate) target: Option<ParticipantId>,
+}
+#[derive(Deserialize)]
+pub struct GrantFloorPayload {
+    // First hand in the queue when missing
+    #[serde(default)]
+    pub(crate) target: Option<ParticipantId>,
+    // How long before the floor is taken back, `hand_raise::FLOOR_TIMEOUT` when missing
+    #[serde(default)]
+    pub(crate) duration_secs: Option<u64>,
+}
+#[derive(Deserialize)]
+pub struct CreatePollPayload {
+    pub(crate) question: String,
+    pub(crate) kind: PollKind,
+    // Only for single and multiple choice
+    #[serde(default)]
+    pub(crate) options: Vec<String>,
+    // Set to make it a quiz
+    #[serde(default)]
+    pub(crate) correct: Option<PollAnswer>,
+    // Numeric quizzes only, 0 when missing
+    #[serde(default)]
+    pub(crate) tolerance: Option<f64>,
+    // Answer window, `polls::DEFAULT_POLL_SECS` when missing
+    #[serde(default)]
+    pub(crate) duration_secs: Option<u64>,
+}
+#[derive(Deserialize)]
+pub struct AnswerPollPayload {
+    pub(crate) poll_id: u64,
+    pub(crate) answer: PollAnswer,
+}
+#[derive(Deserialize)]
+pub struct ClosePollPayload {
+    pub(crate) poll_id: u64,
+}
+#[derive(Deserialize)]
+pub struct AttestationPayload {
+    // Defaults to the sender, only the teacher can ask for someone else
+    #[serde(default)]
+    pub(crate) student: Option<AccountIdentity>,
+}
+#[derive(Deserialize)]
+pub struct WhiteboardModePayload {
+    pub(crate) mode: DrawMode,
+}
+#[derive(Deserialize)]
+pub struct StartPresentationPayload {
+    pub(crate) deck: Deck,
+    // Slide to open on, the first when missing
+    #[serde(default)]
+    pub(crate) slide: Option<u32>,
+}
+#[derive(Deserialize)]
+pub struct SetSlidePayload {
+    pub(crate) slide: u32,
+}
+#[derive(Deserialize)]
+pub struct LaserPointerPayload {
+    // Hides the pointer when missing
+    #[serde(default)]
+    pub(crate) position: Option<[f32; 2]>,
+}
+#[derive(Deserialize)]
+pub struct FollowPresenterPayload {
+    pub(crate) follow: bool,
+}
+#[derive(Deserialize)]
+pub struct StartBreakoutsPayload {
+    pub(crate) assignment: BreakoutAssignment,
+    // How many rooms, for random and by-seat assignment
+    #[serde(default)]
+    pub(crate) count: Option<usize>,
+    // One list of students per room, for manual assignment
+    #[serde(default)]
+    pub(crate) groups: Vec<Vec<ParticipantId>>,
+    // Everyone's brought back after this, `breakout::DEFAULT_BREAKOUT_SECS` when missing
+    #[serde(default)]
+    pub(crate) duration_secs: Option<u64>,
+}
+#[derive(Deserialize)]
+pub struct VisitBreakoutPayload {
+    // Back to the main room when missing
+    #[serde(default)]
+    pub(crate) room_id: Option<u32>,
+}
+#[derive(Deserialize)]
+pub struct AssignBreakoutPayload {
+    pub(crate) target: ParticipantId,
+    pub(crate) room_id: u32,
+}
+#[derive(Deserialize)]
+pub struct BreakoutBroadcastPayload {
+    pub(crate) content: String,
+}
+#[derive(Deserialize)]
+pub struct WaitingRoomPayload {
+    pub(crate) enabled: bool,
+}
+#[derive(Deserialize)]
+pub struct LobbyDecisionPayload {
+    // Everyone waiting when missing
+    #[serde(default)]
+    pub(crate) target: Option<ParticipantId>,
 }
-
 #[derive(Deserialize)]
 pub struct ProducePayload {
-    kind: MediaKind,
-    rtp_parameters: RtpParameters,
+    pub(crate) kind: MediaKind,
+    pub(crate) rtp_parameters: RtpParameters,
+    // Screen shares need their own permission
+    #[serde(default)]
+    pub(crate) stream_type: Option<StreamType>,
 }
 
 #[derive(Deserialize)]
 pub struct ConsumePayload {
-    producer_id: String,
+    pub(crate) producer_id: String,
+    // The client device's, the router checks it can decode the producer's codec
+    pub(crate) rtp_capabilities: RtpCapabilities,
 }
 #[derive(Deserialize)]
 pub struct ResumePayload {
-    consumer_id: String,
+    pub(crate) consumer_id: String,
 }
 #[derive(Deserialize)]
 pub struct TransportOptions {
-    id: String,
-    ice_parameters: IceParameters,
-    ice_candidates: Vec<IceCandidate>,
-    dtls_parameters: DtlsParameters,
+    pub(crate) id: String,
+    pub(crate) dtls_parameters: DtlsParameters,
+}
+
+#[derive(Deserialize)]
+pub struct ModerationPayload {
+    pub(crate) action: ModerationAction,
+    pub(crate) target: Option<AccountIdentity>,
+    // Only for `delegate`
+    #[serde(default)]
+    pub(crate) role: Option<Role>,
+    // Unix seconds, part of the signed message
+    pub(crate) issued_at: u64,
+    // Teacher's sr25519 signature over `moderation::signed_message`, hex encoded
+    pub(crate) signature: String,
 }